@@ -0,0 +1,23 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use pixelpwnr_render::{Color, Pixmap};
+
+/// Compare copying a 4K canvas on a single thread against copying it in parallel.
+fn snapshot(c: &mut Criterion) {
+    let (width, height) = (3840, 2160);
+    let pixmap = Pixmap::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let color = Color::from_rgb(x as u8, y as u8, (x ^ y) as u8);
+            pixmap.set_pixel(x, y, color).unwrap();
+        }
+    }
+    assert_eq!(pixmap.par_as_image(), pixmap.as_bytes());
+
+    let mut group = c.benchmark_group("snapshot 4K");
+    group.bench_function("serial", |b| b.iter(|| pixmap.as_bytes().to_vec()));
+    group.bench_function("parallel", |b| b.iter(|| pixmap.par_as_image()));
+    group.finish();
+}
+
+criterion_group!(benches, snapshot);
+criterion_main!(benches);
@@ -4,6 +4,29 @@ use std::num::ParseIntError;
 /// The default alpha channel value, if not specified. (0xFF = opaque)
 const DEFAULT_ALPHA: u8 = 0xFF;
 
+/// Lookup table to convert an sRGB encoded channel value into linear light.
+///
+/// Linear values are scaled to the full `u16` range.
+#[rustfmt::skip]
+const SRGB_TO_LINEAR: [u16; 256] = [
+    0, 20, 40, 60, 80, 99, 119, 139, 159, 179, 199, 219, 241, 264, 288, 313,
+    340, 367, 396, 427, 458, 491, 526, 562, 599, 637, 677, 718, 761, 805, 851, 898,
+    947, 997, 1048, 1101, 1156, 1212, 1270, 1330, 1391, 1453, 1517, 1583, 1651, 1720, 1790, 1863,
+    1937, 2013, 2090, 2170, 2250, 2333, 2418, 2504, 2592, 2681, 2773, 2866, 2961, 3058, 3157, 3258,
+    3360, 3464, 3570, 3678, 3788, 3900, 4014, 4129, 4247, 4366, 4488, 4611, 4736, 4864, 4993, 5124,
+    5257, 5392, 5530, 5669, 5810, 5953, 6099, 6246, 6395, 6547, 6700, 6856, 7014, 7174, 7335, 7500,
+    7666, 7834, 8004, 8177, 8352, 8528, 8708, 8889, 9072, 9258, 9445, 9635, 9828, 10022, 10219, 10417,
+    10619, 10822, 11028, 11235, 11446, 11658, 11873, 12090, 12309, 12530, 12754, 12980, 13209, 13440, 13673, 13909,
+    14146, 14387, 14629, 14874, 15122, 15371, 15623, 15878, 16135, 16394, 16656, 16920, 17187, 17456, 17727, 18001,
+    18277, 18556, 18837, 19121, 19407, 19696, 19987, 20281, 20577, 20876, 21177, 21481, 21787, 22096, 22407, 22721,
+    23038, 23357, 23678, 24002, 24329, 24658, 24990, 25325, 25662, 26001, 26344, 26688, 27036, 27386, 27739, 28094,
+    28452, 28813, 29176, 29542, 29911, 30282, 30656, 31033, 31412, 31794, 32179, 32567, 32957, 33350, 33745, 34143,
+    34544, 34948, 35355, 35764, 36176, 36591, 37008, 37429, 37852, 38278, 38706, 39138, 39572, 40009, 40449, 40891,
+    41337, 41785, 42236, 42690, 43147, 43606, 44069, 44534, 45002, 45473, 45947, 46423, 46903, 47385, 47871, 48359,
+    48850, 49344, 49841, 50341, 50844, 51349, 51858, 52369, 52884, 53401, 53921, 54445, 54971, 55500, 56032, 56567,
+    57105, 57646, 58190, 58737, 59287, 59840, 60396, 60955, 61517, 62082, 62650, 63221, 63795, 64372, 64952, 65535,
+];
+
 #[derive(Debug, Clone, Copy)]
 pub enum ParseColorError {
     /// 6 or 8 characters are required
@@ -70,6 +93,16 @@ impl Color {
         (self.value & 0xFF000000) >> 24
     }
 
+    /// Check whether this color is fully opaque.
+    pub const fn is_opaque(&self) -> bool {
+        self.alpha() == u8::MAX as u32
+    }
+
+    /// Check whether this color is fully transparent.
+    pub const fn is_transparent(&self) -> bool {
+        self.alpha() == 0
+    }
+
     /// Construct a new color, from the given hexadecimal string.
     ///
     /// If parsing the hexadecimal string failed, an error is returned.
@@ -162,9 +195,9 @@ impl Color {
         let mut b = other.blue();
         let mut a = other.alpha();
 
-        if a == 0 {
+        if other.is_transparent() {
             return;
-        } else if a < u8::MAX as u32 {
+        } else if !other.is_opaque() {
             let na = u8::MAX as u32 - a;
             r = ((a * r) + (na * self.red())) / 0xFF;
             g = ((a * g) + (na * self.green())) / 0xFF;
@@ -173,6 +206,58 @@ impl Color {
         }
         self.value = r & 0xFF | (g & 0xFF) << 8 | (b & 0xFF) << 16 | (a & 0xFF) << 24;
     }
+
+    /// Blend this color with another, in linear light.
+    ///
+    /// This is similar to `blend`, but converts the color channels from sRGB
+    /// to linear space before blending, and back to sRGB afterwards. This
+    /// produces correct colors when many semi-transparent colors are stacked,
+    /// at a small performance cost.
+    ///
+    /// Self should be the current value, and `other` should be the incoming value
+    pub fn blend_gamma(&mut self, other: Color) {
+        let mut r = other.red();
+        let mut g = other.green();
+        let mut b = other.blue();
+        let mut a = other.alpha();
+
+        if other.is_transparent() {
+            return;
+        } else if !other.is_opaque() {
+            let na = u8::MAX as u32 - a;
+            let mix = |src: u32, dst: u32| {
+                let linear = ((a * SRGB_TO_LINEAR[src as usize] as u32)
+                    + (na * SRGB_TO_LINEAR[dst as usize] as u32))
+                    / 0xFF;
+                linear_to_srgb(linear as u16) as u32
+            };
+            r = mix(r, self.red());
+            g = mix(g, self.green());
+            b = mix(b, self.blue());
+            a += self.alpha();
+        }
+        self.value = r & 0xFF | (g & 0xFF) << 8 | (b & 0xFF) << 16 | (a & 0xFF) << 24;
+    }
+}
+
+/// Convert a linear light value back into an sRGB encoded channel value.
+///
+/// The nearest value is looked up in `SRGB_TO_LINEAR` using a binary search,
+/// to prevent invoking `powf` for each channel.
+fn linear_to_srgb(linear: u16) -> u8 {
+    let i = SRGB_TO_LINEAR.partition_point(|v| *v < linear);
+    if i == 0 {
+        return 0;
+    } else if i >= SRGB_TO_LINEAR.len() {
+        return u8::MAX;
+    }
+
+    // Pick whichever neighbour is closest
+    if SRGB_TO_LINEAR[i] - linear < linear - SRGB_TO_LINEAR[i - 1] {
+        i as u8
+    } else {
+        (i - 1) as u8
+    }
 }
 
 impl fmt::Debug for Color {
@@ -216,3 +301,22 @@ fn from_hex_raw() {
     test!("AABBCC", 0xFFCCBBAA, "ColorRGBA(AA, BB, CC, FF)");
     test!("ABCDEF00", 0x00EFCDAB, "ColorRGB(AB, CD, EF)");
 }
+
+#[test]
+fn blend_gamma() {
+    // Blend white at 50% over black, in both modes, alpha is accumulated
+    let white = Color::from_rgba(0xFF, 0xFF, 0xFF, 0x80);
+
+    let mut color = Color::black();
+    color.blend(white);
+    assert_eq!(color, Color::from_rgba(0x80, 0x80, 0x80, 0x7F));
+
+    let mut color = Color::black();
+    color.blend_gamma(white);
+    assert_eq!(color, Color::from_rgba(0xBC, 0xBC, 0xBC, 0x7F));
+
+    // Opaque colors are identical in both modes
+    let mut color = Color::black();
+    color.blend_gamma(Color::from_rgb(0x12, 0x34, 0x56));
+    assert_eq!(color, Color::from_rgb(0x12, 0x34, 0x56));
+}
@@ -0,0 +1,19 @@
+/// The offset basis of the 64-bit FNV-1a hash.
+const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// The prime of the 64-bit FNV-1a hash.
+const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Hash the given bytes with the fast, non-cryptographic 64-bit FNV-1a hash.
+pub fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+#[test]
+fn fnv1a_vectors() {
+    assert_eq!(fnv1a([]), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(*b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(fnv1a(*b"foobar"), 0x8594_4171_f739_67e8);
+}
@@ -1,13 +1,19 @@
 pub mod color;
 pub mod fps_counter;
+pub mod hash;
 mod model;
 pub mod pixmap;
+pub mod preview;
 mod primitive;
 pub mod renderer;
 pub use renderer::stats_renderer;
+pub mod text;
 mod vertex;
 
 // Reexport types
 pub use color::Color;
-pub use pixmap::{Pixmap, PixmapErr};
-pub use renderer::Renderer;
+pub use hash::fnv1a;
+pub use pixmap::{Pattern, Pixmap, PixmapErr, WriteMode};
+pub use preview::Preview;
+pub use renderer::{Config, FullscreenMode, RenderError, Renderer};
+pub use text::draw_text;
@@ -1,6 +1,11 @@
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use image::RgbaImage;
 
 use crate::color::Color;
+use crate::hash::fnv1a;
 
 /// A struct representing a pixelmap for pixelflut.
 ///
@@ -30,6 +35,27 @@ pub struct Pixmap {
 
     /// Pixelmap dimensions, width and height
     dimensions: (usize, usize),
+
+    /// Whether to blend colors in linear light, see `Color::blend_gamma`.
+    gamma_correct: bool,
+
+    /// Whether to skip blending, and store colors as opaque.
+    no_alpha: bool,
+
+    /// How written colors are combined with the current pixel.
+    write_mode: WriteMode,
+
+    /// Whether coordinates wrap around the edges, as on a torus.
+    wrap: bool,
+
+    /// The pattern the pixel map was initialized with, if any.
+    pattern: Option<Pattern>,
+
+    /// Whether any pixel has been set since the pixel map was created.
+    touched: AtomicBool,
+
+    /// The write cooldown, if any, shared with clones.
+    cooldown: Option<Arc<Cooldown>>,
 }
 
 impl Clone for Pixmap {
@@ -43,7 +69,82 @@ impl Clone for Pixmap {
         Self {
             map,
             dimensions: self.dimensions,
+            gamma_correct: self.gamma_correct,
+            no_alpha: self.no_alpha,
+            write_mode: self.write_mode,
+            wrap: self.wrap,
+            pattern: self.pattern,
+            touched: AtomicBool::new(self.is_touched()),
+            cooldown: self.cooldown.clone(),
+        }
+    }
+}
+
+/// Write cooldown for square regions of a pixel map.
+struct Cooldown {
+    /// The cooldown, in milliseconds.
+    cooldown: u64,
+
+    /// The size of each region, in pixels.
+    region: usize,
+
+    /// The number of regions on each row.
+    columns: usize,
+
+    /// The time cooldowns are measured from.
+    start: Instant,
+
+    /// The time each region was last written, in milliseconds since `start`,
+    /// offset by the cooldown so that unwritten regions are never cooling down.
+    written: Vec<AtomicU64>,
+}
+
+/// A started cooldown: its region index, and the previous and new time the
+/// region was written. See `Cooldown::undo`.
+type Started = (usize, u64, u64);
+
+impl Cooldown {
+    fn new((width, height): (usize, usize), cooldown: Duration, region: usize) -> Self {
+        let region = region.max(1);
+        let columns = width.div_ceil(region);
+        let rows = height.div_ceil(region);
+        Self {
+            cooldown: cooldown.as_millis() as u64,
+            region,
+            columns,
+            start: Instant::now(),
+            written: (0..columns * rows).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Start the cooldown for the region of the given in bound coordinate,
+    /// unless it is still cooling down.
+    fn try_start(&self, x: usize, y: usize) -> Option<Started> {
+        self.try_start_at(x, y, self.start.elapsed().as_millis() as u64)
+    }
+
+    /// Same as `try_start`, at the given time in milliseconds since `start`.
+    fn try_start_at(&self, x: usize, y: usize, elapsed: u64) -> Option<Started> {
+        let region = y / self.region * self.columns + x / self.region;
+        let written = &self.written[region];
+        let now = elapsed + self.cooldown;
+        let last = written.load(Ordering::Relaxed);
+
+        // Of concurrent writers, only the first one gets through. A writer
+        // that was overtaken may see a later time than its own.
+        if now.saturating_sub(last) < self.cooldown {
+            return None;
         }
+        written
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .ok()
+            .map(|_| (region, last, now))
+    }
+
+    /// Undo a started cooldown, unless its region was written again since.
+    fn undo(&self, (region, last, now): Started) {
+        let _ =
+            self.written[region].compare_exchange(now, last, Ordering::Relaxed, Ordering::Relaxed);
     }
 }
 
@@ -61,9 +162,85 @@ impl Pixmap {
 
             // Set the dimensions
             dimensions: (width, height),
+
+            gamma_correct: false,
+            no_alpha: false,
+            write_mode: WriteMode::Blend,
+            wrap: false,
+            pattern: None,
+            touched: AtomicBool::new(false),
+            cooldown: None,
         }
     }
 
+    /// Construct a new pixel map, initialized with the given pattern.
+    pub fn new_with_pattern(width: usize, height: usize, pattern: Pattern) -> Self {
+        let mut pixmap = Self::new(width, height);
+        pixmap.map = (0..width * height)
+            .map(|i| AtomicU32::new(pattern.color(i % width, i / width).to_raw()))
+            .collect();
+        pixmap.pattern = Some(pattern);
+        pixmap
+    }
+
+    /// Set whether to blend semi-transparent colors in linear light.
+    ///
+    /// This is slightly slower, but produces correct colors when many
+    /// semi-transparent colors are stacked. Disabled by default.
+    pub fn with_gamma_correct(mut self, gamma_correct: bool) -> Self {
+        self.gamma_correct = gamma_correct;
+        self
+    }
+
+    /// Set whether to disable alpha blending entirely.
+    ///
+    /// When enabled, every written color is treated as opaque: it is stored
+    /// without reading and blending with the current pixel, and its alpha
+    /// channel is discarded. This is the fastest way to write pixels, and
+    /// takes precedence over the write mode. Disabled by default.
+    pub fn with_no_alpha(mut self, no_alpha: bool) -> Self {
+        self.no_alpha = no_alpha;
+        self
+    }
+
+    /// Set how written colors are combined with the current pixel.
+    ///
+    /// Unlike `with_no_alpha`, `WriteMode::Replace` keeps the alpha channel
+    /// of written colors, so it still shows up in snapshots. Defaults to
+    /// `WriteMode::Blend`.
+    pub fn with_write_mode(mut self, write_mode: WriteMode) -> Self {
+        self.write_mode = write_mode;
+        self
+    }
+
+    /// Set whether coordinates wrap around the edges of the pixel map.
+    ///
+    /// When enabled, coordinates beyond the edges are taken modulo the width
+    /// and height, tiling the pixel map as a torus, instead of being out of
+    /// bound. Disabled by default.
+    pub fn with_wrap(mut self, wrap: bool) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    /// Set a write cooldown, ignoring rapid rewrites of the same pixel.
+    ///
+    /// The pixel map is divided in square regions of the given size, in
+    /// pixels. Once a pixel is written, writes to its region are dropped until
+    /// the cooldown has passed. This applies to every write, except for
+    /// `set_pixel_opaque` and `blend_image`. A region size of 1 tracks each
+    /// pixel separately, larger regions use less memory.
+    pub fn with_cooldown(mut self, cooldown: Option<Duration>, region: usize) -> Self {
+        self.cooldown =
+            cooldown.map(|cooldown| Arc::new(Cooldown::new(self.dimensions, cooldown, region)));
+        self
+    }
+
+    /// Get how written colors are combined with the current pixel.
+    pub fn write_mode(&self) -> WriteMode {
+        self.write_mode
+    }
+
     /// Get the width of the pixel map.
     pub fn width(&self) -> usize {
         self.dimensions.0
@@ -86,21 +263,309 @@ impl Pixmap {
         Ok(Color::new(pixel_value))
     }
 
+    /// Iterate over all pixels in row-major order, yielding their coordinate
+    /// and color.
+    ///
+    /// Each pixel is loaded as it is yielded, other threads may still be
+    /// setting pixels while iterating.
+    pub fn iter_pixels(&self) -> impl Iterator<Item = (usize, usize, Color)> + '_ {
+        let width = self.dimensions.0;
+        self.map.iter().enumerate().map(move |(i, pixel)| {
+            (
+                i % width,
+                i / width,
+                Color::new(pixel.load(Ordering::Relaxed)),
+            )
+        })
+    }
+
+    /// Check whether any pixel has been set since the pixel map was created.
+    pub fn is_touched(&self) -> bool {
+        self.touched.load(Ordering::Relaxed)
+    }
+
     /// Set the pixel at the given coordinate, to the given color.
-    pub fn set_pixel(&self, x: usize, y: usize, color: Color) -> Result<(), PixmapErr> {
+    ///
+    /// Returns whether the pixel was written, false if the write was dropped
+    /// within the cooldown, see `with_cooldown`.
+    pub fn set_pixel(&self, x: usize, y: usize, color: Color) -> Result<bool, PixmapErr> {
         let pixel_index = self.pixel_index(x, y)?;
+        if !self.try_cooldown(pixel_index) {
+            return Ok(false);
+        }
+
+        // Only store once, to keep the flag cheap to read for all writers
+        if !self.touched.load(Ordering::Relaxed) {
+            self.touched.store(true, Ordering::Relaxed);
+        }
 
         // A data race can occur here: if two separate threads update the pixel at the same time,
         // the result of one of them will be discarded. This is an acceptable loss as it is unlikely
         // and fixing it is really bad for performance.
-        let mut current_color = Color::new(self.map[pixel_index].load(Ordering::Relaxed));
-        current_color.blend(color);
-        self.map[pixel_index].store(current_color.to_raw(), Ordering::Relaxed);
+        let pixel = &self.map[pixel_index];
+        if let Some(raw) = self.composite(color, || pixel.load(Ordering::Relaxed)) {
+            pixel.store(raw, Ordering::Relaxed);
+        }
+        Ok(true)
+    }
+
+    /// Get the raw pixel value to store when writing the given color, following
+    /// the alpha, write mode and gamma settings.
+    ///
+    /// `current` gives the current raw pixel value, it is only called when
+    /// blending. Returns `None` if the pixel is left as is.
+    fn composite(&self, color: Color, current: impl FnOnce() -> u32) -> Option<u32> {
+        // Without alpha, simply overwrite the pixel with an opaque color
+        if self.no_alpha {
+            return Some(color.to_raw() | Color::from_rgba(0, 0, 0, 0xFF).to_raw());
+        }
+
+        // When replacing, or when blending an opaque color, overwrite the
+        // pixel without reading it first
+        if self.write_mode == WriteMode::Replace || color.is_opaque() {
+            return Some(color.to_raw());
+        }
+
+        // Blending a transparent color leaves the pixel as is
+        if color.is_transparent() {
+            return None;
+        }
+
+        let mut current_color = Color::new(current());
+        if self.gamma_correct {
+            current_color.blend_gamma(color);
+        } else {
+            current_color.blend(color);
+        }
+        Some(current_color.to_raw())
+    }
+
+    /// Composite the given image over the pixel map, with its top left corner
+    /// at the given coordinate.
+    ///
+    /// Each pixel is blended over the current color, regardless of the write
+    /// mode. Parts of the image beyond the edges are clipped.
+    pub fn blend_image(&self, img: &RgbaImage, x: usize, y: usize) {
+        let (width, height) = self.dimensions;
+        let x_end = x.saturating_add(img.width() as usize).min(width);
+        let y_end = y.saturating_add(img.height() as usize).min(height);
+        if x >= x_end || y >= y_end {
+            return;
+        }
+        self.touched.store(true, Ordering::Relaxed);
+
+        for py in y..y_end {
+            for px in x..x_end {
+                let [r, g, b, a] = img.get_pixel((px - x) as u32, (py - y) as u32).0;
+                let pixel = &self.map[py * width + px];
+                let mut color = Color::new(pixel.load(Ordering::Relaxed));
+                if self.gamma_correct {
+                    color.blend_gamma(Color::from_rgba(r, g, b, a));
+                } else {
+                    color.blend(Color::from_rgba(r, g, b, a));
+                }
+                pixel.store(color.to_raw(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Set the pixel at the given coordinate to the given color as is, without
+    /// blending, regardless of the write mode.
+    pub fn set_pixel_opaque(&self, x: usize, y: usize, color: Color) -> Result<(), PixmapErr> {
+        let pixel_index = self.pixel_index(x, y)?;
+        if !self.touched.load(Ordering::Relaxed) {
+            self.touched.store(true, Ordering::Relaxed);
+        }
+        self.map[pixel_index].store(color.to_raw(), Ordering::Relaxed);
         Ok(())
     }
 
+    /// Check whether the pixel at the given index may be written, and if so,
+    /// start the cooldown of its region.
+    ///
+    /// Always true if no cooldown is set, see `with_cooldown`.
+    fn try_cooldown(&self, pixel_index: usize) -> bool {
+        match &self.cooldown {
+            Some(cooldown) => cooldown
+                .try_start(
+                    pixel_index % self.dimensions.0,
+                    pixel_index / self.dimensions.0,
+                )
+                .is_some(),
+            None => true,
+        }
+    }
+
+    /// Get the coordinate the given coordinate refers to, wrapped around the
+    /// edges if wrapping.
+    pub fn wrap_coord(&self, x: usize, y: usize) -> Result<(usize, usize), PixmapErr> {
+        let pixel_index = self.pixel_index(x, y)?;
+        Ok((
+            pixel_index % self.dimensions.0,
+            pixel_index / self.dimensions.0,
+        ))
+    }
+
+    /// Get the background color at the given coordinate, the color the pixel
+    /// map was initialized with.
+    pub fn background(&self, x: usize, y: usize) -> Color {
+        match self.pattern {
+            Some(pattern) => pattern.color(x, y),
+            None => Color::new(Self::DEFAULT_PIXEL),
+        }
+    }
+
+    /// Erase the pixel at the given coordinate, resetting it to the background
+    /// color without blending.
+    ///
+    /// Returns whether the pixel was erased, false if the write was dropped
+    /// within the cooldown.
+    pub fn erase_pixel(&self, x: usize, y: usize) -> Result<bool, PixmapErr> {
+        let pixel_index = self.pixel_index(x, y)?;
+        if !self.try_cooldown(pixel_index) {
+            return Ok(false);
+        }
+        if !self.touched.load(Ordering::Relaxed) {
+            self.touched.store(true, Ordering::Relaxed);
+        }
+        let (x, y) = (
+            pixel_index % self.dimensions.0,
+            pixel_index / self.dimensions.0,
+        );
+        self.map[pixel_index].store(self.background(x, y).to_raw(), Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Fill a rectangle with the given color, with its top left corner at the
+    /// given coordinate.
+    ///
+    /// The rectangle is clipped at the right and bottom edge of the pixel
+    /// map, but its top left corner must be within bounds. When wrapping, only
+    /// the top left corner wraps. Colors are blended the same as with
+    /// `set_pixel`. Returns the number of pixels set, pixels dropped within the
+    /// cooldown aren't counted.
+    pub fn fill_rect(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        color: Color,
+    ) -> Result<usize, PixmapErr> {
+        let index = self.pixel_index(x, y)?;
+        let (x, y) = (index % self.dimensions.0, index / self.dimensions.0);
+
+        let x_end = x.saturating_add(width).min(self.dimensions.0);
+        let y_end = y.saturating_add(height).min(self.dimensions.1);
+        let mut count = 0;
+        for y in y..y_end {
+            for x in x..x_end {
+                if self.set_pixel(x, y, color)? {
+                    count += 1;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Set the pixel at the given coordinate to the `new` color, only if it
+    /// currently has the `expected` color.
+    ///
+    /// Only the color channels are compared, as the alpha channel of pixels
+    /// isn't reported to clients. The new color is written the same as with
+    /// `set_pixel`. Returns whether the pixel was set, false if the write was
+    /// dropped within the cooldown or blends a transparent color.
+    pub fn compare_and_set(
+        &self,
+        x: usize,
+        y: usize,
+        expected: Color,
+        new: Color,
+    ) -> Result<bool, PixmapErr> {
+        let pixel_index = self.pixel_index(x, y)?;
+
+        let current = self.map[pixel_index].load(Ordering::Relaxed);
+        let current_color = Color::new(current);
+        if (
+            current_color.red(),
+            current_color.green(),
+            current_color.blue(),
+        ) != (expected.red(), expected.green(), expected.blue())
+        {
+            return Ok(false);
+        }
+
+        // Blending a transparent color leaves the pixel as is
+        let new = match self.composite(new, || current) {
+            Some(new) => new,
+            None => return Ok(false),
+        };
+
+        // Start the cooldown, and undo it if the swap doesn't go through
+        let started = match &self.cooldown {
+            Some(cooldown) => match cooldown.try_start(
+                pixel_index % self.dimensions.0,
+                pixel_index / self.dimensions.0,
+            ) {
+                Some(started) => Some((cooldown, started)),
+                None => return Ok(false),
+            },
+            None => None,
+        };
+
+        // Fails if another thread changed the pixel in the meantime
+        let swapped = self.map[pixel_index]
+            .compare_exchange(current, new, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok();
+        if swapped && !self.touched.load(Ordering::Relaxed) {
+            self.touched.store(true, Ordering::Relaxed);
+        }
+        if let (false, Some((cooldown, started))) = (swapped, started) {
+            cooldown.undo(started);
+        }
+
+        Ok(swapped)
+    }
+
+    /// Fade every pixel towards black, by `factor / 255`.
+    ///
+    /// Each color channel is scaled by `(255 - factor) / 255`, the alpha
+    /// channel is kept. Every pixel is updated with a single atomic
+    /// read-modify-write, so pixels set concurrently are never lost.
+    ///
+    /// This touches the whole canvas, its cost grows linearly with the canvas
+    /// size. Don't call it more often than needed.
+    pub fn decay(&self, factor: u8) {
+        let keep = (u8::MAX - factor) as u32;
+        for pixel in &self.map {
+            let _ = pixel.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |raw| {
+                let color = Color::new(raw);
+                let fade = |channel: u32| (channel * keep / 0xFF) as u8;
+                Some(
+                    Color::from_rgba(
+                        fade(color.red()),
+                        fade(color.green()),
+                        fade(color.blue()),
+                        color.alpha() as u8,
+                    )
+                    .to_raw(),
+                )
+            });
+        }
+    }
+
     /// Get the index a pixel is at, for the given coordinate.
+    ///
+    /// If wrapping, the coordinate is wrapped around the edges first.
     fn pixel_index(&self, x: usize, y: usize) -> Result<usize, PixmapErr> {
+        let (width, height) = self.dimensions;
+        let (x, y) = if self.wrap && width > 0 && height > 0 {
+            (x % width, y % height)
+        } else {
+            (x, y)
+        };
+
         // Check pixel bounds
         if x >= self.dimensions.0 {
             return Err(PixmapErr::OutOfBound("x coordinate out of bound"));
@@ -119,7 +584,11 @@ impl Pixmap {
     ///
     /// This data may be used to send to the GPU, as raw texture buffer, for
     /// rendering.
-    pub fn as_bytes(&mut self) -> &[u8] {
+    ///
+    /// Other threads may still be setting pixels while the returned slice is
+    /// read, which may show up as a torn pixel. Clone the pixel map first if
+    /// a consistent snapshot is required.
+    pub fn as_bytes(&self) -> &[u8] {
         let map = &self.map;
 
         let len = map.len() * 4;
@@ -140,12 +609,91 @@ impl Pixmap {
         // A correctly aligned [u32] will most likely also constitute a correctly
         // aligned [u8]
         //
-        // Because we are borrowing `self` for 'me (by means of a mutable borrow),
-        // we can safely create an immutable slice of the memory that we're
-        // pointing to that for 'me.
+        // The slice borrows `self`, so the map can't be reallocated or dropped
+        // while it is alive. We never hand out mutable references to the map,
+        // all writes go through the atomics with a shared reference instead.
+        // Reading those bytes while another thread stores a pixel is the same
+        // non-atomic access tradeoff described on `Pixmap`: at worst a single
+        // pixel is read half updated, which is acceptable for rendering.
         let slice = unsafe { core::slice::from_raw_parts(ptr, len) };
         slice
     }
+
+    /// Compute a hash of the dimensions and the contents of the pixel map.
+    ///
+    /// This uses the fast, non-cryptographic 64-bit FNV-1a hash over the
+    /// bytes from `as_bytes`, in a single pass. It's meant to detect whether
+    /// the canvas changed between snapshots, and is too slow to call for
+    /// every pixel write.
+    pub fn content_hash(&self) -> u64 {
+        let (width, height) = self.dimensions;
+        fnv1a(
+            [width as u64, height as u64]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .chain(self.as_bytes().iter().copied()),
+        )
+    }
+
+    /// Copy the pixelmap data into an owned buffer of bytes, in parallel.
+    ///
+    /// The bytes are laid out the same as in `as_bytes`, so they may be used
+    /// as RGBA image buffer. The canvas is split in chunks of rows, which are
+    /// copied concurrently. This is faster than copying the data on a single
+    /// thread for large canvases.
+    #[cfg(feature = "parallel")]
+    pub fn par_as_image(&self) -> Vec<u8> {
+        use rayon::prelude::*;
+
+        /// The number of rows copied in each chunk.
+        const ROWS: usize = 16;
+
+        let width = self.dimensions.0.max(1);
+        let mut bytes = vec![0; self.map.len() * 4];
+        bytes
+            .par_chunks_mut(width * ROWS * 4)
+            .zip(self.map.par_chunks(width * ROWS))
+            .for_each(|(bytes, pixels)| {
+                for (bytes, pixel) in bytes.chunks_exact_mut(4).zip(pixels) {
+                    bytes.copy_from_slice(&pixel.load(Ordering::Relaxed).to_ne_bytes());
+                }
+            });
+        bytes
+    }
+}
+
+/// How written colors are combined with the current pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Alpha blend written colors over the current pixel.
+    Blend,
+
+    /// Store written colors as is, including their alpha channel.
+    Replace,
+}
+
+/// A pattern to initialize a pixel map with.
+#[derive(Debug, Clone, Copy)]
+pub enum Pattern {
+    /// A checkerboard of square blocks of `size` pixels, alternating between
+    /// colors `a` and `b`, starting with `a` in the top left corner.
+    Checkerboard { size: usize, a: Color, b: Color },
+}
+
+impl Pattern {
+    /// Get the color of this pattern at the given coordinate.
+    pub fn color(&self, x: usize, y: usize) -> Color {
+        match *self {
+            Pattern::Checkerboard { size, a, b } => {
+                let size = size.max(1);
+                if (x / size + y / size).is_multiple_of(2) {
+                    a
+                } else {
+                    b
+                }
+            }
+        }
+    }
 }
 
 /// An error representation for pixel map operations.
@@ -154,3 +702,381 @@ pub enum PixmapErr<'a> {
     /// The given pixel coordinate or index is out of bound.
     OutOfBound(&'a str),
 }
+
+#[test]
+fn new_with_pattern() {
+    let (a, b) = (Color::black(), Color::from_rgb(0xFF, 0xFF, 0xFF));
+    let pixmap = Pixmap::new_with_pattern(4, 4, Pattern::Checkerboard { size: 2, a, b });
+
+    #[rustfmt::skip]
+    let expected = [
+        a, a, b, b,
+        a, a, b, b,
+        b, b, a, a,
+        b, b, a, a,
+    ];
+    for (i, color) in expected.iter().enumerate() {
+        assert_eq!(pixmap.pixel(i % 4, i / 4).unwrap(), *color);
+    }
+}
+
+#[test]
+fn as_bytes_shared() {
+    let pixmap = std::sync::Arc::new(Pixmap::new(2, 1));
+    pixmap
+        .set_pixel(1, 0, Color::from_rgb(0x12, 0x34, 0x56))
+        .unwrap();
+
+    let shared = pixmap.clone();
+    assert_eq!(
+        shared.as_bytes(),
+        &[0x00, 0x00, 0x00, 0xFF, 0x12, 0x34, 0x56, 0xFF]
+    );
+}
+
+#[test]
+fn set_pixel_no_alpha() {
+    let pixmap = Pixmap::new(1, 1).with_no_alpha(true);
+    let color = Color::from_rgba(0x12, 0x34, 0x56, 0x80);
+    pixmap.set_pixel(0, 0, color).unwrap();
+    assert_eq!(
+        pixmap.pixel(0, 0).unwrap(),
+        Color::from_rgb(0x12, 0x34, 0x56)
+    );
+}
+
+#[test]
+fn write_modes() {
+    let background = Color::from_rgba(0x00, 0x00, 0xFF, 0x80);
+    let color = Color::from_rgba(0xFF, 0x00, 0x00, 0x80);
+
+    // Blending mixes the color with the semi-transparent background
+    let pixmap = Pixmap::new(1, 1).with_write_mode(WriteMode::Replace);
+    pixmap.set_pixel(0, 0, background).unwrap();
+    let pixmap = pixmap.with_write_mode(WriteMode::Blend);
+    pixmap.set_pixel(0, 0, color).unwrap();
+    let mut blended = background;
+    blended.blend(color);
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), blended);
+    assert_ne!(blended, color);
+
+    // Replacing stores the color as is, including its alpha channel
+    let pixmap = Pixmap::new(1, 1).with_write_mode(WriteMode::Replace);
+    pixmap.set_pixel(0, 0, background).unwrap();
+    pixmap.set_pixel(0, 0, color).unwrap();
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), color);
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn par_as_image_identical() {
+    let pixmap = Pixmap::new(123, 45);
+    for i in 0..123 * 45 {
+        let color = Color::from_rgba(i as u8, (i >> 8) as u8, 0x12, 0xFF);
+        pixmap.set_pixel(i % 123, i / 123, color).unwrap();
+    }
+    assert_eq!(pixmap.par_as_image(), pixmap.as_bytes());
+}
+
+#[test]
+fn touched() {
+    let pixmap = Pixmap::new(2, 2);
+    assert!(!pixmap.is_touched());
+    assert!(pixmap.set_pixel(2, 0, Color::black()).is_err());
+    assert!(!pixmap.is_touched());
+    pixmap.set_pixel(1, 1, Color::black()).unwrap();
+    assert!(pixmap.is_touched());
+}
+
+#[test]
+fn compare_and_set() {
+    let pixmap = Pixmap::new(2, 2);
+    let (red, blue) = (Color::from_rgb(0xFF, 0, 0), Color::from_rgb(0, 0, 0xFF));
+
+    // Succeeds when the current color matches
+    assert!(pixmap.compare_and_set(1, 1, Color::black(), red).unwrap());
+    assert_eq!(pixmap.pixel(1, 1).unwrap(), red);
+
+    // Fails when the current color doesn't match
+    assert!(!pixmap.compare_and_set(1, 1, Color::black(), blue).unwrap());
+    assert_eq!(pixmap.pixel(1, 1).unwrap(), red);
+
+    assert!(pixmap.compare_and_set(2, 0, red, blue).is_err());
+}
+
+#[test]
+fn compare_and_set_write_mode() {
+    let half_red = Color::from_rgba(0xFF, 0, 0, 0x80);
+
+    // Blended over the current color
+    let pixmap = Pixmap::new(1, 1);
+    assert!(pixmap
+        .compare_and_set(0, 0, Color::black(), half_red)
+        .unwrap());
+    let mut blended = Color::black();
+    blended.blend(half_red);
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), blended);
+
+    // Blended in linear light
+    let pixmap = Pixmap::new(1, 1).with_gamma_correct(true);
+    assert!(pixmap
+        .compare_and_set(0, 0, Color::black(), half_red)
+        .unwrap());
+    let mut blended = Color::black();
+    blended.blend_gamma(half_red);
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), blended);
+
+    // Stored opaque, without alpha
+    let pixmap = Pixmap::new(1, 1).with_no_alpha(true);
+    assert!(pixmap
+        .compare_and_set(0, 0, Color::black(), half_red)
+        .unwrap());
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), Color::from_rgb(0xFF, 0, 0));
+
+    // Stored as is when replacing
+    let pixmap = Pixmap::new(1, 1).with_write_mode(WriteMode::Replace);
+    assert!(pixmap
+        .compare_and_set(0, 0, Color::black(), half_red)
+        .unwrap());
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), half_red);
+
+    // Nothing is written when blending a transparent color
+    let pixmap = Pixmap::new(1, 1);
+    assert!(!pixmap
+        .compare_and_set(0, 0, Color::black(), Color::from_rgba(0xFF, 0, 0, 0))
+        .unwrap());
+    assert!(!pixmap.is_touched());
+}
+
+#[test]
+fn iter_pixels() {
+    let pixmap = Pixmap::new(2, 3);
+    let (red, blue) = (Color::from_rgb(0xFF, 0, 0), Color::from_rgb(0, 0, 0xFF));
+    pixmap.set_pixel(1, 0, red).unwrap();
+    pixmap.set_pixel(0, 2, blue).unwrap();
+
+    let black = Color::black();
+    let pixels: Vec<_> = pixmap.iter_pixels().collect();
+    assert_eq!(
+        pixels,
+        [
+            (0, 0, black),
+            (1, 0, red),
+            (0, 1, black),
+            (1, 1, black),
+            (0, 2, blue),
+            (1, 2, black),
+        ]
+    );
+}
+
+#[test]
+fn fill_rect() {
+    let pixmap = Pixmap::new(4, 4);
+    let red = Color::from_rgb(0xFF, 0, 0);
+
+    // Clipped at the right and bottom edge
+    assert_eq!(pixmap.fill_rect(2, 1, 5, 2, red).unwrap(), 4);
+    for (x, y, color) in pixmap.iter_pixels() {
+        let inside = x >= 2 && (1..3).contains(&y);
+        assert_eq!(color, if inside { red } else { Color::black() });
+    }
+
+    assert!(pixmap.fill_rect(4, 0, 1, 1, red).is_err());
+    assert_eq!(pixmap.fill_rect(0, 0, 0, 4, red).unwrap(), 0);
+}
+
+#[test]
+fn content_hash() {
+    let (a, b) = (Pixmap::new(4, 4), Pixmap::new(4, 4));
+    a.set_pixel(1, 2, Color::from_rgb(0x12, 0x34, 0x56))
+        .unwrap();
+    b.set_pixel(1, 2, Color::from_rgb(0x12, 0x34, 0x56))
+        .unwrap();
+    assert_eq!(a.content_hash(), b.content_hash());
+
+    b.set_pixel(3, 3, Color::from_rgb(0, 0, 1)).unwrap();
+    assert_ne!(a.content_hash(), b.content_hash());
+
+    // The same pixels in a different shape hash differently
+    assert_ne!(
+        Pixmap::new(2, 8).content_hash(),
+        Pixmap::new(8, 2).content_hash()
+    );
+}
+
+#[test]
+fn decay() {
+    let pixmap = Pixmap::new(2, 1);
+    pixmap
+        .set_pixel(0, 0, Color::from_rgb(0xFF, 0xFF, 0xFF))
+        .unwrap();
+
+    pixmap.decay(0x10);
+    let color = pixmap.pixel(0, 0).unwrap();
+    assert_eq!(color, Color::from_rgb(0xEF, 0xEF, 0xEF));
+
+    // Black stays black, and nothing changes without a factor
+    assert_eq!(pixmap.pixel(1, 0).unwrap(), Color::black());
+    pixmap.decay(0);
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), color);
+}
+
+#[test]
+fn set_pixel_opacity() {
+    let pixmap = Pixmap::new(2, 1);
+    pixmap.set_pixel(0, 0, Color::from_rgb(0, 0, 0xFF)).unwrap();
+    pixmap.set_pixel(1, 0, Color::from_rgb(0, 0, 0xFF)).unwrap();
+
+    // Opaque colors overwrite the pixel
+    pixmap.set_pixel(0, 0, Color::from_rgb(0xFF, 0, 0)).unwrap();
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), Color::from_rgb(0xFF, 0, 0));
+
+    // Transparent colors don't change the pixel
+    pixmap
+        .set_pixel(1, 0, Color::from_rgba(0xFF, 0, 0, 0))
+        .unwrap();
+    assert_eq!(pixmap.pixel(1, 0).unwrap(), Color::from_rgb(0, 0, 0xFF));
+
+    // Semi-transparent colors are blended
+    pixmap
+        .set_pixel(1, 0, Color::from_rgba(0xFF, 0, 0, 0x80))
+        .unwrap();
+    let mut expected = Color::from_rgb(0, 0, 0xFF);
+    expected.blend(Color::from_rgba(0xFF, 0, 0, 0x80));
+    assert_eq!(pixmap.pixel(1, 0).unwrap(), expected);
+}
+
+#[test]
+fn set_pixel_opaque() {
+    let pixmap = Pixmap::new(1, 1);
+    pixmap.set_pixel(0, 0, Color::from_rgb(0, 0, 0xFF)).unwrap();
+
+    // The color is stored as is, without blending
+    let color = Color::from_rgba(0xFF, 0, 0, 0x80);
+    pixmap.set_pixel_opaque(0, 0, color).unwrap();
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), color);
+}
+
+#[test]
+fn wrap() {
+    let pixmap = Pixmap::new(4, 3).with_wrap(true);
+    let red = Color::from_rgb(0xFF, 0, 0);
+
+    // Coordinates beyond the edges land at the modular coordinate
+    pixmap.set_pixel(5, 7, red).unwrap();
+    assert_eq!(pixmap.pixel(1, 1).unwrap(), red);
+    assert_eq!(pixmap.pixel(9, 4).unwrap(), red);
+
+    // Only the rectangle origin wraps, it is still clipped at the edges
+    assert_eq!(pixmap.fill_rect(6, 3, 4, 1, red).unwrap(), 2);
+    assert_eq!(pixmap.pixel(3, 0).unwrap(), red);
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), Color::black());
+
+    // Without wrapping, the same coordinates are out of bound
+    let pixmap = Pixmap::new(4, 3);
+    assert!(pixmap.set_pixel(5, 7, red).is_err());
+}
+
+#[test]
+fn erase_pixel() {
+    let (a, b) = (Color::black(), Color::from_rgb(0xFF, 0xFF, 0xFF));
+    let pixmap = Pixmap::new_with_pattern(4, 4, Pattern::Checkerboard { size: 2, a, b });
+    let red = Color::from_rgb(0xFF, 0, 0);
+
+    // Erased pixels are reset to the pattern
+    pixmap.set_pixel(0, 0, red).unwrap();
+    pixmap.set_pixel(2, 0, red).unwrap();
+    pixmap.erase_pixel(0, 0).unwrap();
+    pixmap.erase_pixel(2, 0).unwrap();
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), a);
+    assert_eq!(pixmap.pixel(2, 0).unwrap(), b);
+
+    // Erasing touches the pixel map
+    let pixmap = Pixmap::new(4, 4);
+    pixmap.erase_pixel(0, 0).unwrap();
+    assert!(pixmap.is_touched());
+
+    // Without pattern, erased pixels are black
+    pixmap.set_pixel(1, 1, red).unwrap();
+    pixmap.erase_pixel(1, 1).unwrap();
+    assert_eq!(pixmap.pixel(1, 1).unwrap(), Color::black());
+    assert!(pixmap.erase_pixel(4, 0).is_err());
+}
+
+#[test]
+fn cooldown() {
+    let cooldown = Cooldown::new((8, 8), Duration::from_millis(100), 4);
+
+    // Rapid rewrites within the region are dropped
+    assert!(cooldown.try_start_at(0, 0, 0).is_some());
+    assert!(cooldown.try_start_at(0, 0, 10).is_none());
+    assert!(cooldown.try_start_at(3, 3, 99).is_none());
+
+    // Other regions cool down separately
+    assert!(cooldown.try_start_at(4, 0, 10).is_some());
+    assert!(cooldown.try_start_at(7, 7, 10).is_some());
+
+    // Writes are allowed again once cooled down
+    assert!(cooldown.try_start_at(3, 3, 100).is_some());
+    assert!(cooldown.try_start_at(0, 0, 150).is_none());
+
+    // A writer overtaken by a later one is dropped, without moving the time back
+    assert!(cooldown.try_start_at(4, 4, 500).is_some());
+    assert!(cooldown.try_start_at(4, 4, 450).is_none());
+    assert!(cooldown.try_start_at(4, 4, 550).is_none());
+    assert!(cooldown.try_start_at(4, 4, 600).is_some());
+
+    // An undone cooldown lets the next write through
+    let started = cooldown.try_start_at(0, 4, 0).unwrap();
+    cooldown.undo(started);
+    assert!(cooldown.try_start_at(0, 4, 10).is_some());
+    assert!(cooldown.try_start_at(0, 4, 20).is_none());
+}
+
+#[test]
+fn cooldown_writes() {
+    let red = Color::from_rgb(0xFF, 0, 0);
+    let blue = Color::from_rgb(0, 0, 0xFF);
+    let pixmap = Pixmap::new(4, 4).with_cooldown(Some(Duration::from_secs(3600)), 1);
+
+    // Every kind of write to a cooling down pixel is dropped
+    assert!(pixmap.set_pixel(0, 0, red).unwrap());
+    assert!(!pixmap.set_pixel(0, 0, blue).unwrap());
+    assert!(!pixmap.erase_pixel(0, 0).unwrap());
+    assert!(!pixmap.compare_and_set(0, 0, red, blue).unwrap());
+    assert_eq!(pixmap.fill_rect(0, 0, 2, 1, blue).unwrap(), 1);
+    assert_eq!(pixmap.pixel(0, 0).unwrap(), red);
+    assert_eq!(pixmap.pixel(1, 0).unwrap(), blue);
+    assert!(pixmap.set_pixel(4, 0, red).is_err());
+
+    // Without cooldown, every write is allowed
+    let pixmap = Pixmap::new(4, 4);
+    assert!(pixmap.set_pixel(0, 0, red).unwrap());
+    assert!(pixmap.set_pixel(0, 0, blue).unwrap());
+}
+
+#[test]
+fn blend_image() {
+    let pixmap = Pixmap::new(4, 4);
+    pixmap
+        .fill_rect(0, 0, 4, 4, Color::from_rgb(0, 0, 0xFF))
+        .unwrap();
+
+    // Half transparent red on the left, transparent on the right
+    let img = RgbaImage::from_fn(2, 3, |x, _| match x {
+        0 => image::Rgba([0xFF, 0, 0, 0x80]),
+        _ => image::Rgba([0xFF, 0, 0, 0]),
+    });
+    pixmap.blend_image(&img, 1, 2);
+
+    for y in 2..4 {
+        let color = pixmap.pixel(1, y).unwrap();
+        assert_eq!((color.red(), color.green(), color.blue()), (0x80, 0, 0x7F));
+    }
+    assert_eq!(pixmap.pixel(2, 2).unwrap(), Color::from_rgb(0, 0, 0xFF));
+    assert_eq!(pixmap.pixel(1, 1).unwrap(), Color::from_rgb(0, 0, 0xFF));
+
+    // Entirely beyond the edges
+    pixmap.blend_image(&img, 4, 0);
+}
@@ -0,0 +1,103 @@
+use parking_lot::RwLock;
+
+use crate::pixmap::Pixmap;
+
+/// A downscaled copy of a pixel map, for remote viewing.
+///
+/// Each preview pixel is the average of a block of `scale` by `scale` pixels
+/// in the pixel map, a box filter. The preview isn't updated when pixels are
+/// set, call `update` periodically instead.
+pub struct Preview {
+    /// The downscale factor.
+    scale: usize,
+
+    /// Preview dimensions, width and height
+    dimensions: (usize, usize),
+
+    /// The preview as RGBA bytes.
+    data: RwLock<Vec<u8>>,
+}
+
+impl Preview {
+    /// Construct a new preview for a pixel map with the given dimensions,
+    /// downscaled by the given factor.
+    ///
+    /// The preview dimensions are rounded up, blocks at the right and bottom
+    /// edge average only the pixels they cover.
+    pub fn new(width: usize, height: usize, scale: usize) -> Self {
+        let scale = scale.max(1);
+        let dimensions = (width.div_ceil(scale), height.div_ceil(scale));
+
+        Preview {
+            scale,
+            dimensions,
+            data: RwLock::new(vec![0; dimensions.0 * dimensions.1 * 4]),
+        }
+    }
+
+    /// Get the downscale factor.
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Get the dimensions of the preview.
+    pub fn dimensions(&self) -> (usize, usize) {
+        self.dimensions
+    }
+
+    /// Update the preview from the current contents of the given pixel map.
+    pub fn update(&self, pixmap: &Pixmap) {
+        let (width, _) = self.dimensions;
+
+        // Sum the channels and count the pixels of each block
+        let mut sums = vec![[0u64; 4]; self.dimensions.0 * self.dimensions.1];
+        let mut counts = vec![0u64; sums.len()];
+        for (x, y, color) in pixmap.iter_pixels() {
+            let i = (y / self.scale) * width + x / self.scale;
+            if i >= sums.len() {
+                continue;
+            }
+            let sum = &mut sums[i];
+            sum[0] += color.red() as u64;
+            sum[1] += color.green() as u64;
+            sum[2] += color.blue() as u64;
+            sum[3] += color.alpha() as u64;
+            counts[i] += 1;
+        }
+
+        let data = sums
+            .iter()
+            .zip(&counts)
+            .flat_map(|(sum, count)| sum.map(|c| c.checked_div(*count).unwrap_or(0) as u8))
+            .collect();
+        *self.data.write() = data;
+    }
+
+    /// Get a copy of the preview, as RGBA bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.data.read().clone()
+    }
+}
+
+#[test]
+fn update() {
+    use crate::color::Color;
+
+    let pixmap = Pixmap::new(5, 3);
+    pixmap.set_pixel(0, 0, Color::from_rgb(0xFF, 0, 0)).unwrap();
+    pixmap.set_pixel(1, 1, Color::from_rgb(0, 0xFF, 0)).unwrap();
+    pixmap.set_pixel(4, 2, Color::from_rgb(0, 0, 0xFF)).unwrap();
+
+    let preview = Preview::new(5, 3, 2);
+    assert_eq!(preview.dimensions(), (3, 2));
+    preview.update(&pixmap);
+
+    let bytes = preview.to_bytes();
+    assert_eq!(bytes.len(), 3 * 2 * 4);
+
+    // The top left block averages four pixels
+    assert_eq!(bytes[..4], [0x3F, 0x3F, 0, 0xFF]);
+
+    // The bottom right block only covers a single pixel
+    assert_eq!(bytes[20..], [0, 0, 0xFF, 0xFF]);
+}
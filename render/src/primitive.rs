@@ -7,6 +7,39 @@ pub fn create_quad_max() -> Model {
     create_quad((-1f32, -1f32), (2f32, 2f32))
 }
 
+/// Generate a rectangular frame model, along the inside of the edges of the
+/// given rectangle, with the given horizontal and vertical edge width.
+pub fn create_frame(pos: (f32, f32), size: (f32, f32), width: (f32, f32)) -> Model {
+    // A frame wider than the rectangle covers it entirely
+    let width = (width.0.min(size.0 / 2f32), width.1.min(size.1 / 2f32));
+
+    // The bottom, top, left and right edge
+    let edges = [
+        (pos, (size.0, width.1)),
+        ((pos.0, pos.1 + size.1 - width.1), (size.0, width.1)),
+        (pos, (width.0, size.1)),
+        ((pos.0 + size.0 - width.0, pos.1), (width.0, size.1)),
+    ];
+
+    let mut vertices = Vec::with_capacity(edges.len() * 4);
+    let mut indices = Vec::with_capacity(edges.len() * 6);
+    for (pos, size) in edges {
+        let base = vertices.len() as u16;
+        vertices.extend_from_slice(&[
+            Vertex { pos: [ pos.0 + size.0, pos.1         ], uv: [0.0, 0.0] },
+            Vertex { pos: [ pos.0         , pos.1         ], uv: [0.0, 0.0] },
+            Vertex { pos: [ pos.0         , pos.1 + size.1], uv: [0.0, 0.0] },
+            Vertex { pos: [ pos.0 + size.0, pos.1 + size.1], uv: [0.0, 0.0] },
+        ]);
+        indices.extend_from_slice(&[
+            base, base + 1, base + 2,
+            base + 2, base + 3, base,
+        ]);
+    }
+
+    Model::from(vertices, indices)
+}
+
 /// Generate a quad primitive model.
 pub fn create_quad(pos: (f32, f32), size: (f32, f32)) -> Model {
     Model::from(
@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use crate::color::Color;
+
+/// Renderer configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Render in a fullscreen window.
+    pub fullscreen: bool,
+
+    /// How to go fullscreen, if `fullscreen` is set.
+    pub fullscreen_mode: FullscreenMode,
+
+    /// Draw a border of the given width in canvas pixels and color, along the
+    /// inside of the canvas edges.
+    pub border: Option<(u32, Color)>,
+
+    /// Scale the canvas using nearest neighbor filtering, instead of linear
+    /// filtering.
+    pub nearest_neighbor: bool,
+
+    /// Stats font size in pixels.
+    pub stats_size: u8,
+
+    /// Stats offset from the window corner in pixels.
+    pub stats_offset: (u32, u32),
+
+    /// Stats padding in pixels.
+    pub stats_padding: i32,
+
+    /// Stats column spacing in pixels.
+    pub stats_col_spacing: i32,
+
+    /// Extra vertical gap between stats rows in pixels.
+    pub stats_row_gap: i32,
+
+    /// Draw a semi-transparent background behind the stats.
+    pub stats_background: bool,
+
+    /// Draw the scoreboard text in the center of the window, with this font
+    /// size in pixels. If `None`, no scoreboard is drawn.
+    pub scoreboard_size: Option<u8>,
+
+    /// Preserve the canvas aspect ratio with black bars, instead of stretching
+    /// it to fill the window.
+    pub preserve_aspect: bool,
+
+    /// Don't stretch the canvas, render it at the largest integer scale that
+    /// fits the window, centered on a black background. Mainly useful for
+    /// small canvases on large fullscreen windows.
+    ///
+    /// Takes precedence over `preserve_aspect`.
+    pub no_stretch: bool,
+
+    /// Create the window hidden, and show it once the first pixel is set.
+    ///
+    /// With `fullscreen`, the window only goes fullscreen once it is shown.
+    pub start_hidden: bool,
+
+    /// Keep the canvas aspect ratio when the window is resized, by adjusting
+    /// the window size. Not applied in fullscreen.
+    ///
+    /// Some platforms, such as tiling window managers and Wayland, may ignore
+    /// the requested size. The canvas is letterboxed in that case.
+    pub lock_aspect: bool,
+
+    /// The position of the window on the desktop in physical pixels. Not
+    /// applied in fullscreen. If `None`, the OS chooses the position.
+    ///
+    /// A warning is printed if the position is not on any monitor.
+    pub window_position: Option<(i32, i32)>,
+
+    /// Allow the window to be resized by the user. The canvas and stats are
+    /// fitted to the new window size.
+    pub resizable: bool,
+
+    /// Synchronize presenting frames with the display refresh rate. Disable
+    /// for the lowest latency, at the cost of tearing.
+    pub vsync: bool,
+
+    /// The number of samples per pixel used for multisample anti-aliasing.
+    ///
+    /// This is part of the graphics context, changing it requires restarting
+    /// the renderer.
+    pub msaa: u16,
+
+    /// Recreate the window and graphics context if no frame was presented for
+    /// this long, for example because the graphics driver stalled. If `None`,
+    /// the renderer is not watched.
+    ///
+    /// Recovery only works if the render loop gets control back. On a truly
+    /// hung driver, presenting the frame never returns, so the window can't be
+    /// recreated. The stall is still reported, and clients stay served.
+    pub watchdog: Option<Duration>,
+}
+
+/// How to go fullscreen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullscreenMode {
+    /// Cover the monitor with a borderless window, at the current video mode.
+    /// Switching to other windows is fast, but the compositor may add latency
+    /// and the refresh rate can't be chosen.
+    Borderless,
+
+    /// Take exclusive control of the monitor, switching to the video mode
+    /// closest to the canvas size at the highest refresh rate. This may
+    /// perform better, but switching away is slow and may flicker. Falls back
+    /// to borderless if no video mode is available.
+    Exclusive,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            fullscreen: false,
+            fullscreen_mode: FullscreenMode::Borderless,
+            border: None,
+            nearest_neighbor: false,
+            stats_size: 20,
+            stats_offset: (10, 10),
+            stats_padding: 12,
+            stats_col_spacing: 20,
+            stats_row_gap: 0,
+            stats_background: true,
+            scoreboard_size: None,
+            preserve_aspect: false,
+            no_stretch: false,
+            start_hidden: false,
+            lock_aspect: false,
+            window_position: None,
+            resizable: true,
+            vsync: true,
+            msaa: 1,
+            watchdog: None,
+        }
+    }
+}
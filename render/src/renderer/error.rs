@@ -0,0 +1,29 @@
+use std::fmt;
+
+/// An error that prevents the renderer from starting.
+#[derive(Debug)]
+pub enum RenderError {
+    /// The window event loop could not be created, for example on a headless
+    /// system without a display server.
+    EventLoop,
+
+    /// The window or graphics context could not be created, for example due
+    /// to a broken or missing GL driver.
+    Context(String),
+
+    /// The shaders or text renderer could not be set up on the graphics
+    /// context.
+    Pipeline(String),
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RenderError::EventLoop => write!(f, "failed to create window event loop"),
+            RenderError::Context(err) => write!(f, "failed to create graphics context: {}", err),
+            RenderError::Pipeline(err) => write!(f, "failed to create render pipeline: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
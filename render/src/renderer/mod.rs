@@ -1,6 +1,12 @@
+mod config;
+mod error;
 mod ref_values;
 pub mod stats_renderer;
 
+pub use config::{Config, FullscreenMode};
+pub use error::RenderError;
+
+use std::cmp::Reverse;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -9,18 +15,22 @@ use gfx::handle::ShaderResourceView;
 use gfx::texture::{AaMode, Kind, Mipmap, SamplerInfo};
 use gfx::traits::FactoryExt;
 use gfx_glutin::{ContextBuilderExt, WindowInitExt, WindowUpdateExt};
-use glutin::dpi::LogicalSize;
+use glutin::dpi::{LogicalSize, PhysicalPosition, PhysicalSize};
 use glutin::event::{Event, VirtualKeyCode, WindowEvent};
 use glutin::{ContextBuilder, GlProfile, GlRequest, Robustness};
 
 use gfx::{self, *};
 use glutin::event_loop::{ControlFlow, EventLoop};
+use glutin::monitor::{MonitorHandle, VideoMode};
+use glutin::platform::run_return::EventLoopExtRunReturn;
 use glutin::window::{Fullscreen, WindowBuilder};
 use old_school_gfx_glutin_ext as gfx_glutin;
+use parking_lot::Mutex;
 
 use crate::fps_counter::FpsCounter;
+use crate::model::Model;
 use crate::pixmap::Pixmap;
-use crate::primitive::create_quad_max;
+use crate::primitive::{create_frame, create_quad, create_quad_max};
 use crate::vertex::Vertex;
 use stats_renderer::{Corner, StatsRenderer};
 
@@ -30,6 +40,9 @@ pub(crate) type DepthFormat = gfx::format::DepthStencil;
 type F = gfx_device_gl::Factory;
 pub(crate) type R = gfx_device_gl::Resources;
 
+/// A position and size in normalized device coordinates.
+type Rect = ((f32, f32), (f32, f32));
+
 /// Black color definition with 4 channels.
 const BLACK: [f32; 4] = [0.0, 0.0, 0.0, 1.0];
 
@@ -42,6 +55,19 @@ gfx_defines! {
     }
 }
 
+// Border shader data pipeline
+gfx_defines! {
+    pipeline border_pipe {
+        vbuf: gfx::VertexBuffer<Vertex> = (),
+        color: gfx::Global<[f32; 4]> = "u_Color",
+        out: gfx::RenderTarget<ColorFormat> = "Target0",
+    }
+}
+
+/// A hook called after each rendered frame, with the pixel map and the frame
+/// number, see `Renderer::with_frame_hook`.
+pub type FrameHook = Box<dyn FnMut(&Pixmap, u64)>;
+
 /// The renderer.
 pub struct Renderer<'a> {
     // The window title.
@@ -53,12 +79,21 @@ pub struct Renderer<'a> {
     // Used to render statistics on the canvas.
     stats: StatsRenderer<F>,
 
+    // Used to render a scoreboard in the center of the canvas.
+    scoreboard: StatsRenderer<F>,
+
     // Glutin events loop.
     events_loop: EventLoop<()>,
 
     // A FPS counter for the renderer.
     #[allow(unused)]
     fps: FpsCounter,
+
+    // A hook called after each rendered frame.
+    frame_hook: Option<FrameHook>,
+
+    // The time the last frame was presented.
+    last_frame: Arc<Mutex<Instant>>,
 }
 
 impl<'a> Renderer<'a> {
@@ -66,48 +101,157 @@ impl<'a> Renderer<'a> {
     ///
     /// The renderer window title should be given to `title`.
     /// The pixel map that is rendered should be given to `pixmap`.
+    ///
+    /// Panics if the window event loop can't be created, see `try_new`.
     pub fn new(title: &'a str, pixmap: Arc<Pixmap>) -> Renderer<'a> {
+        Self::try_new(title, pixmap).expect("failed to initialize renderer")
+    }
+
+    /// Construct a new renderer, or return an error if the window event loop
+    /// can't be created, for example on a headless system.
+    pub fn try_new(title: &'a str, pixmap: Arc<Pixmap>) -> Result<Renderer<'a>, RenderError> {
+        // The event loop panics instead of returning an error on failure
+        let events_loop =
+            std::panic::catch_unwind(EventLoop::new).map_err(|_| RenderError::EventLoop)?;
+
         // Construct and return the renderer
-        Renderer {
+        Ok(Renderer {
             title,
             pixmap,
             stats: StatsRenderer::new(Corner::TopLeft),
-            events_loop: EventLoop::new(),
+            scoreboard: StatsRenderer::new(Corner::Center),
+            events_loop,
             fps: FpsCounter::default(),
+            frame_hook: None,
+            last_frame: Arc::new(Mutex::new(Instant::now())),
+        })
+    }
+
+    /// Set a hook that is called after each rendered frame, with the pixel
+    /// map and the frame number, starting at 0.
+    ///
+    /// The hook is called on the render thread, right after the frame is
+    /// presented. It must be fast, as any time spent in it directly delays
+    /// rendering the next frame. Move heavy work, such as encoding, to
+    /// another thread.
+    pub fn with_frame_hook(mut self, hook: impl FnMut(&Pixmap, u64) + 'static) -> Self {
+        self.frame_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Run the renderer with the given configuration, until the window is
+    /// closed or `keep_running` is cleared.
+    ///
+    /// Returns an error right away if the graphics context can't be set up.
+    /// With a watchdog configured, the window and graphics context are
+    /// recreated when rendering stalls, see `Config::watchdog`.
+    pub fn run(mut self, config: Config, keep_running: Arc<AtomicBool>) -> Result<(), RenderError> {
+        let recreate = Arc::new(AtomicBool::new(false));
+        if let Some(timeout) = config.watchdog {
+            self.spawn_watchdog(timeout, recreate.clone(), keep_running.clone());
         }
+
+        let mut frame = 0;
+        while self.run_context(config.clone(), &keep_running, &recreate, &mut frame)? {
+            eprintln!("Recreating the window and graphics context");
+        }
+
+        Ok(())
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn run(
-        mut self,
-        fullscreen: bool,
-        nearest_neighbor: bool,
-        stats_size: u8,
-        stats_offset: (u32, u32),
-        stats_padding: i32,
-        stats_col_spacing: i32,
-        keep_running: Arc<AtomicBool>,
-    ) {
+    /// Create the window and graphics context, and render until the window is
+    /// closed, `keep_running` is cleared or `recreate` is set.
+    ///
+    /// Returns whether the window and graphics context should be recreated.
+    fn run_context(
+        &mut self,
+        config: Config,
+        keep_running: &AtomicBool,
+        recreate: &AtomicBool,
+        frame: &mut u64,
+    ) -> Result<bool, RenderError> {
+        // Setting up the context doesn't count as a stall
+        *self.last_frame.lock() = Instant::now();
+
+        let Config {
+            fullscreen,
+            fullscreen_mode,
+            border,
+            nearest_neighbor,
+            stats_size,
+            stats_offset,
+            stats_padding,
+            stats_col_spacing,
+            stats_row_gap,
+            stats_background,
+            scoreboard_size,
+            preserve_aspect,
+            no_stretch,
+            start_hidden,
+            lock_aspect,
+            window_position,
+            resizable,
+            vsync,
+            msaa,
+            watchdog: _,
+        } = config;
+
+        // When the aspect lock can't be enforced, fall back to letterboxing
+        let preserve_aspect = preserve_aspect || lock_aspect;
+
         // Get the size of the canvas
         let size = self.pixmap.dimensions();
 
         // Select a monitor for full screening
         // TODO: allow selecting a specific monitor
         let monitor = if fullscreen {
-            Some(Fullscreen::Borderless(self.events_loop.primary_monitor()))
+            let monitor = self.events_loop.primary_monitor();
+            let video_mode = match fullscreen_mode {
+                FullscreenMode::Borderless => None,
+                FullscreenMode::Exclusive => {
+                    let mode = monitor
+                        .as_ref()
+                        .and_then(|monitor| Renderer::video_mode(monitor, size));
+                    if mode.is_none() {
+                        eprintln!(
+                            "Warning: no video mode for exclusive fullscreen, using borderless"
+                        );
+                    }
+                    mode
+                }
+            };
+            Some(match video_mode {
+                Some(mode) => Fullscreen::Exclusive(mode),
+                None => Fullscreen::Borderless(monitor),
+            })
         } else {
             None
         };
 
         // Define a window builder
-        let builder = WindowBuilder::new()
+        let mut builder = WindowBuilder::new()
             .with_title(self.title.to_string())
             .with_fullscreen(monitor)
+            .with_visible(!start_hidden)
+            .with_resizable(resizable)
             .with_inner_size(LogicalSize {
                 width: size.0 as f64,
                 height: size.1 as f64,
             });
 
+        // Place the window at the given position
+        if let (Some((x, y)), false) = (window_position, fullscreen) {
+            let on_monitor = self.events_loop.available_monitors().any(|monitor| {
+                let (pos, size) = (monitor.position(), monitor.size());
+                (pos.x..pos.x + size.width as i32).contains(&x)
+                    && (pos.y..pos.y + size.height as i32).contains(&y)
+            });
+            if !on_monitor {
+                eprintln!("Warning: window position {},{} is not on any monitor", x, y);
+            }
+            builder = builder.with_position(PhysicalPosition::new(x, y));
+        }
+
         // Define the graphics context
         // TODO: properly configure this context
         let (window, mut device, mut factory, mut main_color, mut main_depth) =
@@ -116,11 +260,11 @@ impl<'a> Renderer<'a> {
                 .with_gl(GlRequest::Latest)
                 .with_gl_robustness(Robustness::TryRobustNoResetNotification)
                 .with_gl_profile(GlProfile::Core)
-                .with_multisampling(1)
+                .with_multisampling(msaa)
                 .with_gfx_color_depth::<ColorFormat, DepthFormat>()
-                .with_vsync(true)
+                .with_vsync(vsync)
                 .build_windowed(builder, &self.events_loop)
-                .unwrap()
+                .map_err(|err| RenderError::Context(err.to_string()))?
                 .init_gfx();
 
         let my_window_id = window.window().id();
@@ -140,21 +284,18 @@ impl<'a> Renderer<'a> {
                 include_bytes!("../../shaders/screen.glslf"),
                 pipe::new(),
             )
-            .unwrap();
+            .map_err(|err| RenderError::Pipeline(format!("{:?}", err)))?;
 
         // Create a full screen quad, plane, that is rendered on
         let plane = create_quad_max();
-        let (vertex_buffer, slice) = plane.create_vertex_buffer(&mut factory);
+        let (vertex_buffer, mut slice) = plane.create_vertex_buffer(&mut factory);
 
         // Define the texture kind
         let texture_kind = Kind::D2(size.0 as u16, size.1 as u16, AaMode::Single);
 
-        // Create a clone of the pixel map
-        let mut pixmap = (*self.pixmap).clone();
-
         // Create a base image
         let base_image = (
-            Renderer::create_texture(&mut factory, pixmap.as_bytes(), texture_kind),
+            Renderer::create_texture(&mut factory, self.pixmap.as_bytes(), texture_kind),
             factory.create_sampler_linear(),
         );
 
@@ -179,12 +320,88 @@ impl<'a> Renderer<'a> {
                 stats_padding,
                 stats_col_spacing,
             )
-            .expect("failed to initialize stats text renderer");
+            .map_err(|err| RenderError::Pipeline(format!("{:?}", err)))?;
+        self.stats.set_background(stats_background);
+        self.stats.set_row_gap(stats_row_gap);
+
+        // Build the scoreboard renderer, an independent overlay
+        if let Some(scoreboard_size) = scoreboard_size {
+            self.scoreboard
+                .init(
+                    factory.clone(),
+                    dimensions,
+                    main_color.clone(),
+                    main_depth.clone(),
+                    scoreboard_size,
+                    (0, 0),
+                    stats_padding,
+                    stats_col_spacing,
+                )
+                .map_err(|err| RenderError::Pipeline(format!("{:?}", err)))?;
+        }
+
+        // The position and size of the canvas in the window, in normalized
+        // device coordinates
+        let mut canvas_rect = ((-1f32, -1f32), (2f32, 2f32));
+
+        // Fit the canvas in the initial window
+        if preserve_aspect || no_stretch {
+            let inner = window.window().inner_size();
+            let origin;
+            (canvas_rect, origin) =
+                Renderer::letterbox(size, (inner.width as f32, inner.height as f32), no_stretch);
+            (data.vbuf, slice) =
+                create_quad(canvas_rect.0, canvas_rect.1).create_vertex_buffer(&mut factory);
+
+            // Without stretching, stats stay anchored to the window corner
+            if !no_stretch {
+                self.stats.set_origin(origin);
+            }
+        }
+
+        // Build the border pipeline, drawn along the canvas edges
+        let mut border = match border {
+            Some((width, color)) => {
+                let pso = factory
+                    .create_pipeline_simple(
+                        include_bytes!("../../shaders/border.glslv"),
+                        include_bytes!("../../shaders/border.glslf"),
+                        border_pipe::new(),
+                    )
+                    .map_err(|err| RenderError::Pipeline(format!("{:?}", err)))?;
+                let (vbuf, slice) =
+                    Renderer::border(size, canvas_rect, width).create_vertex_buffer(&mut factory);
+                let data = border_pipe::Data {
+                    vbuf,
+                    color: [
+                        color.red() as f32 / 255f32,
+                        color.green() as f32 / 255f32,
+                        color.blue() as f32 / 255f32,
+                        1f32,
+                    ],
+                    out: main_color.clone(),
+                };
+                Some((pso, slice, data, width))
+            }
+            None => None,
+        };
 
         let mut next_frame_time = Instant::now();
+        let mut hidden = start_hidden;
+        let mut last_size = window.window().inner_size();
 
-        self.events_loop.run(move |event, _target, control_flow| {
-            if !keep_running.load(Ordering::SeqCst) {
+        // Borrow the fields separately, the event loop is borrowed while running
+        let Renderer {
+            events_loop,
+            pixmap,
+            stats,
+            scoreboard,
+            frame_hook,
+            last_frame,
+            ..
+        } = self;
+        events_loop.run_return(move |event, _target, control_flow| {
+            if !keep_running.load(Ordering::SeqCst) || recreate.load(Ordering::SeqCst) {
                 *control_flow = ControlFlow::Exit;
                 return;
             }
@@ -220,22 +437,54 @@ impl<'a> Renderer<'a> {
                 ..
             } = event
             {
+                // Request a window size with the canvas aspect ratio
+                if lock_aspect && !fullscreen {
+                    if let Some(locked) = Renderer::lock_aspect(size, last_size, s) {
+                        window.window().set_inner_size(locked);
+                    }
+                }
+                last_size = s;
+
                 let dimensions = (s.width as f32, s.height as f32);
                 // Update the main color and depth
                 window.update_gfx(&mut main_color, &mut main_depth);
+                if let Some((_, _, data, _)) = &mut border {
+                    data.out = main_color.clone();
+                }
 
                 // Update the pixel texture
                 window.update_gfx(&mut data.out, &mut data_depth);
 
                 // Update the stats text
-                self.stats.update_views(&window, dimensions);
+                stats.update_views(&window, dimensions);
+                scoreboard.update_views(&window, dimensions);
+
+                // Fit the canvas in the resized window
+                if preserve_aspect || no_stretch {
+                    let (canvas_rect, origin) = Renderer::letterbox(size, dimensions, no_stretch);
+                    (data.vbuf, slice) = create_quad(canvas_rect.0, canvas_rect.1)
+                        .create_vertex_buffer(&mut factory);
+                    if !no_stretch {
+                        stats.set_origin(origin);
+                    }
+
+                    // Keep the border on the canvas edges
+                    if let Some((_, slice, data, width)) = &mut border {
+                        (data.vbuf, *slice) = Renderer::border(size, canvas_rect, *width)
+                            .create_vertex_buffer(&mut factory);
+                    }
+                }
+            }
+
+            // Show the window once drawing starts
+            if hidden && pixmap.is_touched() {
+                window.window().set_visible(true);
+                hidden = false;
             }
 
             // We don't want to re-render the whole frame each time someone moves their mouse, so let's
             // put a time limit on it
             if Instant::now() > next_frame_time || event == Event::MainEventsCleared {
-                let mut pixmap = (*self.pixmap).clone();
-
                 let sampler_info: SamplerInfo = SamplerInfo::new(
                     match nearest_neighbor {
                         true => gfx::texture::FilterMethod::Scale,
@@ -254,15 +503,28 @@ impl<'a> Renderer<'a> {
                 // Draw through the pipeline
                 encoder.draw(&slice, &pso, &data);
 
-                // Draw the stats
-                self.stats.draw(&mut encoder, &main_color).unwrap();
+                // Draw the border over the canvas, under the stats
+                if let Some((pso, slice, data, _)) = &border {
+                    encoder.draw(slice, pso, data);
+                }
+
+                // Draw the stats and the scoreboard
+                stats.draw(&mut encoder, &main_color).unwrap();
+                scoreboard.draw(&mut encoder, &main_color).unwrap();
 
                 encoder.flush(&mut device);
 
                 // Swap the frame buffers
                 window.swap_buffers().unwrap();
+                *last_frame.lock() = Instant::now();
 
                 device.cleanup();
+
+                if let Some(hook) = frame_hook.as_mut() {
+                    hook(pixmap, *frame);
+                }
+                *frame += 1;
+
                 // Reserve at most 1 ms for processing input events
                 next_frame_time = Instant::now() + Duration::from_millis(1);
             }
@@ -273,25 +535,142 @@ impl<'a> Renderer<'a> {
                 *control_flow = ControlFlow::WaitUntil(next_frame_time);
             }
         });
+
+        Ok(recreate.swap(false, Ordering::SeqCst) && keep_running.load(Ordering::SeqCst))
+    }
+
+    /// Spawn a thread that sets `recreate` when no frame was presented for the
+    /// given timeout, until `keep_running` is cleared.
+    fn spawn_watchdog(
+        &self,
+        timeout: Duration,
+        recreate: Arc<AtomicBool>,
+        keep_running: Arc<AtomicBool>,
+    ) {
+        let last_frame = self.last_frame.clone();
+        std::thread::spawn(move || {
+            // Report each stall once, until a frame is presented again
+            let mut stalled = false;
+            while keep_running.load(Ordering::SeqCst) {
+                std::thread::sleep((timeout / 4).max(Duration::from_millis(10)));
+                let elapsed = last_frame.lock().elapsed();
+                if elapsed < timeout {
+                    stalled = false;
+                } else if !stalled {
+                    eprintln!(
+                        "Error: no frame rendered for {:.1}s, render loop stalled",
+                        elapsed.as_secs_f32()
+                    );
+                    recreate.store(true, Ordering::SeqCst);
+                    stalled = true;
+                }
+            }
+        });
     }
 
     /// This will run forever, or until an escape character is input
     pub fn run_default(self) {
-        self.run(
-            false,
-            false,
-            20,
-            (10, 10),
-            12,
-            20,
-            Arc::new(AtomicBool::new(true)),
-        );
+        self.run(Config::default(), Arc::new(AtomicBool::new(true)))
+            .expect("failed to run renderer");
     }
 
     pub fn stats(&self) -> &StatsRenderer<F> {
         &self.stats
     }
 
+    /// Get the scoreboard renderer. Its text is only drawn if the scoreboard
+    /// is enabled in the configuration.
+    pub fn scoreboard(&self) -> &StatsRenderer<F> {
+        &self.scoreboard
+    }
+
+    /// Get the time the last frame was presented, updated by the render loop.
+    pub fn last_frame(&self) -> Arc<Mutex<Instant>> {
+        self.last_frame.clone()
+    }
+
+    /// Fit the canvas within the window, preserving its aspect ratio.
+    ///
+    /// If `integer` is set, the canvas is only scaled up by whole multiples.
+    /// Returns the position and size of the fitted canvas in normalized device
+    /// coordinates, and the offset of the canvas in the window in pixels.
+    fn letterbox(canvas: (usize, usize), window: (f32, f32), integer: bool) -> (Rect, (u32, u32)) {
+        // Find the largest scale at which the canvas fits the window
+        let mut scale = (window.0 / canvas.0 as f32).min(window.1 / canvas.1 as f32);
+        if integer && scale >= 1f32 {
+            scale = scale.floor();
+        }
+        let (w, h) = (canvas.0 as f32 * scale, canvas.1 as f32 * scale);
+
+        let rect = (
+            (-w / window.0, -h / window.1),
+            (w / window.0 * 2f32, h / window.1 * 2f32),
+        );
+        let origin = (
+            ((window.0 - w) / 2f32) as u32,
+            ((window.1 - h) / 2f32) as u32,
+        );
+
+        (rect, origin)
+    }
+
+    /// Build a border model of the given width in canvas pixels, along the
+    /// inside of the edges of the canvas at the given position and size in
+    /// normalized device coordinates.
+    ///
+    /// The border scales with the canvas, so it stays on the canvas edges.
+    fn border(canvas: (usize, usize), rect: Rect, width: u32) -> Model {
+        let (pos, size) = rect;
+        create_frame(
+            pos,
+            size,
+            (
+                size.0 * width as f32 / canvas.0 as f32,
+                size.1 * width as f32 / canvas.1 as f32,
+            ),
+        )
+    }
+
+    /// Get the window size to request to keep the canvas aspect ratio, after
+    /// the window was resized from `last` to `new`.
+    ///
+    /// The dimension that changed most is kept. Returns `None` if the new
+    /// size already has the canvas aspect ratio.
+    fn lock_aspect(
+        canvas: (usize, usize),
+        last: PhysicalSize<u32>,
+        new: PhysicalSize<u32>,
+    ) -> Option<PhysicalSize<u32>> {
+        let aspect = canvas.0 as f64 / canvas.1 as f64;
+        let locked = if new.width.abs_diff(last.width) >= new.height.abs_diff(last.height) {
+            PhysicalSize::new(new.width, (new.width as f64 / aspect).round() as u32)
+        } else {
+            PhysicalSize::new((new.height as f64 * aspect).round() as u32, new.height)
+        };
+
+        // Allow rounding differences, to prevent resizing back and forth
+        if locked.width.abs_diff(new.width) <= 1 && locked.height.abs_diff(new.height) <= 1 {
+            None
+        } else {
+            Some(locked)
+        }
+    }
+
+    /// Select the video mode of the given monitor closest to the canvas size,
+    /// preferring the highest refresh rate and bit depth.
+    fn video_mode(monitor: &MonitorHandle, canvas: (usize, usize)) -> Option<VideoMode> {
+        monitor.video_modes().min_by_key(|mode| {
+            let size = mode.size();
+            let distance = (size.width as usize).abs_diff(canvas.0)
+                + (size.height as usize).abs_diff(canvas.1);
+            (
+                distance,
+                Reverse(mode.refresh_rate()),
+                Reverse(mode.bit_depth()),
+            )
+        })
+    }
+
     /// Load a texture from the given `path`.
     fn create_texture(factory: &mut F, data: &[u8], kind: Kind) -> ShaderResourceView<R, [f32; 4]> {
         // Create a GPU texture
@@ -50,18 +50,26 @@ gfx_defines! {
 
 pub struct StatsRenderer<F: Factory<R> + Clone> {
     /// The corner to render the stats in.
-    #[allow(unused)]
     corner: Corner,
 
-    /// The rendering offset.
+    /// The rendering offset, from the corner.
     offset: (u32, u32),
 
+    /// The origin the rendering offset is relative to, in window pixels.
+    origin: (u32, u32),
+
     /// The rendering padding.
     padding: i32,
 
     /// The column spacing amount.
     col_spacing: i32,
 
+    /// The extra vertical gap between rows.
+    row_gap: i32,
+
+    /// Whether to draw a background behind the text.
+    background: bool,
+
     /// The text to render.
     text: Arc<Mutex<String>>,
 
@@ -85,6 +93,39 @@ pub struct StatsRenderer<F: Factory<R> + Clone> {
 
     /// The background rendering data.
     bg_data: Option<bg_pipe::Data<R>>,
+
+    /// Tracks the background quad that is built, to only rebuild it when it
+    /// changes.
+    bg_cache: BackgroundCache,
+}
+
+/// A position and size in normalized device coordinates.
+type Rect = ((f32, f32), (f32, f32));
+
+/// Tracks the background quad that is currently built, so it is only rebuilt
+/// when it changes.
+#[derive(Default)]
+struct BackgroundCache {
+    /// The rectangle of the built quad, `None` if it must be rebuilt.
+    rect: Option<Rect>,
+}
+
+impl BackgroundCache {
+    /// Force a rebuild on the next draw. Repeated invalidations before a draw
+    /// are coalesced into a single rebuild.
+    fn invalidate(&mut self) {
+        self.rect = None;
+    }
+
+    /// Check whether the quad must be rebuilt to cover the given rectangle,
+    /// and remember it as built.
+    fn rebuild(&mut self, rect: Rect) -> bool {
+        if self.rect == Some(rect) {
+            return false;
+        }
+        self.rect = Some(rect);
+        true
+    }
 }
 
 impl<F: Factory<R> + Clone> StatsRenderer<F> {
@@ -93,8 +134,11 @@ impl<F: Factory<R> + Clone> StatsRenderer<F> {
         StatsRenderer {
             corner,
             offset: (0, 0),
+            origin: (0, 0),
             padding: 0,
             col_spacing: 0,
+            row_gap: 0,
+            background: true,
             text: Arc::new(Mutex::new(String::new())),
             renderer: None,
             factory: None,
@@ -103,6 +147,7 @@ impl<F: Factory<R> + Clone> StatsRenderer<F> {
             bg_pso: None,
             bg_slice: None,
             bg_data: None,
+            bg_cache: BackgroundCache::default(),
         }
     }
 
@@ -168,6 +213,36 @@ impl<F: Factory<R> + Clone> StatsRenderer<F> {
         Ok(())
     }
 
+    /// Set the origin the rendering offset is relative to, in window pixels.
+    ///
+    /// This may be used to keep the stats on the canvas, when the canvas
+    /// doesn't fill the window.
+    pub fn set_origin(&mut self, origin: (u32, u32)) {
+        self.origin = origin;
+        self.invalidate_background();
+    }
+
+    /// Rebuild the background quad on the next draw.
+    ///
+    /// Cheap to call often, invalidations are coalesced until the next draw.
+    /// The quad is also rebuilt whenever the text bounds change.
+    pub fn invalidate_background(&mut self) {
+        self.bg_cache.invalidate();
+    }
+
+    /// Set the extra vertical gap between rows of text, in pixels. No gap by
+    /// default.
+    pub fn set_row_gap(&mut self, row_gap: i32) {
+        self.row_gap = row_gap;
+        self.invalidate_background();
+    }
+
+    /// Set whether to draw a semi-transparent background behind the text.
+    /// Enabled by default.
+    pub fn set_background(&mut self, background: bool) {
+        self.background = background;
+    }
+
     /// Get a reference to the text that is rendered.
     pub fn text(&self) -> Arc<Mutex<String>> {
         self.text.clone()
@@ -201,36 +276,41 @@ impl<F: Factory<R> + Clone> StatsRenderer<F> {
         // Unwrap the renderer
         let renderer = self.renderer.as_mut().unwrap();
 
-        // Draw formatted text on the text scene
-        let bounds = Self::scene_draw_format(
-            self.offset,
+        // Get the window dimensions
+        let win = self.window_dimensions.unwrap_or_default();
+
+        // Draw formatted text on the text scene, in the corner
+        let (offset, bounds) = Self::scene_draw_format(
+            &self.corner,
+            win,
+            (self.offset.0 + self.origin.0, self.offset.1 + self.origin.1),
             self.padding,
-            self.col_spacing,
+            (self.col_spacing, self.row_gap),
             renderer,
             &self.text.lock(),
         );
 
-        // Draw the background quad, if there are some bounds
-        if bounds != (0f32, 0f32)
+        // Draw the background quad, if enabled and there are some bounds
+        if self.background
+            && bounds != (0f32, 0f32)
             && self.bg_slice.is_some()
             && self.bg_pso.is_some()
             && self.bg_data.is_some()
         {
-            // Get the window dimensions
-            let win = self.window_dimensions.unwrap();
-
             // Determine the position and size of the background quad
             let w = bounds.0 / win.0 * 2f32;
             let h = bounds.1 / win.1 * 2f32;
-            let x = -1f32 + self.offset.0 as f32 / win.0 * 2f32;
-            let y = 1f32 - self.offset.1 as f32 / win.1 * 2f32 - h;
+            let x = -1f32 + offset.0 as f32 / win.0 * 2f32;
+            let y = 1f32 - offset.1 as f32 / win.1 * 2f32 - h;
 
-            // Rebuild the vertex buffer and slice data
-            let (vertex_buffer, slice) =
-                create_quad((x, y), (w, h)).create_vertex_buffer(self.factory.as_mut().unwrap());
+            // Rebuild the vertex buffer and slice data, only if changed
+            if self.bg_cache.rebuild(((x, y), (w, h))) {
+                let (vertex_buffer, slice) = create_quad((x, y), (w, h))
+                    .create_vertex_buffer(self.factory.as_mut().unwrap());
 
-            *self.bg_slice.as_mut().unwrap() = slice;
-            self.bg_data.as_mut().unwrap().vbuf = vertex_buffer;
+                *self.bg_slice.as_mut().unwrap() = slice;
+                self.bg_data.as_mut().unwrap().vbuf = vertex_buffer;
+            }
 
             encoder.draw(
                 self.bg_slice.as_ref().unwrap(),
@@ -243,89 +323,57 @@ impl<F: Factory<R> + Clone> StatsRenderer<F> {
         renderer.draw(encoder, target)
     }
 
-    /// Draw text in a formatted way.
+    /// Draw text in a formatted way, placed in the given corner of the
+    /// window at the given offset.
     /// This method allows a string to be rendered as table.
     /// Rows are separated by `\n`, while columns are separated by `\t`.
     ///
-    /// The drawing bounds are returned.
+    /// The drawing position and bounds are returned.
     fn scene_draw_format(
-        pos: (u32, u32),
+        corner: &Corner,
+        window: (f32, f32),
+        offset: (u32, u32),
         padding: i32,
-        col_spacing: i32,
+        (col_spacing, row_gap): (i32, i32),
         renderer: &mut TextRenderer<R, F>,
         text: &str,
-    ) -> (f32, f32) {
-        Self::scene_draw_table(
-            pos,
-            padding,
-            col_spacing,
-            renderer,
-            text.split("\n")
-                .map(|row| row.split("\t").collect())
-                .collect(),
-        )
+    ) -> ((u32, u32), (f32, f32)) {
+        let text: Vec<Vec<&str>> = text
+            .split("\n")
+            .map(|row| row.split("\t").collect())
+            .collect();
+
+        // Build a table of text bounds, to place the table before drawing
+        let bounds: Vec<Vec<(i32, i32)>> = text
+            .iter()
+            .map(|col| col.iter().map(|text| renderer.measure(text)).collect())
+            .collect();
+        let layout = TableLayout::new(&bounds, col_spacing, row_gap);
+        let size = layout.size(padding);
+        let pos = corner.position(window, size, offset);
+
+        Self::scene_draw_table(pos, padding, renderer, &layout, text);
+
+        (pos, size)
     }
 
-    /// Draw a table of text with the given `renderer`.
+    /// Draw a table of text with the given `renderer`, in the given layout.
     /// The text table to draw should be defined in the `text` vectors:
     /// `Rows(Columns)`
-    ///
-    /// The drawing bounds are returned.
     fn scene_draw_table(
         pos: (u32, u32),
         padding: i32,
-        col_spacing: i32,
         renderer: &mut TextRenderer<R, F>,
+        layout: &TableLayout,
         text: Vec<Vec<&str>>,
-    ) -> (f32, f32) {
-        // Build a table of text bounds
-        let bounds: Vec<Vec<(i32, i32)>> = text
-            .iter()
-            .map(|col| col.iter().map(|text| renderer.measure(text)).collect())
-            .collect();
-
-        // Find the maximum height for each row
-        let rows_max: Vec<i32> = bounds
-            .iter()
-            .map(|col| col.iter().map(|size| size.1).max().unwrap_or(0))
-            .collect();
-
-        // Find the maximum width for each column
-        let mut cols_max: Vec<i32> = bounds
-            .iter()
-            .map(|row| row.iter().map(|size| size.0).collect())
-            .fold(Vec::new(), |acc: Vec<i32>, row: Vec<i32>| {
-                // Iterate over widths in acc and row,
-                // select the largest one
-                let mut out: Vec<i32> = acc
-                    .iter()
-                    .zip(row.iter())
-                    .map(|(a, b)| max(*a, *b))
-                    .collect();
-
-                // Extend the output if there are any widths left
-                let out_len = out.len();
-                if out_len < acc.len() || out_len < row.len() {
-                    out.extend(acc.iter().skip(out_len));
-                    out.extend(row.iter().skip(out_len));
-                }
-
-                out
-            });
-        cols_max
-            .iter_mut()
-            .rev()
-            .skip(1)
-            .map(|width| *width += col_spacing)
-            .count();
-
+    ) {
         // Render each text
         for (row, text) in text.iter().enumerate() {
             for (col, text) in text.iter().enumerate() {
                 // Find the coordinate to use
                 let (mut x, mut y): (i32, i32) = (
-                    cols_max.iter().take(col).sum::<i32>(),
-                    rows_max.iter().take(row).sum::<i32>(),
+                    layout.cols.iter().take(col).sum::<i32>(),
+                    layout.rows.iter().take(row).sum::<i32>(),
                 );
 
                 // Add the offset and additional spacing
@@ -342,12 +390,6 @@ impl<F: Factory<R> + Clone> StatsRenderer<F> {
                 );
             }
         }
-
-        // Find the total width and height, return it
-        (
-            cols_max.iter().sum::<i32>() as f32 + padding as f32 * 2f32,
-            rows_max.iter().sum::<i32>() as f32 + padding as f32 * 2f32,
-        )
     }
 
     /// Update the stats rendering view, and the window dimensions.
@@ -365,6 +407,70 @@ impl<F: Factory<R> + Clone> StatsRenderer<F> {
 
         // Update the window dimensions
         self.window_dimensions = Some(dimensions);
+        self.invalidate_background();
+    }
+}
+
+/// The layout of a table of text.
+struct TableLayout {
+    /// The width of each column, including the spacing to the next column.
+    cols: Vec<i32>,
+
+    /// The height of each row, including the gap to the next row.
+    rows: Vec<i32>,
+}
+
+impl TableLayout {
+    /// Lay out a table with cells of the given `bounds`, as `Rows(Columns)`.
+    fn new(bounds: &[Vec<(i32, i32)>], col_spacing: i32, row_gap: i32) -> Self {
+        // Find the maximum height for each row
+        let mut rows: Vec<i32> = bounds
+            .iter()
+            .map(|col| col.iter().map(|size| size.1).max().unwrap_or(0))
+            .collect();
+
+        // Find the maximum width for each column
+        let mut cols: Vec<i32> = bounds
+            .iter()
+            .map(|row| row.iter().map(|size| size.0).collect())
+            .fold(Vec::new(), |acc: Vec<i32>, row: Vec<i32>| {
+                // Iterate over widths in acc and row,
+                // select the largest one
+                let mut out: Vec<i32> = acc
+                    .iter()
+                    .zip(row.iter())
+                    .map(|(a, b)| max(*a, *b))
+                    .collect();
+
+                // Extend the output if there are any widths left
+                let out_len = out.len();
+                if out_len < acc.len() || out_len < row.len() {
+                    out.extend(acc.iter().skip(out_len));
+                    out.extend(row.iter().skip(out_len));
+                }
+
+                out
+            });
+        cols.iter_mut()
+            .rev()
+            .skip(1)
+            .map(|width| *width += col_spacing)
+            .count();
+        rows.iter_mut()
+            .rev()
+            .skip(1)
+            .map(|height| *height += row_gap)
+            .count();
+
+        TableLayout { cols, rows }
+    }
+
+    /// Get the total width and height, with the given padding around it.
+    fn size(&self, padding: i32) -> (f32, f32) {
+        (
+            self.cols.iter().sum::<i32>() as f32 + padding as f32 * 2f32,
+            self.rows.iter().sum::<i32>() as f32 + padding as f32 * 2f32,
+        )
     }
 }
 
@@ -381,4 +487,81 @@ pub enum Corner {
 
     /// The bottom right corner of the screen.
     BottomRight,
+
+    /// The center of the screen. The offset is ignored.
+    Center,
+}
+
+impl Corner {
+    /// Get the top left position in the window of something of the given
+    /// size, placed in this corner at the given offset from the window edges.
+    ///
+    /// Positions are clamped to the top left of the window.
+    fn position(&self, window: (f32, f32), size: (f32, f32), offset: (u32, u32)) -> (u32, u32) {
+        let (x, y) = (offset.0 as f32, offset.1 as f32);
+        let right = window.0 - size.0 - x;
+        let bottom = window.1 - size.1 - y;
+        let (x, y) = match self {
+            Corner::TopLeft => (x, y),
+            Corner::TopRight => (right, y),
+            Corner::BottomLeft => (x, bottom),
+            Corner::BottomRight => (right, bottom),
+            Corner::Center => ((window.0 - size.0) / 2f32, (window.1 - size.1) / 2f32),
+        };
+        (x.max(0f32) as u32, y.max(0f32) as u32)
+    }
+}
+
+#[test]
+fn table_layout() {
+    // Columns are as wide as their widest cell, plus spacing between them
+    let layout = TableLayout::new(&[vec![(10, 5), (4, 8)], vec![(6, 3)]], 2, 0);
+    assert_eq!(layout.cols, [12, 4]);
+    assert_eq!(layout.rows, [8, 3]);
+    assert_eq!(layout.size(1), (18f32, 13f32));
+}
+
+#[test]
+fn table_row_gap() {
+    // Each row starts the gap further down than the last, there's no gap
+    // after the last row
+    let bounds = vec![vec![(10, 5)]; 3];
+    let tight = TableLayout::new(&bounds, 0, 0);
+    let gapped = TableLayout::new(&bounds, 0, 4);
+    assert_eq!(tight.rows, [5, 5, 5]);
+    assert_eq!(gapped.rows, [9, 9, 5]);
+    assert_eq!(gapped.size(0).1 - tight.size(0).1, 8f32);
+}
+
+#[test]
+fn corner_position() {
+    let (window, size) = ((200f32, 100f32), (50f32, 20f32));
+    assert_eq!(Corner::TopLeft.position(window, size, (10, 5)), (10, 5));
+    assert_eq!(
+        Corner::BottomRight.position(window, size, (10, 5)),
+        (140, 75)
+    );
+
+    // Centered text ignores the offset, and stays in the window when too big
+    assert_eq!(Corner::Center.position(window, size, (10, 5)), (75, 40));
+    assert_eq!(
+        Corner::Center.position(window, (300f32, 20f32), (0, 0)),
+        (0, 40)
+    );
+}
+
+#[test]
+fn background_rebuild_coalesced() {
+    let mut cache = BackgroundCache::default();
+    let rect = ((-1f32, 0.5f32), (0.5f32, 0.5f32));
+
+    // Invalidations before a draw cause a single rebuild
+    cache.invalidate();
+    cache.invalidate();
+    cache.invalidate();
+    assert!(cache.rebuild(rect));
+    assert!(!cache.rebuild(rect));
+
+    // A changed background is rebuilt
+    assert!(cache.rebuild(((-1f32, 0.4f32), (0.5f32, 0.6f32))));
 }
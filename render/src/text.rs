@@ -0,0 +1,136 @@
+use crate::color::Color;
+use crate::pixmap::{Pixmap, PixmapErr};
+
+/// The width of a glyph in pixels.
+pub const GLYPH_WIDTH: usize = 3;
+
+/// The height of a glyph in pixels.
+pub const GLYPH_HEIGHT: usize = 5;
+
+/// The horizontal distance between the start of consecutive glyphs in pixels.
+pub const GLYPH_ADVANCE: usize = GLYPH_WIDTH + 1;
+
+/// Draw text onto the pixel map, with its top left corner at the given
+/// coordinate.
+///
+/// Text is drawn with a small built-in 3 by 5 pixel font, covering digits,
+/// letters and common punctuation. Lowercase letters are drawn as uppercase,
+/// other characters as `?`. Only the pixels of the glyphs are set, colors are
+/// blended the same as with `set_pixel`.
+///
+/// The top left corner must be within bounds, glyph pixels beyond the edges
+/// are clipped. Returns the number of pixels set.
+pub fn draw_text<'a>(
+    pixmap: &'a Pixmap,
+    x: usize,
+    y: usize,
+    text: &str,
+    color: Color,
+) -> Result<usize, PixmapErr<'a>> {
+    let (x, y) = pixmap.wrap_coord(x, y)?;
+
+    let mut count = 0;
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + i * GLYPH_ADVANCE;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                let (px, py) = (glyph_x + col, y + row);
+                if px < pixmap.width()
+                    && py < pixmap.height()
+                    && matches!(pixmap.set_pixel(px, py, color), Ok(true))
+                {
+                    count += 1;
+                }
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+/// Get the glyph of the given character, as rows of bits, top to bottom. The
+/// most significant bit of each row is the leftmost pixel.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b101, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b101, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+#[test]
+fn draw_glyph() {
+    let pixmap = Pixmap::new(8, 8);
+    let red = Color::from_rgb(0xFF, 0, 0);
+
+    // The letter T, a bar with a stem below its center
+    assert_eq!(draw_text(&pixmap, 1, 1, "T", red).unwrap(), 7);
+    for x in 1..4 {
+        assert_eq!(pixmap.pixel(x, 1).unwrap(), red);
+    }
+    for y in 2..6 {
+        assert_eq!(pixmap.pixel(2, y).unwrap(), red);
+        assert_eq!(pixmap.pixel(1, y).unwrap(), Color::black());
+    }
+}
+
+#[test]
+fn draw_text_clipped() {
+    let pixmap = Pixmap::new(6, 4);
+    let red = Color::from_rgb(0xFF, 0, 0);
+
+    // The bottom row, and the right column of the second glyph are clipped
+    assert_eq!(draw_text(&pixmap, 0, 0, "11", red).unwrap(), 10);
+    assert!(draw_text(&pixmap, 6, 0, "1", red).is_err());
+}
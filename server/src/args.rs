@@ -1,10 +1,17 @@
+use std::fmt::Display;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
-use clap::Parser;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, ValueEnum};
+use pixelpwnr_render::{Color, Config, Pattern};
 
-use crate::codec::{CodecOptions, RateLimit};
+use crate::codec::{CodecOptions, Endianness, EraseToken, RateLimit, Terminator};
+use crate::socket_buffers::SocketBuffers;
+use crate::stats::RateUnit;
+use crate::test_pattern::TestPattern;
 
 #[derive(Parser, Clone)]
 pub struct Opts {
@@ -12,26 +19,205 @@ pub struct Opts {
     #[clap(long, default_value = "0.0.0.0:1337", alias = "bind")]
     pub host: SocketAddr,
 
+    /// Inherit an already bound listener through this file descriptor instead
+    /// of binding to host, for socket activation (Unix only)
+    #[clap(long, value_name = "FD")]
+    pub listen_fd: Option<i32>,
+
+    /// The kernel receive buffer size of client sockets (SO_RCVBUF). Defaults
+    /// to the OS default, the OS may clamp it
+    #[clap(long, value_name = "BYTES")]
+    pub tcp_recv_buffer: Option<usize>,
+
+    /// The kernel send buffer size of client sockets (SO_SNDBUF). Defaults to
+    /// the OS default, the OS may clamp it
+    #[clap(long, value_name = "BYTES")]
+    pub tcp_send_buffer: Option<usize>,
+
     /// Canvas width (def: screen width)
-    #[clap(short, long, value_name = "PIXELS")]
+    #[clap(short, long, value_name = "PIXELS", value_parser = parse_dimension)]
     pub width: Option<usize>,
 
     /// Canvas heigth (def: screen height)
-    #[clap(short, long, value_name = "PIXELS")]
+    #[clap(short, long, value_name = "PIXELS", value_parser = parse_dimension)]
     pub height: Option<usize>,
 
-    /// Do not render the canvas
+    /// Refuse canvases larger than this many bytes, at 4 bytes per pixel.
+    /// Guards against running out of memory on mistyped dimensions
+    #[clap(long, value_name = "BYTES", default_value_t = DEFAULT_MAX_CANVAS_BYTES)]
+    max_canvas_bytes: u64,
+
+    /// Draw the canvas from a file of PX commands on startup, before accepting
+    /// connections
+    #[clap(long, value_name = "PATH")]
+    pub canvas_seed: Option<PathBuf>,
+
+    /// Load the canvas from raw RGBA bytes on stdin on startup, before
+    /// accepting connections. Exactly 4 bytes for each pixel of the canvas
+    /// must be given, row by row
+    #[clap(long)]
+    pub canvas_stdin: bool,
+
+    /// Replace the canvas with this image each time the server receives
+    /// SIGHUP. Images of other dimensions are centered and cropped. Not
+    /// available on other platforms
+    #[clap(long, value_name = "PATH")]
+    pub swap_image: Option<PathBuf>,
+
+    /// Accept this token in place of a color to erase a pixel to the canvas
+    /// background, such as `PX 1 2 --`
+    #[clap(long, value_name = "TOKEN", value_parser = EraseToken::new)]
+    pub erase_token: Option<EraseToken>,
+
+    /// Append every received command to this file, with a timestamp and the
+    /// client address. Heavy on disk I/O, commands are dropped if the disk
+    /// can't keep up.
+    #[clap(long, value_name = "PATH")]
+    pub command_log: Option<PathBuf>,
+
+    /// Initialize the canvas with a pattern, to visualize transparency
+    #[clap(
+        long,
+        value_enum,
+        alias = "canvas-checkerboard",
+        value_name = "PATTERN"
+    )]
+    pub canvas_pattern: Option<CanvasPattern>,
+
+    /// Block size of the canvas pattern
+    #[clap(long, value_name = "PIXELS", default_value = "16")]
+    pub canvas_pattern_size: usize,
+
+    /// Fade all pixels towards black by this factor out of 255, each
+    /// --decay-interval. Each step processes the whole canvas, which costs
+    /// CPU time on large canvases
+    #[clap(long, value_name = "FACTOR")]
+    pub decay: Option<u8>,
+
+    /// The interval at which pixels fade towards black, in milliseconds
+    ///
+    /// This value is only relevant if --decay is specified
+    #[clap(long, value_name = "MILLISECONDS", default_value = "1000")]
+    pub decay_interval: u64,
+
+    /// Draw a test pattern on the canvas at startup, for calibrating displays
+    #[clap(long, value_enum, value_name = "PATTERN", alias = "draw-test-pattern")]
+    pub test_pattern: Option<TestPattern>,
+
+    /// Do not render the canvas, the same as --render-backend none
     #[clap(long)]
     pub no_render: bool,
 
+    /// The backend to render the canvas with. The gfx (OpenGL) backend is the
+    /// default, and the only one that is compiled in
+    #[clap(long, value_enum, value_name = "BACKEND", default_value = "gfx")]
+    render_backend: RenderBackend,
+
+    /// Draw a border of the given width in canvas pixels and color along the
+    /// canvas edges, for aligning projections
+    #[clap(long, value_name = "WIDTH,RRGGBB", value_parser = parse_border)]
+    pub border: Option<(u32, Color)>,
+
     /// Render with nearest-neighbor scaling, instead of linear
     #[clap(short, long)]
     pub nearest_neighbor: bool,
 
+    /// Blend semi-transparent pixels in linear light, for gamma-correct colors
+    #[clap(long)]
+    pub gamma_correct: bool,
+
+    /// Disable alpha blending, treat all written pixels as opaque for maximum
+    /// write speed. Discards the alpha channel, unlike --write-mode replace
+    #[clap(long, conflicts_with = "gamma-correct")]
+    pub no_alpha: bool,
+
+    /// How written pixels are combined with the canvas. Replace stores pixels
+    /// as is, keeping their alpha channel in snapshots
+    #[clap(long, value_enum, value_name = "MODE", default_value = "blend")]
+    write_mode: WriteMode,
+
+    /// Store written pixels exactly as sent, including their alpha channel,
+    /// so reading a pixel returns the color last written to it. Same as, and
+    /// takes precedence over --write-mode replace
+    #[clap(long, conflicts_with = "no-alpha")]
+    store_raw: bool,
+
+    /// Wrap pixel coordinates around the canvas edges, as on a torus, instead
+    /// of rejecting them as out of bound
+    #[clap(long, alias = "canvas-wrap")]
+    pub wrap: bool,
+
+    /// Silently drop pixel writes (PX, PXRUN, CAS, ERASE, DRAWTEXT) to a pixel
+    /// written less than this long ago, to throttle clients spamming the same
+    /// pixel
+    #[clap(long, value_name = "MILLISECONDS")]
+    pixel_cooldown: Option<u64>,
+
+    /// Apply --pixel-cooldown to square regions of this size instead of single
+    /// pixels, to use less memory on large canvases
+    #[clap(long, value_name = "PIXELS", default_value = "1")]
+    pub pixel_cooldown_region: usize,
+
+    /// Preserve the canvas aspect ratio with black bars, instead of stretching
+    /// it to fill the window
+    #[clap(long)]
+    pub preserve_aspect: bool,
+
+    /// Don't stretch the canvas, render it at the largest integer scale that
+    /// fits, centered on a black background. Useful with --fullscreen
+    #[clap(long)]
+    pub no_stretch: bool,
+
+    /// Keep the canvas aspect ratio when resizing the window. Falls back to
+    /// letterboxing where the window size can't be enforced
+    #[clap(long)]
+    pub lock_aspect: bool,
+
+    /// Don't allow resizing the window, for kiosk setups
+    #[clap(long)]
+    pub fixed_window: bool,
+
+    /// Place the window at this position on the desktop in pixels, as
+    /// `<x>,<y>`. Ignored in fullscreen. Default is chosen by the OS
+    #[clap(long, value_name = "X,Y", value_parser = parse_position::<i32>)]
+    pub window_pos: Option<(i32, i32)>,
+
+    /// Synchronize rendering with the display refresh rate. Turn off for the
+    /// lowest latency, at the cost of tearing
+    #[clap(long, value_enum, value_name = "SWITCH", default_value = "on")]
+    vsync: Switch,
+
+    /// The number of samples per pixel for multisample anti-aliasing. Only
+    /// applied when the renderer starts
+    #[clap(long, value_name = "SAMPLES", default_value = "1")]
+    pub msaa: u16,
+
+    /// Recreate the window and graphics context if rendering stalls, for
+    /// example due to a graphics driver hang. Can't recover from a driver that
+    /// hangs for good, but the stall is reported and clients stay served
+    #[clap(long)]
+    render_watchdog: bool,
+
+    /// The time without a rendered frame after which --render-watchdog kicks
+    /// in
+    #[clap(long, value_name = "SECONDS", default_value = "5")]
+    render_watchdog_timeout: u64,
+
+    /// Start with a hidden window, and show it once the first pixel is set.
+    /// With --fullscreen, the window goes fullscreen once shown
+    #[clap(long)]
+    pub start_hidden: bool,
+
     /// Render in fullscreen
     #[clap(short, long)]
     pub fullscreen: bool,
 
+    /// How to go fullscreen. Exclusive switches the monitor to the video mode
+    /// closest to the canvas size, which may perform better, but is slow to
+    /// switch away from
+    #[clap(long, value_enum, value_name = "MODE", default_value = "borderless")]
+    fullscreen_mode: FullscreenMode,
+
     /// The file to use for persistent stats
     #[clap(long, alias = "file", value_name = "FILE")]
     pub stats_file: Option<PathBuf>,
@@ -40,17 +226,25 @@ pub struct Opts {
     #[clap(long, value_name = "SECONDS", alias = "stats-save-interval")]
     stats_file_interval: Option<u64>,
 
+    /// Whether to save persistent stats on exit [default: true].
+    ///
+    /// This value is only relevant if --stats-file is specified
+    #[clap(long, action = clap::ArgAction::Set, value_name = "ENABLED", default_value_t = true)]
+    pub stats_save_on_exit: bool,
+
     /// Whether to show real-time stats on the top left [default: true].
     #[clap(long, action = clap::ArgAction::Set, value_name = "ENABLED", default_value_t = true)]
     pub stats_enabled: bool,
 
-    /// Reporting interval of stats on screen [default: 1]
+    /// Reporting interval of stats on screen, may be fractional for sub-second
+    /// updates, at least 0.05 [default: 1]
     #[clap(
         long,
         value_name = "SECONDS",
         alias = "stats-screen",
         alias = "stats-screen-interval",
-        default_value_t = 1.0
+        default_value_t = 1.0,
+        value_parser = parse_stats_interval
     )]
     stats_interval: f32,
 
@@ -63,10 +257,37 @@ pub struct Opts {
     )]
     stats_stdout: Option<u64>,
 
+    /// Report stats to stdout as a JSON object on a single line, instead of a
+    /// table
+    ///
+    /// This value is only relevant if --stats-stdout is specified
+    #[clap(long)]
+    pub stdout_json: bool,
+
+    /// Report pixels and bytes per second as CSV lines to stdout, at this
+    /// interval
+    #[clap(long, value_name = "SECONDS")]
+    pps_report: Option<u64>,
+
+    /// Write the CSV report to this file instead of stdout
+    #[clap(long, value_name = "PATH", requires = "pps-report")]
+    pub pps_report_file: Option<PathBuf>,
+
     /// Screen stats font size in pixels
     #[clap(long, value_name = "PX", default_value = "20", alias = "font-size")]
     pub stats_font_size: u8,
 
+    /// Show a scoreboard in the center of the screen, ranking client IPs by
+    /// the number of pixels they wrote
+    #[clap(long)]
+    pub scoreboard: bool,
+
+    /// Scoreboard font size in pixels
+    ///
+    /// This value is only relevant if --scoreboard is specified
+    #[clap(long, value_name = "PX", default_value = "48")]
+    pub scoreboard_font_size: u8,
+
     /// Screen stats padding
     #[clap(long, value_name = "PX", default_value = "12", alias = "padding")]
     pub stats_padding: i32,
@@ -79,28 +300,338 @@ pub struct Opts {
     #[clap(long, value_name = "DISPLAY_HOST")]
     pub stats_host: Option<SocketAddr>,
 
+    /// Serve stats and canvas snapshots over HTTP on this address
+    ///
+    /// Routes: /stats and /stats/rates (JSON), /snapshot.png and /stream (MJPEG),
+    /// /ws (pixelflut over WebSocket), /metrics (Prometheus, requires the
+    /// prometheus feature).
+    #[clap(long, value_name = "HOST")]
+    pub http: Option<SocketAddr>,
+
+    /// Accept pixel writes over HTTP on /px, for clients that can't keep a
+    /// pixelflut connection open
+    ///
+    /// Use `GET /px?x=1&y=2&c=FF0000`, or POST such queries one per line.
+    #[clap(long, requires = "http", conflicts_with = "readonly")]
+    pub http_px: bool,
+
+    /// Serve HTTP snapshots and streams downscaled by this factor, to save
+    /// bandwidth
+    #[clap(long, value_name = "FACTOR", requires = "http")]
+    pub preview_scale: Option<usize>,
+
+    /// Serve stats as JSON on a Unix domain socket at this path (Unix only)
+    #[clap(long, value_name = "PATH")]
+    pub stats_unix: Option<PathBuf>,
+
+    /// Send stats as StatsD gauges over UDP to this address (requires the
+    /// statsd feature)
+    #[clap(long, value_name = "HOST")]
+    pub statsd: Option<SocketAddr>,
+
+    /// The interval at which to send stats to StatsD, in seconds
+    ///
+    /// This value is only relevant if --statsd is specified
+    #[clap(long, value_name = "SECONDS", default_value = "10")]
+    pub statsd_interval: u64,
+
+    /// Send stats to this Graphite plaintext listener over TCP (requires the
+    /// graphite feature)
+    #[clap(long, value_name = "HOST")]
+    pub graphite: Option<SocketAddr>,
+
+    /// The interval at which to send stats to Graphite, in seconds
+    ///
+    /// This value is only relevant if --graphite is specified
+    #[clap(long, value_name = "SECONDS", default_value = "10")]
+    pub graphite_interval: u64,
+
+    /// Mirror the canvas into a memory-mapped file at this path, for local
+    /// tools to read (requires the mmap feature)
+    #[clap(long, value_name = "PATH")]
+    pub mmap: Option<PathBuf>,
+
+    /// Don't draw a background behind the screen stats
+    #[clap(long)]
+    pub no_stats_bg: bool,
+
     /// Screen stats column spacing
     #[clap(long, alias = "stats-column-spacing", default_value = "20")]
     pub stats_col_spacing: i32,
 
+    /// Extra vertical gap between screen stats rows, in pixels
+    #[clap(long, value_name = "PX", default_value = "0")]
+    pub stats_row_gap: i32,
+
     /// The directory under which to save images.
     #[clap(long, short)]
     pub save_dir: Option<PathBuf>,
 
-    /// The interval at which to save the current frame, in seconds
+    /// The interval at which to save the current frame, in seconds. Frames
+    /// identical to the last saved one are skipped
     ///
     /// This value is only relevant if --save-dir is specified
     #[clap(long, default_value = "60")]
     pub save_interval: u64,
 
+    /// The number of most recent saved images to keep, older images are removed
+    ///
+    /// This value is only relevant if --save-dir is specified
+    #[clap(long, value_name = "COUNT")]
+    pub save_keep: Option<usize>,
+
+    /// Save images in a subdirectory of --save-dir per server session, named
+    /// after the launch time. --save-keep applies to the current session only
+    #[clap(long)]
+    pub save_session_dirs: bool,
+
     /// The maximum bandwidth at which a single client is
     /// allowed to send data to the server, in bits per second. Default is unlimited.
     #[clap(long)]
     pub bw_limit: Option<usize>,
 
+    /// The number of bits a single client may send in a burst above the
+    /// bandwidth limit, after being idle. Default is one second of bandwidth.
+    ///
+    /// This value is only relevant if --bw-limit is specified
+    #[clap(long, value_name = "BITS")]
+    pub bw_limit_burst: Option<usize>,
+
+    /// The maximum bandwidth at which all clients together are allowed to
+    /// send data to the server, in bits per second. Each client is limited to
+    /// an equal share of it. Default is unlimited.
+    ///
+    /// This applies in addition to --bw-limit
+    #[clap(long, value_name = "BITS")]
+    pub global_bw_limit: Option<usize>,
+
+    /// The number of bits all clients together may send in a burst above the
+    /// global bandwidth limit. Default is one second of bandwidth.
+    ///
+    /// This value is only relevant if --global-bw-limit is specified
+    #[clap(long, value_name = "BITS")]
+    pub global_bw_limit_burst: Option<usize>,
+
+    /// The maximum number of commands a single client may send each second.
+    /// Default is unlimited.
+    ///
+    /// This is enforced independently of --bw-limit, a client is throttled
+    /// by whichever limit it reaches first.
+    #[clap(long, value_name = "COMMANDS", alias = "max-command-rate")]
+    pub cps_limit: Option<usize>,
+
+    /// Forget client IPs not seen for this long when counting unique clients,
+    /// to bound memory usage. Default is to remember all client IPs.
+    #[clap(long, value_name = "SECONDS")]
+    unique_ip_window: Option<u64>,
+
+    /// The unit to show the input rate in
+    #[clap(long, value_enum, value_name = "UNIT", default_value = "bits")]
+    pub rate_unit: RateUnit,
+
+    /// Log a summary of bytes read and pixels set for each closed connection
+    #[clap(long)]
+    pub log_connections: bool,
+
+    /// Warn when processing a single batch of commands from a client takes
+    /// longer than this, to diagnose latency spikes
+    #[clap(long, value_name = "MILLISECONDS")]
+    slow_poll_threshold: Option<u64>,
+
+    /// Run expensive commands, such as DRAWTEXT, on a separate thread, and
+    /// respond with an error if they take longer than this. The client stays
+    /// connected
+    #[clap(long, value_name = "MILLISECONDS")]
+    command_timeout: Option<u64>,
+
+    /// Spawn a synthetic client that keeps drawing the whole canvas.
+    /// Only meant as a testing and benchmarking aid
+    #[clap(long, value_enum, value_name = "MODE", default_value = "off")]
+    pub synthetic: SyntheticMode,
+
     /// Disable binary commands
     #[clap(long)]
     pub no_binary: bool,
+
+    /// The byte order of coordinates in binary pixel commands
+    #[clap(long, value_enum, value_name = "ENDIAN", default_value = "le")]
+    pub binary_endian: Endianness,
+
+    /// Refuse all pixel writes, for display-only mirrors
+    #[clap(long, alias = "canvas-readonly")]
+    pub readonly: bool,
+
+    /// Respond to undecodable commands with an error and continue, instead of
+    /// disconnecting the client
+    #[clap(long)]
+    pub lenient_errors: bool,
+
+    /// The number of undecodable commands a client may send before being
+    /// disconnected anyway.
+    ///
+    /// This value is only relevant if --lenient-errors is specified
+    #[clap(long, value_name = "ERRORS", default_value_t = 100)]
+    pub error_budget: usize,
+
+    /// The origin of this canvas on a larger virtual canvas, as `<x>,<y>`.
+    /// Reported to clients in the SIZE response, for tiled deployments
+    #[clap(long, value_name = "X,Y", value_parser = parse_position::<usize>)]
+    pub virtual_origin: Option<(usize, usize)>,
+
+    /// Interpret pixel coordinates from clients on the virtual canvas, by
+    /// subtracting the virtual origin
+    #[clap(long, requires = "virtual-origin")]
+    pub virtual_coords: bool,
+
+    /// Enable the RATE command, for clients to query their input rate
+    #[clap(long)]
+    pub rate_command: bool,
+
+    /// Require clients to negotiate the protocol version with HELLO, before
+    /// sending any other command
+    #[clap(long)]
+    pub require_hello: bool,
+
+    /// Enable the DRAWTEXT command, for clients to draw text with a built-in
+    /// pixel font. Each command sets many pixels at once
+    #[clap(long)]
+    pub allow_drawtext: bool,
+
+    /// Enable the UNDO command, for clients to restore the last pixels they
+    /// set
+    #[clap(long)]
+    pub allow_undo: bool,
+
+    /// The number of pixels each client can undo. Each connection remembers
+    /// the previous color of this many pixels.
+    ///
+    /// This value is only relevant if --allow-undo is specified
+    #[clap(long, value_name = "PIXELS", default_value_t = 64)]
+    pub undo_depth: usize,
+
+    /// Send clients that QUIT a summary of the pixels and bytes they sent
+    #[clap(long)]
+    pub quit_summary: bool,
+
+    /// The line terminator to end responses with
+    #[clap(long, value_enum, value_name = "TERMINATOR", default_value = "crlf")]
+    pub response_terminator: Terminator,
+}
+
+/// A pattern to initialize the canvas with.
+#[derive(ValueEnum, Clone, Copy)]
+pub enum CanvasPattern {
+    /// A dark and light gray checkerboard
+    Checkerboard,
+}
+
+/// A setting that can be switched on or off.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum Switch {
+    /// Enabled
+    On,
+    /// Disabled
+    Off,
+}
+
+/// How written pixels are combined with the canvas.
+#[derive(ValueEnum, Clone, Copy)]
+pub enum WriteMode {
+    /// Alpha blend pixels over the canvas
+    Blend,
+    /// Replace pixels on the canvas, including their alpha channel
+    Replace,
+}
+
+/// A backend to render the canvas with.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum RenderBackend {
+    /// Render with gfx, on OpenGL
+    Gfx,
+    /// Do not render
+    None,
+}
+
+/// How to go fullscreen.
+#[derive(ValueEnum, Clone, Copy)]
+pub enum FullscreenMode {
+    /// Cover the monitor with a borderless window
+    Borderless,
+    /// Take exclusive control of the monitor
+    Exclusive,
+}
+
+/// The smallest allowed canvas width and height in pixels.
+const MIN_DIMENSION: usize = 1;
+
+/// The largest allowed canvas width and height in pixels, limited by the
+/// texture size used for rendering.
+const MAX_DIMENSION: usize = u16::MAX as usize;
+
+/// The default maximum canvas size in bytes, 4 GiB.
+const DEFAULT_MAX_CANVAS_BYTES: u64 = 4 << 30;
+
+/// The shortest allowed interval to report stats on screen with in seconds, to
+/// prevent reporting from pegging a CPU.
+const MIN_STATS_INTERVAL: f32 = 0.05;
+
+/// Parse a position, as `<x>,<y>`.
+fn parse_position<T>(value: &str) -> Result<(T, T), String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let (x, y) = value
+        .split_once(',')
+        .ok_or_else(|| "must be formatted as <x>,<y>".to_string())?;
+    let parse = |v: &str| v.trim().parse::<T>().map_err(|err| format!("{}", err));
+    Ok((parse(x)?, parse(y)?))
+}
+
+/// Parse a canvas width or height.
+fn parse_dimension(value: &str) -> Result<usize, String> {
+    let value: usize = value.parse().map_err(|err| format!("{}", err))?;
+    if !(MIN_DIMENSION..=MAX_DIMENSION).contains(&value) {
+        return Err(format!(
+            "must be between {} and {} pixels",
+            MIN_DIMENSION, MAX_DIMENSION
+        ));
+    }
+    Ok(value)
+}
+
+/// Parse a stats screen reporting interval in seconds.
+fn parse_stats_interval(value: &str) -> Result<f32, String> {
+    let value: f32 = value.parse().map_err(|err| format!("{}", err))?;
+    if !value.is_finite() || value < MIN_STATS_INTERVAL {
+        return Err(format!("must be at least {} seconds", MIN_STATS_INTERVAL));
+    }
+    Ok(value)
+}
+
+/// Parse a border, as `<width>,<RRGGBB>`.
+fn parse_border(value: &str) -> Result<(u32, Color), String> {
+    let (width, color) = value
+        .split_once(',')
+        .ok_or_else(|| "must be formatted as <width>,<RRGGBB>".to_string())?;
+    let width = width.trim().parse().map_err(|err| format!("{}", err))?;
+    let color = color.trim();
+    if color.len() != 6 {
+        return Err("color must be formatted as RRGGBB".into());
+    }
+    let color = Color::from_hex(color).map_err(|err| format!("{}", err))?;
+    Ok((width, color))
+}
+
+/// A synthetic client mode.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+pub enum SyntheticMode {
+    /// Don't run a synthetic client
+    Off,
+    /// Send commands through the line codec, on the tokio runtime
+    Tokio,
+    /// Set pixels directly, on a separate thread
+    Sync,
 }
 
 macro_rules! map_duration {
@@ -111,9 +642,76 @@ macro_rules! map_duration {
 
 impl Opts {
     /// Get the canvas size.
+    ///
+    /// Exits with an error if the canvas is too large, see `try_size`.
     pub fn size(&self) -> (usize, usize) {
+        self.try_size().unwrap_or_else(|err| {
+            Opts::command()
+                .error(ErrorKind::ValueValidation, err)
+                .exit()
+        })
+    }
+
+    /// Get the canvas size, or an error if the canvas would be too large.
+    ///
+    /// This is checked before the canvas is allocated.
+    fn try_size(&self) -> Result<(usize, usize), String> {
         // TODO: use the current screen size as default here
-        (self.width.unwrap_or(800), self.height.unwrap_or(600))
+        let (width, height) = (self.width.unwrap_or(800), self.height.unwrap_or(600));
+
+        // Each pixel takes 4 bytes, make sure the canvas size can be addressed
+        let bytes = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(4))
+            .ok_or("canvas size is too large")?;
+
+        if bytes as u64 > self.max_canvas_bytes {
+            return Err(format!(
+                "canvas size of {} bytes exceeds the maximum of {} bytes, see --max-canvas-bytes",
+                bytes, self.max_canvas_bytes
+            ));
+        }
+
+        Ok((width, height))
+    }
+
+    /// Get the pattern to initialize the canvas with, if any.
+    pub fn canvas_pattern(&self) -> Option<Pattern> {
+        self.canvas_pattern.map(|pattern| match pattern {
+            CanvasPattern::Checkerboard => Pattern::Checkerboard {
+                size: self.canvas_pattern_size,
+                a: Color::from_rgb(0x40, 0x40, 0x40),
+                b: Color::from_rgb(0x80, 0x80, 0x80),
+            },
+        })
+    }
+
+    /// Get how written pixels are combined with the canvas
+    pub fn write_mode(&self) -> pixelpwnr_render::WriteMode {
+        if self.store_raw {
+            return pixelpwnr_render::WriteMode::Replace;
+        }
+        match self.write_mode {
+            WriteMode::Blend => pixelpwnr_render::WriteMode::Blend,
+            WriteMode::Replace => pixelpwnr_render::WriteMode::Replace,
+        }
+    }
+
+    /// Get the backend to render the canvas with.
+    pub fn render_backend(&self) -> RenderBackend {
+        if self.no_render {
+            RenderBackend::None
+        } else {
+            self.render_backend
+        }
+    }
+
+    /// Get the kernel buffer sizes to use for client sockets.
+    pub fn socket_buffers(&self) -> SocketBuffers {
+        SocketBuffers {
+            recv: self.tcp_recv_buffer,
+            send: self.tcp_send_buffer,
+        }
     }
 
     /// Get the stats save interval
@@ -132,6 +730,52 @@ impl Opts {
         map_duration!(self.stats_stdout)
     }
 
+    /// Get the CSV report interval
+    pub fn pps_report_interval(&self) -> Option<Duration> {
+        map_duration!(self.pps_report)
+    }
+
+    /// Get the pixel write cooldown
+    pub fn pixel_cooldown(&self) -> Option<Duration> {
+        self.pixel_cooldown.map(Duration::from_millis)
+    }
+
+    /// Get the window in which unique client IPs are counted
+    pub fn unique_ip_window(&self) -> Option<Duration> {
+        map_duration!(self.unique_ip_window)
+    }
+
+    /// Get the renderer configuration
+    pub fn render_config(&self) -> Config {
+        Config {
+            fullscreen: self.fullscreen,
+            fullscreen_mode: match self.fullscreen_mode {
+                FullscreenMode::Borderless => pixelpwnr_render::FullscreenMode::Borderless,
+                FullscreenMode::Exclusive => pixelpwnr_render::FullscreenMode::Exclusive,
+            },
+            border: self.border,
+            nearest_neighbor: self.nearest_neighbor,
+            stats_size: self.stats_font_size,
+            stats_offset: self.stats_offset(),
+            stats_padding: self.stats_padding,
+            stats_col_spacing: self.stats_col_spacing,
+            stats_row_gap: self.stats_row_gap,
+            stats_background: !self.no_stats_bg,
+            scoreboard_size: self.scoreboard.then_some(self.scoreboard_font_size),
+            preserve_aspect: self.preserve_aspect,
+            no_stretch: self.no_stretch,
+            start_hidden: self.start_hidden,
+            lock_aspect: self.lock_aspect,
+            window_position: self.window_pos,
+            resizable: !self.fixed_window,
+            vsync: self.vsync == Switch::On,
+            msaa: self.msaa,
+            watchdog: self
+                .render_watchdog
+                .then_some(Duration::from_secs(self.render_watchdog_timeout)),
+        }
+    }
+
     /// Get the stats screen offset
     pub fn stats_offset(&self) -> (u32, u32) {
         let lower_case = self.stats_offset.to_lowercase();
@@ -158,11 +802,123 @@ impl Opts {
 
 impl From<Opts> for CodecOptions {
     fn from(opts: Opts) -> Self {
-        CodecOptions {
-            rate_limit: opts
-                .bw_limit
-                .map(|bps| RateLimit::BitsPerSecond { limit: bps }),
-            allow_binary_cmd: !opts.no_binary,
-        }
+        CodecOptions::new()
+            .with_rate_limit(opts.bw_limit.map(|bps| RateLimit::BitsPerSecond {
+                limit: bps,
+                burst: opts.bw_limit_burst.unwrap_or(bps),
+            }))
+            .with_cmd_rate_limit(opts.cps_limit)
+            .with_binary(!opts.no_binary)
+            .with_binary_endian(opts.binary_endian)
+            .with_readonly(opts.readonly)
+            .with_response_terminator(opts.response_terminator)
+            .with_error_budget(opts.lenient_errors.then_some(opts.error_budget))
+            .with_quit_summary(opts.quit_summary)
+            .with_require_hello(opts.require_hello)
+            .with_drawtext(opts.allow_drawtext)
+            .with_undo_depth(opts.allow_undo.then_some(opts.undo_depth))
+            .with_rate_command(opts.rate_command)
+            .with_virtual_origin(opts.virtual_origin, opts.virtual_coords)
+            .with_slow_poll_threshold(opts.slow_poll_threshold.map(Duration::from_millis))
+            .with_command_timeout(opts.command_timeout.map(Duration::from_millis))
+            .with_erase_token(opts.erase_token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reject_zero_size() {
+        let parse = |args: &[&str]| Opts::try_parse_from([&["pixelpwnr-server"], args].concat());
+
+        assert!(parse(&["--width", "0"]).is_err());
+        assert!(parse(&["--height", "0"]).is_err());
+        assert!(parse(&["--width", "70000"]).is_err());
+
+        let opts = parse(&["--width", "1", "--height", "1"]).unwrap();
+        assert_eq!(opts.size(), (1, 1));
+    }
+
+    #[test]
+    fn border() {
+        let (width, color) = parse_border("4,FF0000").unwrap();
+        assert_eq!(width, 4);
+        assert!(color == Color::from_rgb(0xFF, 0, 0));
+        assert!(parse_border("4").is_err());
+        assert!(parse_border("4,FF00").is_err());
+        assert!(parse_border("a,FF0000").is_err());
+    }
+
+    #[test]
+    fn window_position() {
+        assert_eq!(parse_position::<i32>("1920,0"), Ok((1920, 0)));
+        assert_eq!(parse_position::<i32>("-1280, 200"), Ok((-1280, 200)));
+        assert!(parse_position::<i32>("1920").is_err());
+        assert!(parse_position::<i32>("1920,a").is_err());
+        assert!(parse_position::<usize>("-1,0").is_err());
+    }
+
+    #[test]
+    fn stats_interval() {
+        let parse = |args: &[&str]| Opts::try_parse_from([&["pixelpwnr-server"], args].concat());
+
+        let opts = parse(&["--stats-interval", "0.25"]).unwrap();
+        assert_eq!(
+            opts.stats_screen_interval(),
+            Some(Duration::from_millis(250))
+        );
+
+        // Intervals that would peg a CPU are refused
+        assert!(parse(&["--stats-interval", "0.001"]).is_err());
+        assert!(parse(&["--stats-interval", "0"]).is_err());
+        assert!(parse(&["--stats-interval", "-1"]).is_err());
+        assert!(parse(&["--stats-interval", "NaN"]).is_err());
+    }
+
+    #[test]
+    fn render_backend() {
+        let parse = |args: &[&str]| Opts::try_parse_from([&["pixelpwnr-server"], args].concat());
+
+        assert!(parse(&[]).unwrap().render_backend() == RenderBackend::Gfx);
+        assert!(parse(&["--no-render"]).unwrap().render_backend() == RenderBackend::None);
+        assert!(
+            parse(&["--render-backend", "none"])
+                .unwrap()
+                .render_backend()
+                == RenderBackend::None
+        );
+
+        // Backends that aren't compiled in are refused at startup
+        assert!(parse(&["--render-backend", "glium"]).is_err());
+    }
+
+    #[test]
+    fn reject_large_canvas() {
+        let parse = |args: &[&str]| Opts::try_parse_from([&["pixelpwnr-server"], args].concat());
+
+        let opts = parse(&["--width", "65535", "--height", "65535"]).unwrap();
+        assert!(opts.try_size().is_err());
+
+        let opts = parse(&[
+            "--width",
+            "100",
+            "--height",
+            "100",
+            "--max-canvas-bytes",
+            "39999",
+        ]);
+        assert!(opts.unwrap().try_size().is_err());
+
+        let opts = parse(&[
+            "--width",
+            "100",
+            "--height",
+            "100",
+            "--max-canvas-bytes",
+            "40000",
+        ]);
+        assert_eq!(opts.unwrap().try_size(), Ok((100, 100)));
     }
 }
@@ -1,7 +1,19 @@
+use std::fmt;
+
 use atoi::atoi;
-use pixelpwnr_render::{Color, Pixmap, PixmapErr};
+use pixelpwnr_render::{draw_text, Color, Pixmap, PixmapErr, WriteMode};
+
+use crate::codec::{CodecOptions, RateLimit, LINE_MAX_LENGTH, PSB_PREFIX};
+use crate::stats::CmdKind;
+
+/// The latest protocol version, negotiated with HELLO.
+///
+/// Version 1 is the plain pixelflut protocol, with only the PX, SIZE, HELP and
+/// QUIT commands. Version 2 adds all extensions.
+pub const PROTOCOL_VERSION: u32 = 2;
 
-use crate::codec::{CodecOptions, RateLimit};
+/// The maximum number of characters drawn by a single DRAWTEXT command.
+pub const DRAWTEXT_MAX_LENGTH: usize = 64;
 
 /// A set of pixel commands a client might send.
 ///
@@ -19,12 +31,58 @@ pub enum Cmd {
     /// The `x` and `y` coordinate, with a `color`.
     SetPixel(usize, usize, Color),
 
+    /// Erase a pixel, resetting it to the canvas background.
+    ///
+    /// The `x` and `y` coordinate.
+    ErasePixel(usize, usize),
+
+    /// Set the color of a horizontal run of pixels.
+    ///
+    /// The `x` and `y` coordinate of the first pixel, the length of the run,
+    /// and a `color`.
+    SetRun(usize, usize, usize, Color),
+
+    /// Draw text onto the canvas, with the built-in pixel font.
+    ///
+    /// The `x` and `y` coordinate of the top left corner, a `color`, and the
+    /// text.
+    DrawText(usize, usize, Color, String),
+
+    /// Set a pixel color, only if it currently has the expected color.
+    ///
+    /// The `x` and `y` coordinate, with the `expected` and `new` color.
+    CompareAndSet(usize, usize, Color, Color),
+
     /// Request the size of the screen.
     Size,
 
+    /// Request the size of the screen, with a binary response.
+    BinarySize,
+
+    /// Subscribe to, or unsubscribe from, canvas size changes.
+    SubscribeSize(bool),
+
+    /// Request the input rate of this connection.
+    Rate,
+
+    /// Restore the pixels last written by this connection.
+    Undo,
+
+    /// Request the limits of this connection, and their current use.
+    Limits,
+
+    /// Request the address the server sees this connection from.
+    WhoAmI,
+
     /// Request help.
     Help,
 
+    /// Request the list of server capabilities.
+    Caps,
+
+    /// Negotiate the protocol version, with the version the client requests.
+    Hello(u32),
+
     /// Quit, break the connection.
     Quit,
 
@@ -35,10 +93,22 @@ pub enum Cmd {
 
 impl Cmd {
     /// Decode the command to run, from the given input bytes.
+    #[cfg(test)]
     pub fn decode_line<'a>(input_bytes: &[u8]) -> Result<Self, &'a str> {
-        // Iterate over input parts
+        Self::decode_line_erase(input_bytes, None)
+    }
+
+    /// Decode the command to run, from the given input bytes.
+    ///
+    /// If an erase token is given, it is accepted in place of the color of a
+    /// pixel command to erase the pixel.
+    pub fn decode_line_erase<'a>(
+        input_bytes: &[u8],
+        erase_token: Option<&str>,
+    ) -> Result<Self, &'a str> {
+        // Iterate over input parts, separated by spaces or tabs
         let mut input = input_bytes
-            .split(|b| b == &b' ')
+            .split(|b| b == &b' ' || b == &b'\t')
             .filter(|part| !part.is_empty());
 
         // Decode the command
@@ -48,30 +118,93 @@ impl Cmd {
                 b"PX" => {
                     // Get and parse coordinates
                     let (x, y) = (
-                        atoi(input.next().ok_or("missing x coordinate")?)
+                        decode_coord(input.next().ok_or("missing x coordinate")?)
                             .ok_or("invalid x coordinate")?,
-                        atoi(input.next().ok_or("missing y coordinate")?)
+                        decode_coord(input.next().ok_or("missing y coordinate")?)
                             .ok_or("invalid y coordinate")?,
                     );
 
                     // Get the color part, determine whether this is a get/set
                     // command
                     match input.next() {
-                        // Color part found, set the pixel command
-                        Some(color) => {
-                            let color =
-                                Color::from_hex_raw(color).map_err(|_| "invalid color value")?;
-                            Ok(Cmd::SetPixel(x, y, color))
+                        // Erase token found, erase the pixel command
+                        Some(color) if erase_token.map(str::as_bytes) == Some(color) => {
+                            Ok(Cmd::ErasePixel(x, y))
                         }
 
+                        // Color part found, set the pixel command
+                        Some(color) => Ok(Cmd::SetPixel(x, y, decode_color(color)?)),
+
                         // No color part found, get the pixel color
                         None => Ok(Cmd::GetPixel(x, y)),
                     }
                 }
 
+                // Horizontal pixel run command
+                b"PXRUN" => {
+                    let (x, y) = (
+                        decode_coord(input.next().ok_or("missing x coordinate")?)
+                            .ok_or("invalid x coordinate")?,
+                        decode_coord(input.next().ok_or("missing y coordinate")?)
+                            .ok_or("invalid y coordinate")?,
+                    );
+                    let len = atoi(input.next().ok_or("missing run length")?)
+                        .ok_or("invalid run length")?;
+                    let color = decode_color(input.next().ok_or("missing color")?)?;
+                    Ok(Cmd::SetRun(x, y, len, color))
+                }
+
+                // Draw text command, runs of whitespace in the text collapse
+                b"DRAWTEXT" => {
+                    let (x, y) = (
+                        decode_coord(input.next().ok_or("missing x coordinate")?)
+                            .ok_or("invalid x coordinate")?,
+                        decode_coord(input.next().ok_or("missing y coordinate")?)
+                            .ok_or("invalid y coordinate")?,
+                    );
+                    let color = decode_color(input.next().ok_or("missing color")?)?;
+                    let text = input
+                        .map(|part| std::str::from_utf8(part).map_err(|_| "invalid text"))
+                        .collect::<Result<Vec<_>, _>>()?
+                        .join(" ");
+                    if text.is_empty() {
+                        return Err("missing text");
+                    }
+                    if text.chars().count() > DRAWTEXT_MAX_LENGTH {
+                        return Err("text too long");
+                    }
+                    Ok(Cmd::DrawText(x, y, color, text))
+                }
+
+                // Compare and set pixel command
+                b"CAS" => {
+                    let (x, y) = (
+                        decode_coord(input.next().ok_or("missing x coordinate")?)
+                            .ok_or("invalid x coordinate")?,
+                        decode_coord(input.next().ok_or("missing y coordinate")?)
+                            .ok_or("invalid y coordinate")?,
+                    );
+                    let expected = decode_color(input.next().ok_or("missing expected color")?)?;
+                    let new = decode_color(input.next().ok_or("missing new color")?)?;
+                    Ok(Cmd::CompareAndSet(x, y, expected, new))
+                }
+
                 // Basic commands
                 b"SIZE" => Ok(Cmd::Size),
+                b"SUBSCRIBE" | b"UNSUBSCRIBE" => match input.next() {
+                    Some(b"SIZE") => Ok(Cmd::SubscribeSize(cmd == b"SUBSCRIBE")),
+                    _ => Err("unknown subscription, use HELP"),
+                },
+                b"RATE" => Ok(Cmd::Rate),
+                b"UNDO" => Ok(Cmd::Undo),
+                b"LIMITS" => Ok(Cmd::Limits),
+                b"WHOAMI" => Ok(Cmd::WhoAmI),
                 b"HELP" => Ok(Cmd::Help),
+                b"CAPS" => Ok(Cmd::Caps),
+                b"HELLO" => match atoi::<u32>(input.next().ok_or("missing version")?) {
+                    Some(version) if version > 0 => Ok(Cmd::Hello(version)),
+                    _ => Err("invalid version"),
+                },
                 b"QUIT" => Ok(Cmd::Quit),
                 b"" => Ok(Cmd::None),
                 // Unknown command
@@ -83,6 +216,72 @@ impl Cmd {
         }
     }
 
+    /// Get the kind of this command, for stats. `None` if nothing is done.
+    pub fn kind(&self) -> Option<CmdKind> {
+        match self {
+            Cmd::SetPixel(..)
+            | Cmd::ErasePixel(..)
+            | Cmd::SetRun(..)
+            | Cmd::DrawText(..)
+            | Cmd::CompareAndSet(..)
+            | Cmd::Undo => Some(CmdKind::Set),
+            Cmd::GetPixel(..) => Some(CmdKind::Get),
+            Cmd::Size | Cmd::BinarySize | Cmd::SubscribeSize(_) => Some(CmdKind::Size),
+            Cmd::Help | Cmd::Caps => Some(CmdKind::Help),
+            Cmd::Rate | Cmd::Limits | Cmd::WhoAmI | Cmd::Hello(_) | Cmd::Quit => {
+                Some(CmdKind::Other)
+            }
+            Cmd::None => None,
+        }
+    }
+
+    /// Check whether this command may take long to invoke. With a command
+    /// timeout, these run on a blocking thread.
+    ///
+    /// Expensive commands only result in `Ok`, `Response` or `ClientErr`.
+    pub fn is_expensive(&self) -> bool {
+        matches!(self, Cmd::DrawText(..))
+    }
+
+    /// Get the protocol version this command was introduced in, see
+    /// `PROTOCOL_VERSION`.
+    pub fn version(&self) -> u32 {
+        match self {
+            Cmd::GetPixel(..)
+            | Cmd::SetPixel(..)
+            | Cmd::Size
+            | Cmd::Help
+            | Cmd::Hello(_)
+            | Cmd::Quit
+            | Cmd::None => 1,
+            _ => 2,
+        }
+    }
+
+    /// Call `f` with the local coordinate of each pixel this command writes,
+    /// in order. Pixels that are out of bound are skipped.
+    pub fn for_each_written(
+        &self,
+        pixmap: &Pixmap,
+        opts: &CodecOptions,
+        mut f: impl FnMut(usize, usize),
+    ) {
+        let (x, y, len) = match *self {
+            Cmd::SetPixel(x, y, _) | Cmd::ErasePixel(x, y) | Cmd::CompareAndSet(x, y, ..) => {
+                (x, y, 1)
+            }
+            Cmd::SetRun(x, y, len, _) => (x, y, len),
+            _ => return,
+        };
+
+        // Runs are clipped at the row end, only their first pixel wraps
+        let (x, y) = match to_local(x, y, opts).and_then(|(x, y)| pixmap.wrap_coord(x, y)) {
+            Ok(coord) => coord,
+            Err(_) => return,
+        };
+        (x..x.saturating_add(len).min(pixmap.width())).for_each(|x| f(x, y));
+    }
+
     /// Invoke the command, and return the result.
     ///
     /// pixel_set_count is a mutable reference to the amount of pixels
@@ -98,20 +297,98 @@ impl Cmd {
         match self {
             // Set the pixel on the pixel map
             Cmd::SetPixel(x, y, color) => {
-                // Set the pixel
-                if let Err(err) = pixmap.set_pixel(x, y, color) {
-                    return err.into();
-                } else {
+                // Refuse writes on a read-only server
+                if codec_opts.readonly {
+                    return CmdResult::ClientErr("read-only server".into());
+                }
+
+                // Set the pixel, silently dropping writes within the cooldown
+                match to_local(x, y, codec_opts).and_then(|(x, y)| pixmap.set_pixel(x, y, color)) {
+                    Err(err) => return err.into(),
+                    Ok(true) => *pixel_set_count += 1,
+                    Ok(false) => {}
+                }
+            }
+
+            // Erase the pixel to the canvas background
+            Cmd::ErasePixel(x, y) => {
+                // Refuse writes on a read-only server
+                if codec_opts.readonly {
+                    return CmdResult::ClientErr("read-only server".into());
+                }
+
+                match to_local(x, y, codec_opts).and_then(|(x, y)| pixmap.erase_pixel(x, y)) {
+                    Err(err) => return err.into(),
+                    Ok(true) => *pixel_set_count += 1,
+                    Ok(false) => {}
+                }
+            }
+
+            // Set a run of pixels on the pixel map, clipped at the row end
+            Cmd::SetRun(x, y, len, color) => {
+                // Refuse writes on a read-only server
+                if codec_opts.readonly {
+                    return CmdResult::ClientErr("read-only server".into());
+                }
+
+                match to_local(x, y, codec_opts)
+                    .and_then(|(x, y)| pixmap.fill_rect(x, y, len, 1, color))
+                {
+                    Err(err) => return err.into(),
+                    Ok(count) => *pixel_set_count += count,
+                }
+            }
+
+            // Draw text on the pixel map, if enabled
+            Cmd::DrawText(x, y, color, text) => {
+                // Refuse writes on a read-only server
+                if codec_opts.readonly {
+                    return CmdResult::ClientErr("read-only server".into());
+                }
+                if !codec_opts.allow_drawtext {
+                    return CmdResult::ClientErr("drawtext command disabled".into());
+                }
+
+                match to_local(x, y, codec_opts)
+                    .and_then(|(x, y)| draw_text(pixmap, x, y, &text, color))
+                {
+                    Err(err) => return err.into(),
+                    Ok(count) => *pixel_set_count += count,
+                }
+            }
+
+            // Set the pixel on the pixel map, if it has the expected color
+            Cmd::CompareAndSet(x, y, expected, new) => {
+                // Refuse writes on a read-only server
+                if codec_opts.readonly {
+                    return CmdResult::ClientErr("read-only server".into());
+                }
+
+                let swapped = match to_local(x, y, codec_opts)
+                    .and_then(|(x, y)| pixmap.compare_and_set(x, y, expected, new))
+                {
+                    Err(err) => return err.into(),
+                    Ok(swapped) => swapped,
+                };
+                if swapped {
                     *pixel_set_count += 1;
                 }
+
+                // Send the response
+                return CmdResult::Response(format!("CAS {} {} {}", x, y, swapped as u8));
             }
 
             // Get a pixel color from the pixel map
             Cmd::GetPixel(x, y) => {
-                // Get the hexadecimal color value of a pixel
-                let color = match pixmap.pixel(x, y) {
+                // Get the hexadecimal color value of a pixel. Stored as is
+                // when replacing, so include the alpha channel to return the
+                // exact color last written.
+                let color = match to_local(x, y, codec_opts).and_then(|(x, y)| pixmap.pixel(x, y)) {
                     Err(err) => return err.into(),
-                    Ok(color) => color.hex(),
+                    Ok(color) => match pixmap.write_mode() {
+                        WriteMode::Replace => encode_color(&color),
+                        WriteMode::Blend => color.hex(),
+                    },
                 };
 
                 // Send the response
@@ -123,13 +400,56 @@ impl Cmd {
                 // Get the size
                 let (x, y) = pixmap.dimensions();
 
-                // Send the response
-                return CmdResult::Response(format!("SIZE {} {}", x, y));
+                // Send the response, with the virtual origin if configured
+                return match codec_opts.virtual_origin {
+                    Some((ox, oy)) => {
+                        CmdResult::Response(format!("SIZE {} {} {} {}", x, y, ox, oy))
+                    }
+                    None => CmdResult::Response(format!("SIZE {} {}", x, y)),
+                };
+            }
+
+            // Get the size of the screen, as binary frame
+            Cmd::BinarySize => {
+                let (x, y) = pixmap.dimensions();
+
+                let mut response = PSB_PREFIX.to_vec();
+                response.extend_from_slice(&(x as u32).to_le_bytes());
+                response.extend_from_slice(&(y as u32).to_le_bytes());
+                return CmdResult::BinaryResponse(response);
+            }
+
+            // (Un)subscribe to canvas size changes
+            Cmd::SubscribeSize(subscribe) => return CmdResult::SubscribeSize(subscribe),
+
+            // Get the input rate of this connection
+            Cmd::Rate => return CmdResult::Rate,
+
+            // Get the limits of this connection
+            Cmd::Limits => return CmdResult::Limits,
+
+            // Get the address of this connection
+            Cmd::WhoAmI => return CmdResult::WhoAmI,
+
+            // Restore the pixels last written by this connection
+            Cmd::Undo => {
+                // Refuse writes on a read-only server
+                if codec_opts.readonly {
+                    return CmdResult::ClientErr("read-only server".into());
+                }
+
+                return CmdResult::Undo;
             }
 
             // Show help
             Cmd::Help => return CmdResult::Response(Self::help_list(codec_opts)),
 
+            // List the capabilities
+            Cmd::Caps => return CmdResult::Response(Self::caps_list(pixmap, codec_opts)),
+
+            // Negotiate the protocol version
+            Cmd::Hello(version) => return CmdResult::Hello(version.min(PROTOCOL_VERSION)),
+
             // Quit the connection
             Cmd::Quit => return CmdResult::Quit,
 
@@ -149,7 +469,15 @@ impl Cmd {
             HELP Commands:\r\n\
             HELP - PX <x> <y> <RRGGBB[AA]>\r\n\
             HELP - PX <x> <y>   >>  PX <x> <y> <RRGGBB>\r\n\
-            HELP - SIZE         >>  SIZE <width> <height>\r\n\
+            HELP - PXRUN <x> <y> <length> <RRGGBB[AA]> (horizontal, clipped at the row end)\r\n\
+            HELP - CAS <x> <y> <expected RRGGBB> <new RRGGBB[AA]>   >>  CAS <x> <y> <1|0>\r\n\
+            HELP - SIZE         >>  SIZE <width> <height> [<origin x> <origin y>]\r\n\
+            HELP - SUBSCRIBE SIZE   >>  SIZE <width> <height> (on resize)\r\n\
+            HELP - UNSUBSCRIBE SIZE\r\n\
+            HELP - CAPS         >>  CAP <key>=<value> ...\r\n\
+            HELP - LIMITS       >>  LIMIT <key>=<value> ...\r\n\
+            HELP - WHOAMI       >>  WHOAMI <ip>:<port>\r\n\
+            HELP - HELLO <version>  >>  HELLO <negotiated version>\r\n\
             HELP - HELP         >>  HELP ...\
             ",
             env!("CARGO_PKG_NAME"),
@@ -157,24 +485,204 @@ impl Cmd {
         );
 
         if opts.allow_binary_cmd {
-            help.push_str(
-                "\r\nHELP - PBxyrgba (NO newline, x, y = 2 byte LE u16, r, g, b, a = single byte)",
-            );
+            help.push_str(&format!(
+                "\r\nHELP - PBxyrgba (NO newline, x, y = 2 byte {} u16, r, g, b, a = single byte)\
+                 \r\nHELP - PS (NO newline)  >>  PSwh (NO newline, w, h = 4 byte LE u32)",
+                opts.binary_endian.as_str()
+            ));
         }
 
         help.push_str("            \r\nHELP - QUIT         >> (Disconnect)");
 
-        if let Some(RateLimit::BitsPerSecond { limit }) = opts.rate_limit {
+        if let Some(token) = opts.erase_token {
+            help.push_str(&format!(
+                "\r\nHELP - PX <x> <y> {}  (erase to the canvas background)",
+                token
+            ));
+        }
+
+        if opts.allow_drawtext {
+            help.push_str(&format!(
+                "\r\nHELP - DRAWTEXT <x> <y> <RRGGBB[AA]> <text> (up to {} characters)",
+                DRAWTEXT_MAX_LENGTH
+            ));
+        }
+
+        if opts.rate_command {
+            help.push_str("\r\nHELP - RATE         >>  RATE <input bytes per second>");
+        }
+
+        if let Some(depth) = opts.undo_depth {
+            help.push_str(&format!(
+                "\r\nHELP - UNDO         >>  UNDO <pixels restored> (up to the last {} pixels you set)",
+                depth
+            ));
+        }
+
+        if opts.readonly {
+            help.push_str("\r\nHELP - This server is read-only, setting pixels is refused");
+        }
+
+        if let Some(RateLimit::BitsPerSecond { limit, burst }) = opts.rate_limit {
             help.push_str(&format!(
-                "\r\nHELP - Input from a single client is limited to {} bits per second",
+                "\r\nHELP - Input from a single client is limited to {} bits per second, with bursts up to {} bits",
+                limit, burst
+            ));
+        }
+
+        if let Some(limit) = opts.cmd_rate_limit {
+            help.push_str(&format!(
+                "\r\nHELP - Commands from a single client are limited to {} per second",
                 limit
             ));
         }
 
-        help
+        // Use the configured line terminator between lines
+        help.replace("\r\n", opts.response_terminator.as_str())
+    }
+
+    /// Get the list of capabilities, to respond to a client.
+    ///
+    /// Each capability is listed as `CAP <key>=<value>` on its own line. Keys
+    /// are stable, disabled capabilities are left out.
+    pub fn caps_list(pixmap: &Pixmap, opts: &CodecOptions) -> String {
+        let (width, height) = pixmap.dimensions();
+        let mut caps = vec![
+            format!("size={}x{}", width, height),
+            format!("max-line-length={}", LINE_MAX_LENGTH),
+            format!("protocol={}", PROTOCOL_VERSION),
+            format!(
+                "write-mode={}",
+                match pixmap.write_mode() {
+                    WriteMode::Blend => "blend",
+                    WriteMode::Replace => "replace",
+                }
+            ),
+        ];
+
+        if opts.allow_binary_cmd {
+            caps.push("binary=1".into());
+            caps.push(format!("binary-endian={}", opts.binary_endian.as_str()));
+        }
+        if opts.readonly {
+            caps.push("readonly=1".into());
+        }
+        if opts.require_hello {
+            caps.push("require-hello=1".into());
+        }
+        if let Some(token) = opts.erase_token {
+            caps.push(format!("erase={}", token));
+        }
+        if opts.allow_drawtext {
+            caps.push(format!("drawtext={}", DRAWTEXT_MAX_LENGTH));
+        }
+        if opts.rate_command {
+            caps.push("rate=1".into());
+        }
+        if let Some(depth) = opts.undo_depth {
+            caps.push(format!("undo={}", depth));
+        }
+        if let Some(RateLimit::BitsPerSecond { limit, burst }) = opts.rate_limit {
+            caps.push(format!("rate-limit={}", limit));
+            caps.push(format!("rate-burst={}", burst));
+        }
+        if let Some(limit) = opts.cmd_rate_limit {
+            caps.push(format!("cmd-rate-limit={}", limit));
+        }
+        if let Some((x, y)) = opts.virtual_origin {
+            caps.push(format!("offset={},{}", x, y));
+            if opts.virtual_coords {
+                caps.push("virtual-coords=1".into());
+            }
+        }
+
+        caps.iter()
+            .map(|cap| format!("CAP {}", cap))
+            .collect::<Vec<_>>()
+            .join(opts.response_terminator.as_str())
     }
 }
 
+/// Format the command as the text line it is decoded from.
+///
+/// Binary commands are formatted as their textual equivalent, erase commands
+/// use the `--` erase token.
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cmd::GetPixel(x, y) => write!(f, "PX {} {}", x, y),
+            Cmd::ErasePixel(x, y) => write!(f, "PX {} {} --", x, y),
+            Cmd::SetPixel(x, y, color) => write!(f, "PX {} {} {}", x, y, encode_color(color)),
+            Cmd::SetRun(x, y, len, color) => {
+                write!(f, "PXRUN {} {} {} {}", x, y, len, encode_color(color))
+            }
+            Cmd::DrawText(x, y, color, text) => {
+                write!(f, "DRAWTEXT {} {} {} {}", x, y, encode_color(color), text)
+            }
+            Cmd::CompareAndSet(x, y, expected, new) => write!(
+                f,
+                "CAS {} {} {} {}",
+                x,
+                y,
+                encode_color(expected),
+                encode_color(new)
+            ),
+            Cmd::Size | Cmd::BinarySize => write!(f, "SIZE"),
+            Cmd::SubscribeSize(true) => write!(f, "SUBSCRIBE SIZE"),
+            Cmd::SubscribeSize(false) => write!(f, "UNSUBSCRIBE SIZE"),
+            Cmd::Rate => write!(f, "RATE"),
+            Cmd::Undo => write!(f, "UNDO"),
+            Cmd::Limits => write!(f, "LIMITS"),
+            Cmd::WhoAmI => write!(f, "WHOAMI"),
+            Cmd::Help => write!(f, "HELP"),
+            Cmd::Caps => write!(f, "CAPS"),
+            Cmd::Hello(version) => write!(f, "HELLO {}", version),
+            Cmd::Quit => write!(f, "QUIT"),
+            Cmd::None => Ok(()),
+        }
+    }
+}
+
+/// Translate a coordinate on the virtual canvas to this canvas, if virtual
+/// coordinates are enabled.
+///
+/// Coordinates before the virtual origin are out of bound.
+fn to_local<'a>(x: usize, y: usize, opts: &CodecOptions) -> Result<(usize, usize), PixmapErr<'a>> {
+    match opts.virtual_origin {
+        Some((ox, oy)) if opts.virtual_coords => Ok((
+            x.checked_sub(ox)
+                .ok_or(PixmapErr::OutOfBound("x coordinate out of bound"))?,
+            y.checked_sub(oy)
+                .ok_or(PixmapErr::OutOfBound("y coordinate out of bound"))?,
+        )),
+        _ => Ok((x, y)),
+    }
+}
+
+/// Decode a pixel coordinate.
+///
+/// Coordinates are decoded as `u32` on every platform. Larger values can never
+/// be a canvas index, and are rejected as overflow instead of being bound
+/// checked later.
+fn decode_coord(input: &[u8]) -> Option<usize> {
+    atoi::<u32>(input).map(|coord| coord as usize)
+}
+
+/// Encode a hexadecimal color, with the alpha channel only if it isn't opaque.
+fn encode_color(color: &Color) -> String {
+    match color.alpha() {
+        0xFF => color.hex(),
+        alpha => format!("{}{:02X}", color.hex(), alpha),
+    }
+}
+
+/// Decode a hexadecimal color.
+fn decode_color<'a>(color: &[u8]) -> Result<Color, &'a str> {
+    // Allow a single leading hash, as in `#RRGGBB`
+    let color = color.strip_prefix(b"#").unwrap_or(color);
+    Color::from_hex_raw(color).map_err(|_| "invalid color value")
+}
+
 /// A result, returned when invoking a command.
 ///
 /// This result defines the status of the command that was invoked.
@@ -188,10 +696,33 @@ pub enum CmdResult {
     /// should be send to the client.
     Response(String),
 
+    /// The command has been invoked successfully, and the following binary
+    /// response should be send to the client, without line terminator.
+    BinaryResponse(Vec<u8>),
+
     /// The following error occurred while invoking a command, based on the
     /// clients input.
     ClientErr(String),
 
+    /// The connection should subscribe to, or unsubscribe from, canvas
+    /// size changes.
+    SubscribeSize(bool),
+
+    /// The connection should respond with its input rate.
+    Rate,
+
+    /// The connection should restore the pixels it last wrote.
+    Undo,
+
+    /// The connection should respond with its limits.
+    Limits,
+
+    /// The connection should respond with the address it is seen from.
+    WhoAmI,
+
+    /// The connection should use the given negotiated protocol version.
+    Hello(u32),
+
     /// The connection should be closed.
     Quit,
 }
@@ -203,3 +734,285 @@ impl From<PixmapErr<'_>> for CmdResult {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::EraseToken;
+
+    #[test]
+    fn decode_hash_color() {
+        let red = Color::from_rgb(0xFF, 0, 0);
+        assert!(matches!(
+            Cmd::decode_line(b"PX 1 1 #FF0000"),
+            Ok(Cmd::SetPixel(1, 1, color)) if color == red
+        ));
+        assert!(matches!(
+            Cmd::decode_line(b"PX 1 1 #FF000080"),
+            Ok(Cmd::SetPixel(1, 1, color)) if color == Color::from_rgba(0xFF, 0, 0, 0x80)
+        ));
+        assert!(Cmd::decode_line(b"PX 1 1 #").is_err());
+        assert!(Cmd::decode_line(b"PX 1 1 ##FF0000").is_err());
+    }
+
+    #[test]
+    fn decode_tab_separated() {
+        let red = Color::from_rgb(0xFF, 0, 0);
+        assert!(matches!(
+            Cmd::decode_line(b"PX\t1\t2\tFF0000"),
+            Ok(Cmd::SetPixel(1, 2, color)) if color == red
+        ));
+        assert!(matches!(
+            Cmd::decode_line(b"PX\t1\t2"),
+            Ok(Cmd::GetPixel(1, 2))
+        ));
+    }
+
+    #[test]
+    fn decode_mixed_whitespace() {
+        let red = Color::from_rgb(0xFF, 0, 0);
+        assert!(matches!(
+            Cmd::decode_line(b"PX \t1\t 2  \t\tFF0000"),
+            Ok(Cmd::SetPixel(1, 2, color)) if color == red
+        ));
+        assert!(matches!(Cmd::decode_line(b"\tSIZE "), Ok(Cmd::Size)));
+    }
+
+    #[test]
+    fn drawtext() {
+        let pixmap = Pixmap::new(10, 10);
+        let opts = CodecOptions::default().with_drawtext(true);
+
+        let mut count = 0;
+        let cmd = Cmd::decode_line(b"DRAWTEXT 1 1 FF0000 Hi  there").unwrap();
+        assert!(matches!(&cmd, Cmd::DrawText(1, 1, _, text) if text == "Hi there"));
+        assert!(matches!(
+            cmd.invoke(&pixmap, &mut count, &opts),
+            CmdResult::Ok
+        ));
+        assert!(count > 0);
+        assert_eq!(pixmap.pixel(1, 1).unwrap(), Color::from_rgb(0xFF, 0, 0));
+
+        // Disabled by default, and limited in length
+        let cmd = Cmd::decode_line(b"DRAWTEXT 1 1 FF0000 Hi").unwrap();
+        assert!(matches!(
+            cmd.invoke(&pixmap, &mut count, &CodecOptions::default()),
+            CmdResult::ClientErr(_)
+        ));
+        let long = format!(
+            "DRAWTEXT 1 1 FF0000 {}",
+            "A".repeat(DRAWTEXT_MAX_LENGTH + 1)
+        );
+        assert!(Cmd::decode_line(long.as_bytes()).is_err());
+        assert!(Cmd::decode_line(b"DRAWTEXT 1 1 FF0000").is_err());
+    }
+
+    #[test]
+    fn decode_coord_overflow() {
+        assert!(matches!(
+            Cmd::decode_line(b"PX 123456789012345678901234567890 1"),
+            Err("invalid x coordinate")
+        ));
+        assert!(matches!(
+            Cmd::decode_line(b"PX 1 123456789012345678901234567890 FF0000"),
+            Err("invalid y coordinate")
+        ));
+        assert!(matches!(
+            Cmd::decode_line(b"PX 4294967296 1"),
+            Err("invalid x coordinate")
+        ));
+        assert!(matches!(
+            Cmd::decode_line(b"PX 4294967295 1"),
+            Ok(Cmd::GetPixel(4294967295, 1))
+        ));
+    }
+
+    #[test]
+    fn decode_coord_canvas_edge() {
+        let pixmap = Pixmap::new(10, 2);
+        let opts = CodecOptions::default();
+
+        // The last pixel is in bound, the canvas edge is not
+        let mut count = 0;
+        let cmd = Cmd::decode_line(b"PX 9 1").unwrap();
+        assert!(matches!(
+            cmd.invoke(&pixmap, &mut count, &opts),
+            CmdResult::Response(_)
+        ));
+        let cmd = Cmd::decode_line(b"PX 10 1").unwrap();
+        assert!(matches!(
+            cmd.invoke(&pixmap, &mut count, &opts),
+            CmdResult::ClientErr(err) if err == "x coordinate out of bound"
+        ));
+    }
+
+    #[test]
+    fn set_run() {
+        let pixmap = Pixmap::new(10, 2);
+        let red = Color::from_rgb(0xFF, 0, 0);
+        let opts = CodecOptions::default();
+
+        // A run that fits
+        let mut count = 0;
+        let cmd = Cmd::decode_line(b"PXRUN 2 0 4 FF0000").unwrap();
+        assert!(matches!(
+            cmd.invoke(&pixmap, &mut count, &opts),
+            CmdResult::Ok
+        ));
+        assert_eq!(count, 4);
+        for x in 0..10 {
+            let expected = if (2..6).contains(&x) {
+                red
+            } else {
+                Color::black()
+            };
+            assert_eq!(pixmap.pixel(x, 0).unwrap(), expected);
+        }
+
+        // A run that is clipped at the row end
+        let mut count = 0;
+        let cmd = Cmd::decode_line(b"PXRUN 7 1 100 FF0000").unwrap();
+        assert!(matches!(
+            cmd.invoke(&pixmap, &mut count, &opts),
+            CmdResult::Ok
+        ));
+        assert_eq!(count, 3);
+        for x in 0..10 {
+            let expected = if x >= 7 { red } else { Color::black() };
+            assert_eq!(pixmap.pixel(x, 1).unwrap(), expected);
+        }
+
+        let cmd = Cmd::decode_line(b"PXRUN 10 1 1 FF0000").unwrap();
+        assert!(matches!(
+            cmd.invoke(&pixmap, &mut count, &opts),
+            CmdResult::ClientErr(_)
+        ));
+    }
+
+    #[test]
+    fn virtual_origin() {
+        let pixmap = Pixmap::new(10, 10);
+        let red = Color::from_rgb(0xFF, 0, 0);
+        let mut count = 0;
+
+        // The origin is reported in the size response
+        let opts = CodecOptions::default().with_virtual_origin(Some((100, 50)), false);
+        assert!(matches!(
+            Cmd::Size.invoke(&pixmap, &mut count, &opts),
+            CmdResult::Response(size) if size == "SIZE 10 10 100 50"
+        ));
+
+        // Without virtual coordinates, writes use local coordinates
+        assert!(matches!(
+            Cmd::SetPixel(1, 1, red).invoke(&pixmap, &mut count, &opts),
+            CmdResult::Ok
+        ));
+        assert_eq!(pixmap.pixel(1, 1).unwrap(), red);
+
+        // With virtual coordinates, the origin is subtracted
+        let opts = opts.with_virtual_origin(Some((100, 50)), true);
+        assert!(matches!(
+            Cmd::SetPixel(103, 52, red).invoke(&pixmap, &mut count, &opts),
+            CmdResult::Ok
+        ));
+        assert_eq!(pixmap.pixel(3, 2).unwrap(), red);
+        assert!(matches!(
+            Cmd::GetPixel(103, 52).invoke(&pixmap, &mut count, &opts),
+            CmdResult::Response(px) if px == "PX 103 52 FF0000"
+        ));
+
+        // Coordinates outside this part of the virtual canvas are refused
+        assert!(matches!(
+            Cmd::SetPixel(99, 52, red).invoke(&pixmap, &mut count, &opts),
+            CmdResult::ClientErr(_)
+        ));
+        assert!(matches!(
+            Cmd::SetPixel(110, 52, red).invoke(&pixmap, &mut count, &opts),
+            CmdResult::ClientErr(_)
+        ));
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn caps() {
+        let pixmap = Pixmap::new(10, 20);
+        let caps = |opts: &CodecOptions| -> Vec<String> {
+            Cmd::caps_list(&pixmap, opts)
+                .split("\r\n")
+                .map(str::to_string)
+                .collect()
+        };
+
+        let default = caps(&CodecOptions::default());
+        assert!(default.contains(&"CAP size=10x20".to_string()));
+        assert!(default.contains(&"CAP binary=1".to_string()));
+        assert!(default.contains(&"CAP binary-endian=LE".to_string()));
+        assert!(!default.iter().any(|cap| cap.starts_with("CAP readonly=")));
+        assert!(!default.iter().any(|cap| cap.starts_with("CAP offset=")));
+
+        let opts = CodecOptions::default()
+            .with_binary(false)
+            .with_readonly(true)
+            .with_cmd_rate_limit(Some(100))
+            .with_virtual_origin(Some((5, 6)), true);
+        let caps = caps(&opts);
+        assert!(!caps.iter().any(|cap| cap.starts_with("CAP binary")));
+        assert!(caps.contains(&"CAP readonly=1".to_string()));
+        assert!(caps.contains(&"CAP cmd-rate-limit=100".to_string()));
+        assert!(caps.contains(&"CAP offset=5,6".to_string()));
+        assert!(caps.contains(&"CAP virtual-coords=1".to_string()));
+    }
+
+    #[test]
+    fn erase_token() {
+        let pixmap = Pixmap::new(10, 10);
+        let mut count = 0;
+        pixmap.set_pixel(1, 1, Color::from_rgb(0xFF, 0, 0)).unwrap();
+
+        // Without an erase token, the token is an invalid color
+        assert!(Cmd::decode_line(b"PX 1 1 --").is_err());
+
+        let opts = CodecOptions::default().with_erase_token(EraseToken::new("--").ok());
+        let cmd = Cmd::decode_line_erase(b"PX 1 1 --", Some("--")).unwrap();
+        assert!(matches!(
+            cmd.invoke(&pixmap, &mut count, &opts),
+            CmdResult::Ok
+        ));
+        assert_eq!(pixmap.pixel(1, 1).unwrap(), pixmap.background(1, 1));
+        assert_eq!(count, 1);
+
+        // Colors are still accepted
+        assert!(matches!(
+            Cmd::decode_line_erase(b"PX 1 1 FF0000", Some("--")),
+            Ok(Cmd::SetPixel(1, 1, _))
+        ));
+    }
+
+    #[test]
+    fn cooldown() {
+        let pixmap =
+            Pixmap::new(10, 2).with_cooldown(Some(std::time::Duration::from_secs(3600)), 1);
+        let red = Color::from_rgb(0xFF, 0, 0);
+        let opts = CodecOptions::default();
+        let mut count = 0;
+        let mut invoke = |line: &[u8]| {
+            Cmd::decode_line(line)
+                .unwrap()
+                .invoke(&pixmap, &mut count, &opts)
+        };
+
+        assert!(matches!(invoke(b"PX 2 0 FF0000"), CmdResult::Ok));
+
+        // Runs skip pixels that are cooling down
+        assert!(matches!(invoke(b"PXRUN 1 0 3 0000FF"), CmdResult::Ok));
+
+        // Compare and set fails while cooling down
+        assert!(matches!(
+            invoke(b"CAS 2 0 FF0000 00FF00"),
+            CmdResult::Response(response) if response == "CAS 2 0 0"
+        ));
+        assert!(matches!(invoke(b"PX 2 0 00FF00"), CmdResult::Ok));
+        assert_eq!(count, 3);
+        assert_eq!(pixmap.pixel(2, 0).unwrap(), red);
+    }
+}
@@ -1,37 +1,373 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
 use std::ops::DerefMut;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::task::Poll;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use bytes::BytesMut;
 use futures::Future;
 use pipebuf::PipeBuf;
 use pixelpwnr_render::{Color, Pixmap};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::time::Sleep;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Sleep};
 
 use crate::cmd::{Cmd, CmdResult};
-use crate::stats::Stats;
+use crate::command_log::CommandLog;
+use crate::global_limit::{GlobalLimit, GlobalShare};
+use crate::stat_monitor::StatMonitor;
+use crate::stats::{CmdKind, Stats};
 
 #[cfg(test)]
 mod test;
 
 /// Options for this Codec
+///
+/// The bandwidth and command rate limits are independent, a client is
+/// throttled by whichever of them runs out first. The bandwidth limit applies
+/// when reading from the socket, the command rate limit applies when
+/// processing commands that have already been read.
 #[derive(Debug, Clone, Copy)]
 pub struct CodecOptions {
     pub rate_limit: Option<RateLimit>,
+    /// The maximum number of commands processed each second
+    pub cmd_rate_limit: Option<usize>,
     pub allow_binary_cmd: bool,
+    /// The byte order of coordinates in binary pixel commands
+    pub binary_endian: Endianness,
+    /// Refuse all commands that write pixels
+    pub readonly: bool,
+    /// The line terminator appended to responses
+    pub response_terminator: Terminator,
+    /// The number of undecodable commands to respond to with an error before
+    /// disconnecting. If `None`, the first one disconnects the client.
+    pub error_budget: Option<usize>,
+    /// Send a summary of the connection to clients that QUIT
+    pub quit_summary: bool,
+    /// Measure the input rate of each connection, for the RATE command
+    pub rate_command: bool,
+    /// The origin of this canvas on a larger virtual canvas, reported in
+    /// SIZE responses
+    pub virtual_origin: Option<(usize, usize)>,
+    /// Interpret pixel coordinates on the virtual canvas, relative to the
+    /// virtual origin
+    pub virtual_coords: bool,
+    /// Warn when processing a single batch of commands takes longer than this
+    pub slow_poll_threshold: Option<Duration>,
+    /// Run expensive commands on a blocking thread, and give up waiting for
+    /// them after this long
+    pub command_timeout: Option<Duration>,
+    /// A token accepted in place of a color, to erase a pixel to the canvas
+    /// background
+    pub erase_token: Option<EraseToken>,
+    /// Require clients to negotiate the protocol version with HELLO, before
+    /// any other command
+    pub require_hello: bool,
+    /// Allow drawing text with the DRAWTEXT command
+    pub allow_drawtext: bool,
+    /// The number of pixels each connection remembers the previous color of,
+    /// for the UNDO command. If `None`, UNDO is disabled.
+    pub undo_depth: Option<usize>,
+}
+
+impl CodecOptions {
+    /// Construct the default options.
+    ///
+    /// No limits are applied, binary commands are allowed, and every other
+    /// option is disabled.
+    pub const fn new() -> Self {
+        CodecOptions {
+            rate_limit: None,
+            cmd_rate_limit: None,
+            allow_binary_cmd: true,
+            binary_endian: Endianness::Le,
+            readonly: false,
+            response_terminator: Terminator::Crlf,
+            error_budget: None,
+            quit_summary: false,
+            rate_command: false,
+            virtual_origin: None,
+            virtual_coords: false,
+            slow_poll_threshold: None,
+            command_timeout: None,
+            erase_token: None,
+            require_hello: false,
+            allow_drawtext: false,
+            undo_depth: None,
+        }
+    }
+
+    /// Set the bandwidth rate limit.
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Set the maximum number of commands processed each second.
+    pub fn with_cmd_rate_limit(mut self, cmd_rate_limit: Option<usize>) -> Self {
+        self.cmd_rate_limit = cmd_rate_limit;
+        self
+    }
+
+    /// Set whether to allow binary commands.
+    pub fn with_binary(mut self, allow_binary_cmd: bool) -> Self {
+        self.allow_binary_cmd = allow_binary_cmd;
+        self
+    }
+
+    /// Set the byte order of coordinates in binary pixel commands.
+    pub fn with_binary_endian(mut self, binary_endian: Endianness) -> Self {
+        self.binary_endian = binary_endian;
+        self
+    }
+
+    /// Set whether to refuse all commands that write pixels.
+    pub fn with_readonly(mut self, readonly: bool) -> Self {
+        self.readonly = readonly;
+        self
+    }
+
+    /// Set the line terminator appended to responses.
+    pub fn with_response_terminator(mut self, response_terminator: Terminator) -> Self {
+        self.response_terminator = response_terminator;
+        self
+    }
+
+    /// Set the number of undecodable commands to tolerate before
+    /// disconnecting.
+    pub fn with_error_budget(mut self, error_budget: Option<usize>) -> Self {
+        self.error_budget = error_budget;
+        self
+    }
+
+    /// Set whether to send a summary of the connection on QUIT.
+    pub fn with_quit_summary(mut self, quit_summary: bool) -> Self {
+        self.quit_summary = quit_summary;
+        self
+    }
+
+    /// Set whether to measure the input rate of each connection, for the RATE
+    /// command.
+    pub fn with_rate_command(mut self, rate_command: bool) -> Self {
+        self.rate_command = rate_command;
+        self
+    }
+
+    /// Set the origin of this canvas on a larger virtual canvas, and whether
+    /// to interpret pixel coordinates on that virtual canvas.
+    pub fn with_virtual_origin(
+        mut self,
+        virtual_origin: Option<(usize, usize)>,
+        virtual_coords: bool,
+    ) -> Self {
+        self.virtual_origin = virtual_origin;
+        self.virtual_coords = virtual_coords;
+        self
+    }
+
+    /// Set the threshold to warn about slow command processing at.
+    pub fn with_slow_poll_threshold(mut self, slow_poll_threshold: Option<Duration>) -> Self {
+        self.slow_poll_threshold = slow_poll_threshold;
+        self
+    }
+
+    /// Set the timeout of expensive commands. If set, they run on a blocking
+    /// thread instead of the connection task.
+    pub fn with_command_timeout(mut self, command_timeout: Option<Duration>) -> Self {
+        self.command_timeout = command_timeout;
+        self
+    }
+
+    /// Set the token accepted in place of a color, to erase a pixel.
+    pub fn with_erase_token(mut self, erase_token: Option<EraseToken>) -> Self {
+        self.erase_token = erase_token;
+        self
+    }
+
+    /// Set whether to require clients to negotiate the protocol version with
+    /// HELLO first.
+    pub fn with_require_hello(mut self, require_hello: bool) -> Self {
+        self.require_hello = require_hello;
+        self
+    }
+
+    /// Set whether to allow drawing text with the DRAWTEXT command.
+    pub fn with_drawtext(mut self, allow_drawtext: bool) -> Self {
+        self.allow_drawtext = allow_drawtext;
+        self
+    }
+
+    /// Set the number of pixels each connection can undo, enabling the UNDO
+    /// command.
+    pub fn with_undo_depth(mut self, undo_depth: Option<usize>) -> Self {
+        self.undo_depth = undo_depth;
+        self
+    }
+}
+
+impl Default for CodecOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A line terminator for responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Terminator {
+    /// A carriage return and line feed, `\r\n`
+    Crlf,
+    /// A bare line feed, `\n`
+    Lf,
+}
+
+impl Terminator {
+    /// Get the terminator as string.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Terminator::Crlf => "\r\n",
+            Terminator::Lf => "\n",
+        }
+    }
+}
+
+/// The maximum length of an erase token in bytes.
+pub const ERASE_TOKEN_MAX_LENGTH: usize = 16;
+
+/// A token accepted in place of a color, to erase a pixel
+///
+/// Stored inline, to keep the codec options cheap to copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EraseToken {
+    bytes: [u8; ERASE_TOKEN_MAX_LENGTH],
+    len: u8,
+}
+
+impl EraseToken {
+    /// Create an erase token.
+    ///
+    /// Fails if the token is empty, longer than `ERASE_TOKEN_MAX_LENGTH` bytes,
+    /// or contains whitespace, as it could never match a command part.
+    pub fn new(token: &str) -> Result<Self, String> {
+        if token.is_empty() || token.contains(char::is_whitespace) {
+            return Err("must be non-empty, without whitespace".into());
+        }
+        if token.len() > ERASE_TOKEN_MAX_LENGTH {
+            return Err(format!(
+                "must be at most {} bytes long",
+                ERASE_TOKEN_MAX_LENGTH
+            ));
+        }
+
+        let mut bytes = [0; ERASE_TOKEN_MAX_LENGTH];
+        bytes[..token.len()].copy_from_slice(token.as_bytes());
+        Ok(Self {
+            bytes,
+            len: token.len() as u8,
+        })
+    }
+
+    /// Get the token as string.
+    pub fn as_str(&self) -> &str {
+        // Copied from a string in `new`
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+}
+
+impl fmt::Display for EraseToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A byte order for binary commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Endianness {
+    /// Little-endian
+    Le,
+    /// Big-endian
+    Be,
+}
+
+impl Endianness {
+    /// Decode a u16 in this byte order.
+    fn u16_from_bytes(&self, bytes: [u8; 2]) -> u16 {
+        match self {
+            Endianness::Le => u16::from_le_bytes(bytes),
+            Endianness::Be => u16::from_be_bytes(bytes),
+        }
+    }
+
+    /// Get a short name of this byte order.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Endianness::Le => "LE",
+            Endianness::Be => "BE",
+        }
+    }
 }
 
 /// A rate limit
 #[derive(Debug, Clone, Copy)]
 pub enum RateLimit {
-    // A rate limit in bits per second
-    BitsPerSecond { limit: usize },
+    // A rate limit in bits per second, allowing bursts of up to `burst` bits
+    BitsPerSecond { limit: usize, burst: usize },
     // Pixels { pps: usize },
 }
 
+/// The reason a connection was closed, returned by `Lines`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The client sent QUIT.
+    ClientQuit,
+
+    /// The client sent a line longer than the maximum line length.
+    LineTooLong,
+
+    /// A command failed to decode, and the error budget was spent.
+    DecodeError,
+
+    /// A command failed because of the client, with the given error.
+    ClientError(String),
+
+    /// The client closed the connection, or reading from it failed.
+    Eof,
+
+    /// Writing to the socket failed.
+    SocketError,
+}
+
+impl DisconnectReason {
+    /// Get a short name for the category of this reason, for stats.
+    pub fn category(&self) -> &'static str {
+        match self {
+            DisconnectReason::ClientQuit => "client_quit",
+            DisconnectReason::LineTooLong => "line_too_long",
+            DisconnectReason::DecodeError => "decode_error",
+            DisconnectReason::ClientError(_) => "client_error",
+            DisconnectReason::Eof => "eof",
+            DisconnectReason::SocketError => "socket_error",
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisconnectReason::ClientQuit => write!(f, "Client sent QUIT"),
+            DisconnectReason::LineTooLong => write!(f, "Client line length too long"),
+            DisconnectReason::DecodeError => write!(f, "Command decoding failed"),
+            DisconnectReason::ClientError(err) => write!(f, "Client error: {}", err),
+            DisconnectReason::Eof => write!(f, "Client disconnected"),
+            DisconnectReason::SocketError => write!(f, "Socket error"),
+        }
+    }
+}
+
 /// The capacity of the read and write buffer in bytes.
 const BUF_SIZE: usize = 64_000;
 
@@ -45,6 +381,9 @@ const BUF_SIZE: usize = 64_000;
 /// Should be less than `BUF_SIZE` to prevent constant socket reads.
 const BUF_THRESHOLD: usize = 16_000;
 
+/// The window in which the number of processed commands is limited.
+const CMD_RATE_WINDOW: Duration = Duration::from_secs(1);
+
 /// The maximum length of a line in bytes.
 /// If a received line is longer than than the specified amount of bytes,
 /// the search for a newline character (marking the end of a line) will be stalled,
@@ -54,7 +393,7 @@ const BUF_THRESHOLD: usize = 16_000;
 ///
 /// This value must be smaller than `BUF_THRESHOLD` to prevent the server from getting
 /// stuck as it can't find the end of a line within a full buffer.
-const LINE_MAX_LENGTH: usize = 1024;
+pub const LINE_MAX_LENGTH: usize = 1024;
 
 /// The prefix used for the Pixel Binary command
 pub const PXB_PREFIX: [u8; 2] = [b'P', b'B'];
@@ -64,6 +403,36 @@ pub const PXB_PREFIX: [u8; 2] = [b'P', b'B'];
 ///`                            Prefix             x   y   r   g   b   a
 pub const PXB_CMD_SIZE: usize = PXB_PREFIX.len() + 2 + 2 + 1 + 1 + 1 + 1;
 
+/// The prefix used for the binary size query, and its response.
+///
+/// The query is just the prefix. The response is the prefix followed by the
+/// canvas width and height, each as 4 byte LE u32.
+pub const PSB_PREFIX: [u8; 2] = [b'P', b'S'];
+
+/// A future that resolves when the canvas size changes.
+///
+/// It resolves to the receiver again, to allow waiting for the next change,
+/// or to `None` if the canvas size cannot change anymore.
+type SizeChange = Pin<Box<dyn Future<Output = Option<watch::Receiver<(usize, usize)>>> + Send>>;
+
+/// Wait for the canvas size to change, on the given receiver.
+fn wait_size_change(mut size: watch::Receiver<(usize, usize)>) -> SizeChange {
+    Box::pin(async move {
+        size.changed().await.ok()?;
+        Some(size)
+    })
+}
+
+/// A command running on a blocking thread, see `Lines::offload`.
+struct Offloaded {
+    /// The running command, resolving to its result and the number of pixels
+    /// it set.
+    task: JoinHandle<(CmdResult, usize)>,
+
+    /// Resolves when we give up waiting for the command.
+    timeout: Pin<Box<Sleep>>,
+}
+
 /// Line based codec.
 ///
 /// This decorates a socket and presents a line based read / write interface.
@@ -94,7 +463,7 @@ where
     pixmap: Arc<Pixmap>,
 
     /// This is `Some(Reason)` if this Lines is disconnecting
-    disconnecting: Option<String>,
+    disconnecting: Option<DisconnectReason>,
 
     /// Codec options
     opts: CodecOptions,
@@ -103,8 +472,78 @@ where
     /// resume receiving
     rx_wait: Option<Pin<Box<Sleep>>>,
 
-    /// The last time we filled up the RX buffer
+    /// The last time we refilled the rate limit token bucket
     last_refill_time: Instant,
+
+    /// The number of bytes we may currently read under the rate limit
+    rate_tokens: f64,
+
+    /// This connection's share of the bandwidth limit of all connections,
+    /// if any.
+    global_share: Option<GlobalShare>,
+
+    /// The start of the current command rate limit window
+    cmd_window_start: Instant,
+
+    /// The number of commands processed in the current command rate limit
+    /// window
+    cmd_count: usize,
+
+    /// Whether processing stopped at the command rate limit, with commands
+    /// left in the read buffer
+    cmd_limited: bool,
+
+    /// The number of bytes read from this connection.
+    bytes_read: usize,
+
+    /// The number of pixels set by this connection.
+    pixels: usize,
+
+    /// The scoreboard score this connection adds its set pixels to, if any.
+    score: Option<Arc<AtomicUsize>>,
+
+    /// Measures the input rate of this connection, if the RATE command is
+    /// enabled.
+    rate: Option<StatMonitor>,
+
+    /// The number of commands that failed to decode on this connection.
+    decode_errors: usize,
+
+    /// The number of command batches that took longer than the slow poll
+    /// threshold to process.
+    slow_polls: usize,
+
+    /// Logs received commands, with the address of this connection.
+    command_log: Option<(CommandLog, SocketAddr)>,
+
+    /// The address of the client, if known.
+    peer_addr: Option<SocketAddr>,
+
+    /// While set, commands that write pixels are refused.
+    paused: Option<Arc<AtomicBool>>,
+
+    /// The protocol version negotiated with HELLO. Without a handshake, all
+    /// commands are available.
+    protocol: Option<u32>,
+
+    /// A command running on a blocking thread. No other commands are
+    /// processed until it completes or times out.
+    offloaded: Option<Offloaded>,
+
+    /// An offloaded command that timed out, still running on a blocking
+    /// thread. Other expensive commands are refused until it completes.
+    timed_out: Option<JoinHandle<(CmdResult, usize)>>,
+
+    /// The previous color of the last pixels written by this connection,
+    /// oldest first, if the UNDO command is enabled.
+    undo: Option<VecDeque<(usize, usize, Color)>>,
+
+    /// A receiver for canvas size changes.
+    size: watch::Receiver<(usize, usize)>,
+
+    /// Resolves when the canvas size changes, if this connection is
+    /// subscribed to size changes.
+    size_change: Option<SizeChange>,
 }
 
 impl<T> Lines<T>
@@ -113,7 +552,13 @@ where
     T::Target: AsyncRead + AsyncWrite + Unpin,
 {
     /// Create a new `Lines` codec backed by the socket
-    pub fn new(socket: Pin<T>, stats: Arc<Stats>, pixmap: Arc<Pixmap>, opts: CodecOptions) -> Self {
+    pub fn new(
+        socket: Pin<T>,
+        stats: Arc<Stats>,
+        pixmap: Arc<Pixmap>,
+        opts: CodecOptions,
+        size: watch::Receiver<(usize, usize)>,
+    ) -> Self {
         Lines {
             socket,
             rd: PipeBuf::with_fixed_capacity(BUF_SIZE),
@@ -124,9 +569,197 @@ where
             opts,
             rx_wait: None,
             last_refill_time: Instant::now(),
+            rate_tokens: match opts.rate_limit {
+                Some(RateLimit::BitsPerSecond { burst, .. }) => burst as f64 / 8.0,
+                None => 0.0,
+            },
+            global_share: None,
+            cmd_window_start: Instant::now(),
+            cmd_count: 0,
+            cmd_limited: false,
+            bytes_read: 0,
+            pixels: 0,
+            score: None,
+            rate: opts.rate_command.then(StatMonitor::new),
+            decode_errors: 0,
+            slow_polls: 0,
+            command_log: None,
+            peer_addr: None,
+            paused: None,
+            protocol: None,
+            offloaded: None,
+            timed_out: None,
+            undo: opts.undo_depth.map(VecDeque::with_capacity),
+            size,
+            size_change: None,
         }
     }
 
+    /// Log the commands received on this connection, from the given address.
+    pub fn with_command_log(mut self, log: CommandLog, addr: SocketAddr) -> Self {
+        self.command_log = Some((log, addr));
+        self
+    }
+
+    /// Set the address of the client, reported by the WHOAMI command.
+    pub fn with_peer_addr(mut self, addr: SocketAddr) -> Self {
+        self.peer_addr = Some(addr);
+        self
+    }
+
+    /// Refuse commands that write pixels while the given flag is set. The
+    /// client stays connected, and can still read pixels.
+    pub fn with_pause(mut self, paused: Arc<AtomicBool>) -> Self {
+        self.paused = Some(paused);
+        self
+    }
+
+    /// Limit reading to a share of the given bandwidth limit of all
+    /// connections, in addition to the limit of this connection.
+    pub fn with_global_limit(mut self, limit: Arc<GlobalLimit>) -> Self {
+        self.global_share = Some(GlobalShare::new(limit));
+        self
+    }
+
+    /// Add the pixels set by this connection to the given scoreboard score.
+    pub fn with_score(mut self, score: Arc<AtomicUsize>) -> Self {
+        self.score = Some(score);
+        self
+    }
+
+    /// Get the number of bytes read from this connection.
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+
+    /// Get the number of pixels set by this connection.
+    pub fn pixels(&self) -> usize {
+        self.pixels
+    }
+
+    /// Get the number of command batches that took longer than the slow poll
+    /// threshold to process.
+    pub fn slow_polls(&self) -> usize {
+        self.slow_polls
+    }
+
+    /// Capture the current color of the pixels the given command writes,
+    /// before running it. Only as many pixels as can be undone are kept.
+    ///
+    /// Remember them with `record_undo` once the command wrote any pixels.
+    fn capture_undo(&self, command: &Cmd) -> VecDeque<(usize, usize, Color)> {
+        let mut captured = VecDeque::new();
+        let depth = match (&self.undo, self.opts.undo_depth) {
+            (Some(_), Some(depth)) if depth > 0 => depth,
+            _ => return captured,
+        };
+
+        let pixmap = &self.pixmap;
+        command.for_each_written(pixmap, &self.opts, |x, y| {
+            if let Ok(color) = pixmap.pixel(x, y) {
+                if captured.len() >= depth {
+                    captured.pop_front();
+                }
+                captured.push_back((x, y, color));
+            }
+        });
+        captured
+    }
+
+    /// Remember the pixels captured with `capture_undo`, so they can be
+    /// restored with UNDO. Only the most recent pixels are kept.
+    fn record_undo(&mut self, captured: VecDeque<(usize, usize, Color)>) {
+        let (undo, depth) = match (&mut self.undo, self.opts.undo_depth) {
+            (Some(undo), Some(depth)) if depth > 0 => (undo, depth),
+            _ => return,
+        };
+
+        for pixel in captured {
+            if undo.len() >= depth {
+                undo.pop_front();
+            }
+            undo.push_back(pixel);
+        }
+    }
+
+    /// Restore the remembered pixels, most recent first. Returns the number of
+    /// restored pixels.
+    fn undo(&mut self) -> Option<usize> {
+        let undo = self.undo.as_mut()?;
+        let count = undo.len();
+        while let Some((x, y, color)) = undo.pop_back() {
+            let _ = self.pixmap.set_pixel_opaque(x, y, color);
+        }
+        Some(count)
+    }
+
+    /// List the limits of this connection and their current use, to respond
+    /// to a client.
+    ///
+    /// Each limit is listed as `LIMIT <key>=<value>` on its own line, or a
+    /// single `LIMIT none` line if this connection isn't limited.
+    fn limits_list(&self) -> String {
+        let mut limits = Vec::new();
+
+        if let Some(RateLimit::BitsPerSecond { limit, burst }) = self.opts.rate_limit {
+            // Include the tokens refilled since they were last taken
+            let elapsed = Instant::now()
+                .duration_since(self.last_refill_time)
+                .as_secs_f64();
+            let tokens = (self.rate_tokens * 8.0 + elapsed * limit as f64).min(burst as f64);
+            limits.push(format!("bps={}", limit));
+            limits.push(format!("bps-burst={}", burst));
+            limits.push(format!("bps-available={}", tokens.max(0.0) as u64));
+        }
+
+        if let Some(limit) = self.opts.cmd_rate_limit {
+            // The window may have ended without processing another command
+            let used = if self.cmd_window_start.elapsed() < CMD_RATE_WINDOW {
+                self.cmd_count
+            } else {
+                0
+            };
+            limits.push(format!("cps={}", limit));
+            limits.push(format!("cps-used={}", used));
+        }
+
+        if limits.is_empty() {
+            limits.push("none".into());
+        }
+
+        limits
+            .iter()
+            .map(|limit| format!("LIMIT {}", limit))
+            .collect::<Vec<_>>()
+            .join(self.opts.response_terminator.as_str())
+    }
+
+    /// Subscribe to, or unsubscribe from, canvas size changes.
+    ///
+    /// When subscribed, a `SIZE` line is sent to the client each time the
+    /// canvas size changes.
+    fn subscribe_size(&mut self, subscribe: bool) {
+        self.size_change = subscribe.then(|| {
+            let mut size = self.size.clone();
+            size.borrow_and_update();
+            wait_size_change(size)
+        });
+    }
+
+    /// Notify the client about canvas size changes, if subscribed.
+    fn poll_size_change(&mut self, cx: &mut std::task::Context<'_>) {
+        let size = match self.size_change.as_mut().map(|f| f.as_mut().poll(cx)) {
+            Some(Poll::Ready(size)) => size,
+            _ => return,
+        };
+
+        self.size_change = size.map(|mut size| {
+            let (width, height) = *size.borrow_and_update();
+            self.respond(format!("SIZE {} {}", width, height).as_bytes(), cx);
+            wait_size_change(size)
+        });
+    }
+
     /// Buffer a line.
     ///
     /// This writes the line to an internal buffer. Calls to `poll_flush` will
@@ -141,12 +774,21 @@ where
         cx.waker().wake_by_ref();
     }
 
+    /// Buffer a response line, followed by the response terminator.
+    fn respond(&mut self, line: &[u8], cx: &mut std::task::Context<'_>) {
+        self.buffer(line, cx);
+        self.buffer(self.opts.response_terminator.as_str().as_bytes(), cx);
+    }
+
     /// Flush the write buffer to the socket
-    pub fn poll_write(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), &str>> {
+    pub fn poll_write(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), DisconnectReason>> {
         let Self { socket, wr, .. } = self;
 
         match socket.as_mut().poll_write(cx, wr) {
-            Poll::Ready(Ok(0)) => Poll::Ready(Err("Client disconnected")),
+            Poll::Ready(Ok(0)) => Poll::Ready(Err(DisconnectReason::Eof)),
             Poll::Ready(Ok(size)) => {
                 let _ = wr.split_to(size);
                 if self.wr.is_empty() {
@@ -156,7 +798,7 @@ where
                     Poll::Pending
                 }
             }
-            Poll::Ready(Err(_)) => Poll::Ready(Err("Socket error")),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(DisconnectReason::SocketError)),
             Poll::Pending => Poll::Pending,
         }
     }
@@ -169,6 +811,90 @@ where
         }
     }
 
+    /// Run a command on a blocking thread, giving up on it after the given
+    /// timeout. The command returns its result, and the number of pixels it
+    /// set.
+    ///
+    /// A command that times out keeps running in the background. Its result is
+    /// discarded, but the pixels it set are counted once it completes.
+    fn offload<F>(&mut self, command: F, timeout: Duration)
+    where
+        F: FnOnce() -> (CmdResult, usize) + Send + 'static,
+    {
+        self.offloaded = Some(Offloaded {
+            task: tokio::task::spawn_blocking(command),
+            timeout: Box::pin(tokio::time::sleep(timeout)),
+        });
+    }
+
+    /// Wait for the command running on a blocking thread to complete or time
+    /// out, and handle its result.
+    ///
+    /// Only the results of expensive commands, see `Cmd::is_expensive`, are
+    /// handled.
+    fn poll_offloaded(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), DisconnectReason>> {
+        let offloaded = match &mut self.offloaded {
+            Some(offloaded) => offloaded,
+            None => return Poll::Ready(Ok(())),
+        };
+
+        let result = match Pin::new(&mut offloaded.task).poll(cx) {
+            Poll::Ready(Ok(result)) => Some(result),
+            Poll::Ready(Err(_)) => Some((CmdResult::ClientErr("command failed".into()), 0)),
+            Poll::Pending if offloaded.timeout.as_mut().poll(cx).is_ready() => None,
+            Poll::Pending => return Poll::Pending,
+        };
+        let task = self.offloaded.take().map(|offloaded| offloaded.task);
+
+        let (result, pixels) = match result {
+            Some(result) => result,
+            None => {
+                self.timed_out = task;
+                self.respond(b"ERR command timed out", cx);
+                return Poll::Ready(Ok(()));
+            }
+        };
+        self.add_pixels(pixels);
+
+        match result {
+            CmdResult::Ok => {}
+            CmdResult::Response(msg) => self.respond(msg.as_bytes(), cx),
+            CmdResult::ClientErr(err) => {
+                self.respond(format!("ERR {}", err).as_bytes(), cx);
+                return Poll::Ready(Err(DisconnectReason::ClientError(err)));
+            }
+            _ => unreachable!("unexpected result of an offloaded command"),
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Check whether a command that timed out completed in the background, and
+    /// count the pixels it set.
+    fn poll_timed_out(&mut self, cx: &mut std::task::Context<'_>) {
+        let task = match &mut self.timed_out {
+            Some(task) => task,
+            None => return,
+        };
+        if let Poll::Ready(result) = Pin::new(task).poll(cx) {
+            self.timed_out = None;
+            if let Ok((_, pixels)) = result {
+                self.add_pixels(pixels);
+            }
+        }
+    }
+
+    /// Count the given number of pixels as set by this connection.
+    fn add_pixels(&mut self, pixels: usize) {
+        self.stats.inc_pixels_by_n(pixels);
+        self.pixels += pixels;
+        if let Some(score) = &self.score {
+            score.fetch_add(pixels, Ordering::Relaxed);
+        }
+    }
+
     /// Read data from the socket if the buffer isn't full enough,
     /// and it's length reached the lower size threshold.
     ///
@@ -178,7 +904,7 @@ where
     /// that is available.
     ///
     /// If the return value is Poll::Ready, the result contains either `Ok(new_rd_size)` or
-    /// `Err(disconnect reason message)`.
+    /// `Err(())` if the client disconnected.
     #[inline(always)]
     fn fill_read_buf(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<usize, ()>> {
         let rd = self.rd.rd();
@@ -192,22 +918,44 @@ where
         }
 
         let read_len = match self.opts.rate_limit {
-            Some(RateLimit::BitsPerSecond { limit: bps }) => {
-                let duration_since_last_refill =
-                    Instant::now().duration_since(self.last_refill_time);
-                let allowed = ((duration_since_last_refill.as_secs_f32() * (bps as f32 / 8.0))
-                    as usize)
-                    .min(BUF_SIZE - len);
-
-                let wait_dur =
-                    Duration::from_nanos(((BUF_SIZE as u64 * 1_000_000_000) / bps as u64).max(1));
-                self.try_wait_for(wait_dur);
+            // Token bucket, refilled at the configured rate up to the burst capacity
+            Some(RateLimit::BitsPerSecond { limit: bps, burst }) => {
+                let bytes_per_sec = (bps as f64 / 8.0).max(1.0);
+
+                let now = Instant::now();
+                let elapsed = now.duration_since(self.last_refill_time).as_secs_f64();
+                self.rate_tokens =
+                    (self.rate_tokens + elapsed * bytes_per_sec).min((burst as f64 / 8.0).max(1.0));
+                self.last_refill_time = now;
+
+                let allowed = (self.rate_tokens as usize).min(BUF_SIZE - len);
+
+                // Out of tokens, wait for about a tenth of a second worth of data
+                if allowed == 0 {
+                    let wanted = (bytes_per_sec / 10.0).clamp(1.0, (BUF_SIZE - len) as f64);
+                    let wait_dur = Duration::from_secs_f64(
+                        ((wanted - self.rate_tokens) / bytes_per_sec).max(0.000_001),
+                    );
+                    self.try_wait_for(wait_dur);
+                }
 
                 allowed
             }
             None => BUF_SIZE - len,
         };
 
+        // Limit to this connection's share of the bandwidth of all connections
+        let read_len = match &mut self.global_share {
+            Some(share) if read_len > 0 => match share.allowed(read_len) {
+                Ok(allowed) => allowed,
+                Err(wait_dur) => {
+                    self.try_wait_for(wait_dur);
+                    0
+                }
+            },
+            _ => read_len,
+        };
+
         if read_len == 0 {
             return Poll::Ready(Ok(len));
         }
@@ -231,18 +979,48 @@ where
         wr.commit(amount);
 
         self.stats.inc_bytes_read(amount);
+        self.bytes_read += amount;
 
-        self.last_refill_time = Instant::now();
+        if let Some(rate) = &mut self.rate {
+            rate.update(self.bytes_read);
+        }
+
+        if self.opts.rate_limit.is_some() {
+            self.rate_tokens -= amount as f64;
+        }
+
+        if let Some(share) = &mut self.global_share {
+            share.consume(amount);
+        }
 
         // We're done reading
         Poll::Ready(Ok(self.rd.rd().len()))
     }
 
     #[inline(always)]
-    fn process_rx_buffer(&mut self, cx: &mut std::task::Context<'_>) -> Result<(), String> {
+    fn process_rx_buffer(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> Result<(), DisconnectReason> {
         let mut pixels = 0;
 
-        let error_message = loop {
+        let disconnect_reason = loop {
+            // Stop processing if the command budget for this window is spent,
+            // and wait for the next window
+            if let Some(limit) = self.opts.cmd_rate_limit {
+                let now = Instant::now();
+                if now.duration_since(self.cmd_window_start) >= CMD_RATE_WINDOW {
+                    self.cmd_window_start = now;
+                    self.cmd_count = 0;
+                }
+                if self.cmd_count >= limit {
+                    let wait = CMD_RATE_WINDOW - now.duration_since(self.cmd_window_start);
+                    self.try_wait_for(wait);
+                    self.cmd_limited = true;
+                    break None;
+                }
+            }
+
             let mut rd = self.rd.rd();
 
             let rd_len = rd.len();
@@ -256,8 +1034,10 @@ where
                 let input_bytes = &rd.data()[..PXB_CMD_SIZE];
 
                 const OFF: usize = PXB_PREFIX.len();
-                let x = u16::from_le_bytes(input_bytes[OFF..OFF + 2].try_into().expect("Huh"));
-                let y = u16::from_le_bytes(input_bytes[OFF + 2..OFF + 4].try_into().expect("Huh"));
+                let endian = self.opts.binary_endian;
+                let x = endian.u16_from_bytes(input_bytes[OFF..OFF + 2].try_into().expect("Huh"));
+                let y =
+                    endian.u16_from_bytes(input_bytes[OFF + 2..OFF + 4].try_into().expect("Huh"));
 
                 let r = input_bytes[OFF + 4];
                 let g = input_bytes[OFF + 5];
@@ -267,7 +1047,20 @@ where
                 rd.consume(PXB_CMD_SIZE);
 
                 Cmd::SetPixel(x as usize, y as usize, Color::from_rgba(r, g, b, a))
+            } else if self.opts.allow_binary_cmd
+                && rd_len >= PSB_PREFIX.len()
+                && rd.data()[..PSB_PREFIX.len()] == PSB_PREFIX
+            {
+                rd.consume(PSB_PREFIX.len());
+
+                Cmd::BinarySize
             } else if !is_binary_command {
+                // Skip empty lines right away, there's nothing to decode
+                if let Some(b'\n' | b'\r') = rd.data().first() {
+                    rd.consume(1);
+                    continue;
+                }
+
                 // Find the new line character
                 let pos = rd
                     .data()
@@ -287,12 +1080,24 @@ where
                     let line = &rd.data()[..pos];
 
                     // Return the line
-                    let output = match Cmd::decode_line(line) {
+                    let output = match Cmd::decode_line_erase(
+                        line,
+                        self.opts.erase_token.as_ref().map(EraseToken::as_str),
+                    ) {
                         Ok(cmd) => cmd,
                         Err(e) => {
                             // Report the error to the client
-                            self.buffer(format!("ERR {}\r\n", e).as_bytes(), cx);
-                            break Some("Command decoding failed".to_string());
+                            self.respond(format!("ERR {}", e).as_bytes(), cx);
+
+                            // Skip the line if the error budget allows it
+                            match self.opts.error_budget {
+                                Some(budget) if self.decode_errors < budget => {
+                                    self.decode_errors += 1;
+                                    self.rd.rd().consume(pos + newlines);
+                                    continue;
+                                }
+                                _ => break Some(DisconnectReason::DecodeError),
+                            }
                         }
                     };
 
@@ -303,10 +1108,10 @@ where
                     // If no line ending was found, and the buffer is larger than the
                     // maximum command length, disconnect
 
-                    self.buffer(b"ERR Line length >1024\r\n", cx);
+                    self.respond(b"ERR Line length >1024", cx);
 
                     // Break the connection, by ending the lines stream
-                    break Some("Client line length too long".to_string());
+                    break Some(DisconnectReason::LineTooLong);
                 } else {
                     // Didn't find any more data to process
                     break None;
@@ -315,7 +1120,81 @@ where
                 break None;
             };
 
+            self.cmd_count += 1;
+
+            if let Some(kind) = command.kind() {
+                self.stats.inc_commands(kind);
+            }
+
+            // Count the size of the consumed command, including its line
+            // terminator
+            #[cfg(feature = "prometheus")]
+            self.stats
+                .observe_command_bytes(rd_len - self.rd.rd().len());
+
+            if let Some((log, addr)) = &self.command_log {
+                log.log(*addr, &command);
+            }
+
+            // Refuse commands before the handshake, if required
+            if self.opts.require_hello
+                && self.protocol.is_none()
+                && !matches!(command, Cmd::Hello(_) | Cmd::None)
+            {
+                self.respond(b"ERR HELLO required", cx);
+                break Some(DisconnectReason::ClientError("HELLO required".to_string()));
+            }
+
+            // Refuse commands newer than the negotiated protocol version
+            if let Some(protocol) = self.protocol {
+                if command.version() > protocol {
+                    let err = format!("command requires protocol version {}", command.version());
+                    self.respond(format!("ERR {}", err).as_bytes(), cx);
+                    break Some(DisconnectReason::ClientError(err));
+                }
+            }
+
+            // Refuse writes while paused, without disconnecting
+            let paused = self
+                .paused
+                .as_ref()
+                .is_some_and(|p| p.load(Ordering::Relaxed));
+            if paused && command.kind() == Some(CmdKind::Set) {
+                self.respond(b"ERR writes paused", cx);
+                continue;
+            }
+
+            // Run expensive commands on a blocking thread, and wait for them
+            // before processing more
+            if let (Some(timeout), true) = (self.opts.command_timeout, command.is_expensive()) {
+                // Don't pile up blocking threads behind a command that timed out
+                self.poll_timed_out(cx);
+                if self.timed_out.is_some() {
+                    self.respond(b"ERR previous command still running", cx);
+                    continue;
+                }
+
+                let (pixmap, opts) = (self.pixmap.clone(), self.opts);
+                self.offload(
+                    move || {
+                        let mut pixels = 0;
+                        let result = command.invoke(&pixmap, &mut pixels, &opts);
+                        (result, pixels)
+                    },
+                    timeout,
+                );
+                break None;
+            }
+
+            // Only remember overwritten pixels once the command wrote any, a
+            // failed or refused write must not restore them on UNDO
+            let captured = self.capture_undo(&command);
+            let pixels_before = pixels;
             let result = command.invoke(&self.pixmap, &mut pixels, &self.opts);
+            if pixels > pixels_before {
+                self.record_undo(captured);
+            }
+
             // Do something with the result
             match result {
                 // Do nothing
@@ -324,30 +1203,87 @@ where
                 // Respond to the client
                 CmdResult::Response(msg) => {
                     // Create a bytes buffer with the message
-                    self.buffer(msg.as_bytes(), cx);
-                    self.buffer(b"\r\n", cx);
+                    self.respond(msg.as_bytes(), cx);
                 }
 
+                // Respond to the client, without line terminator
+                CmdResult::BinaryResponse(bytes) => self.buffer(&bytes, cx),
+
                 // Report the error to the user
                 CmdResult::ClientErr(err) => {
                     // Report the error to the client
-                    self.buffer(format!("ERR {}\r\n", err).as_bytes(), cx);
-                    break Some(format!("Client error: {}", err));
+                    self.respond(format!("ERR {}", err).as_bytes(), cx);
+                    break Some(DisconnectReason::ClientError(err));
+                }
+
+                // (Un)subscribe to canvas size changes
+                CmdResult::SubscribeSize(subscribe) => self.subscribe_size(subscribe),
+
+                // Report the input rate of this connection
+                CmdResult::Rate => {
+                    let bytes_read = self.bytes_read;
+                    let rate = match &mut self.rate {
+                        Some(rate) => rate.update(bytes_read).unwrap_or(0.0),
+                        None => {
+                            self.respond(b"ERR rate command disabled", cx);
+                            break Some(DisconnectReason::ClientError(
+                                "rate command disabled".to_string(),
+                            ));
+                        }
+                    };
+                    self.respond(format!("RATE {}", rate as u64).as_bytes(), cx);
+                }
+
+                // Use the negotiated protocol version
+                CmdResult::Hello(version) => {
+                    self.protocol = Some(version);
+                    self.respond(format!("HELLO {}", version).as_bytes(), cx);
+                }
+
+                // Report the address of this connection
+                CmdResult::WhoAmI => match self.peer_addr {
+                    Some(addr) => self.respond(format!("WHOAMI {}", addr).as_bytes(), cx),
+                    None => self.respond(b"ERR address unknown", cx),
+                },
+
+                // Report the limits of this connection
+                CmdResult::Limits => {
+                    let limits = self.limits_list();
+                    self.respond(limits.as_bytes(), cx);
                 }
 
-                // Quit the connection
+                // Restore the pixels last written by this connection
+                CmdResult::Undo => match self.undo() {
+                    Some(count) => self.respond(format!("UNDO {}", count).as_bytes(), cx),
+                    None => {
+                        self.respond(b"ERR undo command disabled", cx);
+                        break Some(DisconnectReason::ClientError(
+                            "undo command disabled".to_string(),
+                        ));
+                    }
+                },
+
+                // Quit the connection, after sending the summary
                 CmdResult::Quit => {
-                    break Some("Client sent QUIT".to_string());
+                    if self.opts.quit_summary {
+                        let summary = format!(
+                            "STATS px={} bytes={}",
+                            self.pixels + pixels,
+                            self.bytes_read
+                        );
+                        self.respond(summary.as_bytes(), cx);
+                    }
+                    break Some(DisconnectReason::ClientQuit);
                 }
             }
         };
 
         // Increase the amount of set pixels by the amount of pixel set commands
         // that we processed in this batch
-        self.stats.inc_pixels_by_n(pixels);
+        self.add_pixels(pixels);
 
-        if let Some(disconnect_message) = error_message {
-            Err(disconnect_message)
+        if let Some(reason) = disconnect_reason {
+            Err(reason)
         } else {
             Ok(())
         }
@@ -359,9 +1295,12 @@ where
     T: DerefMut + Unpin,
     T::Target: AsyncRead + AsyncWrite + Unpin,
 {
-    type Output = String;
+    type Output = DisconnectReason;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        // Queue a size notification if the canvas size changed
+        self.poll_size_change(cx);
+
         // First try to write all we have left to write
         let write_is_pending = if !self.wr.is_empty() {
             match self.poll_write(cx) {
@@ -369,17 +1308,27 @@ where
                     // We've finished writing, do nothing
                     false
                 }
-                Poll::Ready(Err(e)) => return Poll::Ready(e.to_string()),
+                Poll::Ready(Err(reason)) => return Poll::Ready(reason),
                 Poll::Pending => true,
             }
         } else {
             false
         };
 
-        if !write_is_pending {
-            if let Some(reason) = &self.disconnecting {
-                return Poll::Ready(reason.clone());
+        // When disconnecting, stop receiving and flush everything that is left
+        // to write first, so the client receives the reason
+        if let Some(reason) = &self.disconnecting {
+            if write_is_pending {
+                return Poll::Pending;
             }
+            let reason = reason.clone();
+            return match self.socket.as_mut().poll_shutdown(cx) {
+                Poll::Ready(_) => Poll::Ready(reason),
+                Poll::Pending => Poll::Pending,
+            };
+        }
+
+        if !write_is_pending {
             if let Some(sleep) = &mut self.rx_wait {
                 if sleep.as_mut().poll(cx).is_pending() {
                     return Poll::Pending;
@@ -393,31 +1342,63 @@ where
             return Poll::Pending;
         }
 
-        // Try to read any new data into the read buffer
-        let fill_read_buf = self.fill_read_buf(cx);
-
-        match fill_read_buf {
-            // An error occured (most likely disconnection)
-            Poll::Ready(Err(_)) => return Poll::Ready("Client disconnected".into()),
-            Poll::Ready(Ok(new_rd_len)) => {
-                if new_rd_len < 2 {
-                    // If the buffer cannot possibly contain a command, it makes sense
-                    // to return `Poll::Pending`. However, this also means that we've now
-                    // created our own pending condition that does not have a waker set by
-                    // an underlying implementation. To avoid having to set that up, we simply
-                    // defer our waking to `fill_read_buf` (which in turn defers it to some tokio::io
-                    // impl) by waking our task immediately.
-                    cx.waker().wake_by_ref();
-                    return Poll::Pending;
-                }
+        // Wait for a command running on a blocking thread. Once it completes,
+        // or after waiting out the command rate limit, first process the
+        // commands left in the buffer, without reading
+        self.poll_timed_out(cx);
+        let cmd_limited = std::mem::take(&mut self.cmd_limited);
+        let resumed = cmd_limited || self.offloaded.is_some();
+        match self.poll_offloaded(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(reason)) => {
+                self.disconnecting = Some(reason);
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
             }
             Poll::Pending => return Poll::Pending,
         }
 
+        if !resumed {
+            // Try to read any new data into the read buffer
+            let fill_read_buf = self.fill_read_buf(cx);
+
+            match fill_read_buf {
+                // An error occured (most likely disconnection)
+                Poll::Ready(Err(_)) => return Poll::Ready(DisconnectReason::Eof),
+                Poll::Ready(Ok(new_rd_len)) => {
+                    if new_rd_len < 2 {
+                        // If the buffer cannot possibly contain a command, it makes sense
+                        // to return `Poll::Pending`. However, this also means that we've now
+                        // created our own pending condition that does not have a waker set by
+                        // an underlying implementation. To avoid having to set that up, we simply
+                        // defer our waking to `fill_read_buf` (which in turn defers it to some tokio::io
+                        // impl) by waking our task immediately.
+                        cx.waker().wake_by_ref();
+                        return Poll::Pending;
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        // Only time processing when slow polls are reported
+        let rx_process_start = self.opts.slow_poll_threshold.map(|_| Instant::now());
+
         let rx_process_result = self.process_rx_buffer(cx);
 
-        if let Err(disconnect_message) = rx_process_result {
-            self.disconnecting = Some(disconnect_message);
+        if let (Some(threshold), Some(start)) = (self.opts.slow_poll_threshold, rx_process_start) {
+            let elapsed = start.elapsed();
+            if elapsed >= threshold {
+                self.slow_polls += 1;
+                eprintln!(
+                    "Warning: processing commands took {:.1}ms, over the slow poll threshold",
+                    elapsed.as_secs_f64() * 1000.0,
+                );
+            }
+        }
+
+        if let Err(reason) = rx_process_result {
+            self.disconnecting = Some(reason);
         }
 
         if !write_is_pending {
@@ -1,24 +1,80 @@
+use pixelpwnr_render::WriteMode;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf,
+    WriteHalf,
+};
 use tokio_test::io::Builder;
 
 use super::*;
+use crate::cmd::PROTOCOL_VERSION;
 
-const CODEC_OPTS: CodecOptions = CodecOptions {
-    rate_limit: None,
-    allow_binary_cmd: true,
-};
+const CODEC_OPTS: CodecOptions = CodecOptions::new();
 
 async fn run<T>(lines: T, opts: Option<CodecOptions>)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (lines, _) = new_lines(lines, opts.unwrap_or(CODEC_OPTS));
+    lines.await;
+}
+
+/// Create a connection over the given socket, on a new 400x800 canvas.
+///
+/// Returns the connection, and the stats it reports to.
+fn new_lines<T>(socket: T, opts: CodecOptions) -> (Lines<Box<T>>, Arc<Stats>)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    new_lines_on(socket, Arc::new(Pixmap::new(400, 800)), opts)
+}
+
+/// Like `new_lines`, on the given canvas.
+fn new_lines_on<T>(
+    socket: T,
+    pixmap: Arc<Pixmap>,
+    opts: CodecOptions,
+) -> (Lines<Box<T>>, Arc<Stats>)
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     let stats = Arc::new(Stats::new());
-    let pixmap = Arc::new(Pixmap::new(400, 800));
+    let (_size_tx, size_rx) = watch::channel(pixmap.dimensions());
 
-    let lines = Box::pin(lines);
+    let lines = Lines::new(Box::pin(socket), stats.clone(), pixmap, opts, size_rx);
+    (lines, stats)
+}
 
-    let lines = Lines::new(lines, stats, pixmap, opts.unwrap_or(CODEC_OPTS));
+/// The reading and writing half of a client connected to a spawned connection.
+type Client = (BufReader<ReadHalf<DuplexStream>>, WriteHalf<DuplexStream>);
 
-    lines.await;
+/// Spawn a connection on a new 400x800 canvas, and connect a client to it.
+///
+/// Returns the stats the connection reports to, and the client.
+fn spawn_lines(opts: CodecOptions) -> (Arc<Stats>, Client) {
+    spawn_lines_with(opts, |lines| lines)
+}
+
+/// Like `spawn_lines`, configuring the connection with `f` before spawning it.
+fn spawn_lines_with<F>(opts: CodecOptions, f: F) -> (Arc<Stats>, Client)
+where
+    F: FnOnce(Lines<Box<DuplexStream>>) -> Lines<Box<DuplexStream>>,
+{
+    let (client, server) = tokio::io::duplex(BUF_SIZE);
+    let (lines, stats) = new_lines(server, opts);
+    tokio::spawn(f(lines));
+
+    let (rd, wr) = tokio::io::split(client);
+    (stats, (BufReader::new(rd), wr))
+}
+
+/// Read a single line from the given reader, including the line terminator.
+async fn read_line<R>(reader: &mut R) -> String
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    reader.read_line(&mut line).await.unwrap();
+    line
 }
 
 #[tokio::test]
@@ -57,19 +113,737 @@ async fn binary_command() {
     run(test, None).await;
 }
 
+#[tokio::test]
+async fn binary_command_big_endian() {
+    let test = Builder::new()
+        .read(&[b'P', b'B', 0x01, 0x02, 0, 5, 0xAB, 0xCD, 0xEF, 0xFF])
+        .read(b"PX 258 5\n")
+        .write(b"PX 258 5 ABCDEF\r\n")
+        .build();
+
+    run(test, Some(CODEC_OPTS.with_binary_endian(Endianness::Be))).await;
+}
+
+#[tokio::test]
+async fn binary_size() {
+    let test = Builder::new()
+        .read(b"PS")
+        .write(&[b'P', b'S', 0x90, 0x01, 0, 0, 0x20, 0x03, 0, 0])
+        // Binary and text commands may be mixed
+        .read(b"PSSIZE\n")
+        .write(&[b'P', b'S', 0x90, 0x01, 0, 0, 0x20, 0x03, 0, 0])
+        .write(b"SIZE 400 800\r\n")
+        .build();
+
+    run(test, None).await;
+}
+
 #[tokio::test]
 async fn binary_command_with_binopt() {
     let codec_opts = Some(CodecOptions {
         allow_binary_cmd: false,
-        rate_limit: None,
+        ..CODEC_OPTS
     });
 
     let test = Builder::new()
         // Note: we need the `\n` so that the program will detect that a command has
         // been passed in, as binary commands are supposed to be disabled.
         .read(&[b'P', b'B', 5, 0, 5, 0, 0xAB, 0xCD, 0xEF, 0xFF, b'\n'])
-        .write(b"ERR")
+        .write(b"ERR unknown command, use HELP\r\n")
+        .build();
+
+    run(test, codec_opts).await;
+}
+
+#[tokio::test]
+async fn subscribe_size() {
+    let (client, server) = tokio::io::duplex(1024);
+    let (size_tx, size_rx) = watch::channel((400, 800));
+
+    let stats = Arc::new(Stats::new());
+    let pixmap = Arc::new(Pixmap::new(400, 800));
+    tokio::spawn(Lines::new(
+        Box::pin(server),
+        stats,
+        pixmap,
+        CODEC_OPTS,
+        size_rx,
+    ));
+
+    let (rd, mut wr) = tokio::io::split(client);
+    let mut rd = BufReader::new(rd);
+
+    // Subscribed clients are notified of size changes
+    wr.write_all(b"SUBSCRIBE SIZE\r\nSIZE\r\n").await.unwrap();
+    assert_eq!(read_line(&mut rd).await, "SIZE 400 800\r\n");
+    size_tx.send((200, 100)).unwrap();
+    assert_eq!(read_line(&mut rd).await, "SIZE 200 100\r\n");
+
+    // Unsubscribed clients are not
+    wr.write_all(b"UNSUBSCRIBE SIZE\r\nSIZE\r\n").await.unwrap();
+    assert_eq!(read_line(&mut rd).await, "SIZE 400 800\r\n");
+    size_tx.send((300, 300)).unwrap();
+    wr.write_all(b"SIZE\r\n").await.unwrap();
+    assert_eq!(read_line(&mut rd).await, "SIZE 400 800\r\n");
+}
+
+#[tokio::test]
+async fn store_raw() {
+    let (client, server) = tokio::io::duplex(1024);
+
+    let pixmap = Arc::new(Pixmap::new(400, 800).with_write_mode(WriteMode::Replace));
+    let (lines, _) = new_lines_on(server, pixmap, CODEC_OPTS);
+    tokio::spawn(lines);
+
+    let (rd, mut wr) = tokio::io::split(client);
+    let mut rd = BufReader::new(rd);
+
+    // A semi-transparent color reads back exactly, without blending
+    wr.write_all(b"PX 1 2 FF000080\r\nPX 1 2\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_line(&mut rd).await, "PX 1 2 FF000080\r\n");
+
+    // Opaque colors read back without an alpha channel
+    wr.write_all(b"PX 1 2 00FF00\r\nPX 1 2\r\n").await.unwrap();
+    assert_eq!(read_line(&mut rd).await, "PX 1 2 00FF00\r\n");
+}
+
+#[tokio::test]
+async fn command_timeout() {
+    let opts = CODEC_OPTS
+        .with_drawtext(true)
+        .with_command_timeout(Some(Duration::from_secs(10)));
+
+    // A command that runs until it is released, much longer than its timeout
+    let (release, released) = std::sync::mpsc::channel::<()>();
+    let (stats, (mut rd, mut wr)) = spawn_lines_with(opts, |mut lines| {
+        lines.offload(
+            move || {
+                released.recv().unwrap();
+                (CmdResult::Response("too late".into()), 5)
+            },
+            Duration::from_millis(20),
+        );
+        lines
+    });
+
+    // The command is cut off, and the connection keeps processing commands
+    wr.write_all(b"SIZE\r\n").await.unwrap();
+    assert_eq!(read_line(&mut rd).await, "ERR command timed out\r\n");
+    assert_eq!(read_line(&mut rd).await, "SIZE 400 800\r\n");
+
+    // Other expensive commands are refused while it keeps running
+    wr.write_all(b"DRAWTEXT 1 1 FF0000 T\r\n").await.unwrap();
+    assert_eq!(
+        read_line(&mut rd).await,
+        "ERR previous command still running\r\n"
+    );
+    assert_eq!(stats.pixels(), 0);
+
+    // Its pixels are counted once it completes
+    release.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while stats.pixels() != 5 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await
+    .unwrap();
+    wr.write_all(b"DRAWTEXT 1 1 FF0000 T\r\nPX 1 1\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_line(&mut rd).await, "PX 1 1 FF0000\r\n");
+    assert_eq!(stats.pixels(), 12);
+}
+
+#[tokio::test]
+async fn command_offloaded() {
+    let opts = CODEC_OPTS
+        .with_drawtext(true)
+        .with_command_timeout(Some(Duration::from_secs(10)));
+    let (stats, (mut rd, mut wr)) = spawn_lines(opts);
+
+    // Commands buffered after an offloaded command run once it completes
+    wr.write_all(b"DRAWTEXT 1 1 FF0000 T\r\nPX 1 1\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_line(&mut rd).await, "PX 1 1 FF0000\r\n");
+    assert_eq!(stats.pixels(), 7);
+}
+
+#[tokio::test]
+async fn pause_writes() {
+    let paused = Arc::new(AtomicBool::new(true));
+    let (_, (mut rd, mut wr)) =
+        spawn_lines_with(CODEC_OPTS, |lines| lines.with_pause(paused.clone()));
+
+    // Writes are refused while paused, reads keep working
+    wr.write_all(b"PX 1 1 FF0000\r\nPX 1 1\r\nSIZE\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_line(&mut rd).await, "ERR writes paused\r\n");
+    assert_eq!(read_line(&mut rd).await, "PX 1 1 000000\r\n");
+    assert_eq!(read_line(&mut rd).await, "SIZE 400 800\r\n");
+
+    // Writes are accepted again once resumed
+    paused.store(false, Ordering::Relaxed);
+    wr.write_all(b"PX 1 1 FF0000\r\nPX 1 1\r\n").await.unwrap();
+    assert_eq!(read_line(&mut rd).await, "PX 1 1 FF0000\r\n");
+}
+
+#[tokio::test]
+async fn hello() {
+    let test = Builder::new()
+        // The version is capped at the latest version
+        .read(b"HELLO 99\r\n")
+        .write(format!("HELLO {}\r\n", PROTOCOL_VERSION).as_bytes())
+        // Extensions are disabled on the plain protocol version
+        .read(b"HELLO 1\r\nPX 1 1 FF0000\r\nPX 1 1\r\n")
+        .write(b"HELLO 1\r\n")
+        .write(b"PX 1 1 FF0000\r\n")
+        .read(b"PXRUN 1 1 2 FF0000\r\n")
+        .write(b"ERR command requires protocol version 2\r\n")
+        .build();
+
+    run(test, Some(CODEC_OPTS.with_require_hello(true))).await;
+}
+
+#[tokio::test]
+async fn hello_required() {
+    let test = Builder::new()
+        .read(b"PX 1 1\r\n")
+        .write(b"ERR HELLO required\r\n")
+        .build();
+
+    run(test, Some(CODEC_OPTS.with_require_hello(true))).await;
+}
+
+#[tokio::test]
+async fn limits() {
+    let test = Builder::new()
+        .read(b"LIMITS\r\n")
+        .write(b"LIMIT none\r\n")
+        .build();
+    run(test, None).await;
+
+    let opts = CODEC_OPTS.with_rate_limit(Some(RateLimit::BitsPerSecond {
+        limit: 1_000_000,
+        burst: 2_000_000,
+    }));
+    let (_, (mut rd, mut wr)) = spawn_lines(opts);
+    wr.write_all(b"LIMITS\r\n").await.unwrap();
+    assert_eq!(read_line(&mut rd).await, "LIMIT bps=1000000\r\n");
+    assert_eq!(read_line(&mut rd).await, "LIMIT bps-burst=2000000\r\n");
+    assert!(read_line(&mut rd).await.starts_with("LIMIT bps-available="));
+}
+
+#[tokio::test]
+async fn whoami() {
+    let test = Builder::new()
+        .read(b"WHOAMI\r\n")
+        .write(b"ERR address unknown\r\n")
+        .build();
+    run(test, None).await;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, addr) = listener.accept().await.unwrap();
+
+    let (lines, _) = new_lines(server, CODEC_OPTS);
+    tokio::spawn(lines.with_peer_addr(addr));
+
+    // The connection is seen from the address the client connected from
+    let local_addr = client.local_addr().unwrap();
+    let (rd, mut wr) = tokio::io::split(client);
+    let mut rd = BufReader::new(rd);
+    wr.write_all(b"WHOAMI\r\n").await.unwrap();
+    assert_eq!(
+        read_line(&mut rd).await,
+        format!("WHOAMI {}\r\n", local_addr)
+    );
+}
+
+#[tokio::test]
+async fn undo() {
+    let test = Builder::new()
+        .read(b"PX 1 1 00FF00\r\nPX 1 1 FF0000\r\nPX 2 1 0000FF\r\n")
+        // Pixels are restored most recent first, up to the undo depth
+        .read(b"UNDO\r\n")
+        .write(b"UNDO 2\r\n")
+        .read(b"PX 1 1\r\nPX 2 1\r\n")
+        .write(b"PX 1 1 00FF00\r\n")
+        .write(b"PX 2 1 000000\r\n")
+        // Undone pixels are forgotten
+        .read(b"UNDO\r\n")
+        .write(b"UNDO 0\r\n")
+        .build();
+
+    run(test, Some(CODEC_OPTS.with_undo_depth(Some(2)))).await;
+}
+
+#[tokio::test]
+async fn undo_failed_write() {
+    let (client, server) = tokio::io::duplex(1024);
+
+    let pixmap = Arc::new(Pixmap::new(400, 800));
+    let opts = CODEC_OPTS.with_undo_depth(Some(2));
+    let (lines, _) = new_lines_on(server, pixmap.clone(), opts);
+    tokio::spawn(lines);
+
+    let (rd, mut wr) = tokio::io::split(client);
+    let mut rd = BufReader::new(rd);
+
+    // A failed write isn't remembered
+    pixmap.set_pixel(1, 1, Color::from_rgb(0xFF, 0, 0)).unwrap();
+    wr.write_all(b"CAS 1 1 000000 00FF00\r\n").await.unwrap();
+    assert_eq!(read_line(&mut rd).await, "CAS 1 1 0\r\n");
+
+    // So undoing leaves pixels written by other clients since intact
+    pixmap.set_pixel(1, 1, Color::from_rgb(0, 0, 0xFF)).unwrap();
+    wr.write_all(b"UNDO\r\n").await.unwrap();
+    assert_eq!(read_line(&mut rd).await, "UNDO 0\r\n");
+    assert_eq!(pixmap.pixel(1, 1).unwrap(), Color::from_rgb(0, 0, 0xFF));
+}
+
+#[tokio::test(start_paused = true)]
+async fn rate_limit_burst() {
+    // Limit to 1000 bytes per second, with bursts up to 2000 bytes
+    let opts = CodecOptions {
+        rate_limit: Some(RateLimit::BitsPerSecond {
+            limit: 8_000,
+            burst: 16_000,
+        }),
+        ..CODEC_OPTS
+    };
+    let (stats, (_rd, mut wr)) = spawn_lines(opts);
+
+    wr.write_all(&[b'\n'; 5_000]).await.unwrap();
+
+    // A full burst is allowed right away
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let read = stats.bytes_read();
+    assert!((2_000..2_050).contains(&read), "read {} bytes", read);
+
+    // After that, the configured rate is enforced
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let read = stats.bytes_read();
+    assert!((2_850..3_150).contains(&read), "read {} bytes", read);
+}
+
+/// The sustained read rate converges to the configured limit, both below and
+/// above one read buffer per second.
+#[tokio::test(start_paused = true)]
+async fn rate_limit_sustained() {
+    for bytes_per_sec in [3_000, 1_000_000] {
+        let opts = CODEC_OPTS.with_rate_limit(Some(RateLimit::BitsPerSecond {
+            limit: bytes_per_sec * 8,
+            burst: bytes_per_sec * 8,
+        }));
+        let (stats, (_rd, mut wr)) = spawn_lines(opts);
+
+        // Keep the client sending as fast as it can
+        tokio::spawn(async move {
+            let chunk = [b'\n'; 4096];
+            while wr.write_all(&chunk).await.is_ok() {}
+        });
+
+        // Skip the initial burst, then measure for a few seconds
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let start = stats.bytes_read();
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        let rate = (stats.bytes_read() - start) as f64 / 4.0;
+
+        let error = (rate - bytes_per_sec as f64).abs() / bytes_per_sec as f64;
+        assert!(
+            error < 0.05,
+            "read {} bytes per second, limit is {}",
+            rate,
+            bytes_per_sec
+        );
+    }
+}
+
+/// Greedy connections under a global bandwidth limit each get an equal share.
+#[tokio::test(start_paused = true)]
+async fn global_limit_fair_share() {
+    let bytes_per_sec = 100_000;
+
+    // The limit divides among the clients counted in these stats
+    let limit_stats = Arc::new(Stats::new());
+    limit_stats.inc_clients();
+    limit_stats.inc_clients();
+    let limit = Arc::new(GlobalLimit::new(
+        bytes_per_sec * 8,
+        bytes_per_sec * 8,
+        limit_stats,
+    ));
+
+    let mut connections = Vec::new();
+    for _ in 0..2 {
+        let (stats, (_rd, mut wr)) =
+            spawn_lines_with(CODEC_OPTS, |lines| lines.with_global_limit(limit.clone()));
+
+        // Keep the client sending as fast as it can
+        tokio::spawn(async move {
+            let chunk = [b'\n'; 4096];
+            while wr.write_all(&chunk).await.is_ok() {}
+        });
+
+        connections.push(stats);
+    }
+
+    // Skip the initial burst, then measure for a few seconds
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let start: Vec<_> = connections.iter().map(|stats| stats.bytes_read()).collect();
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    for (stats, start) in connections.iter().zip(start) {
+        let rate = (stats.bytes_read() - start) as f64 / 4.0;
+        let share = bytes_per_sec as f64 / 2.0;
+        assert!(
+            (rate - share).abs() / share < 0.1,
+            "read {} bytes per second, fair share is {}",
+            rate,
+            share
+        );
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn cmd_rate_limit() {
+    // Limit to 10 commands per second
+    let opts = CodecOptions {
+        cmd_rate_limit: Some(10),
+        ..CODEC_OPTS
+    };
+    let (_, (mut client_rd, mut client_wr)) = spawn_lines(opts);
+
+    // Flood cheap commands
+    let start = Instant::now();
+    client_wr.write_all(&b"SIZE\n".repeat(25)).await.unwrap();
+
+    // Only 10 commands are processed each second
+    for i in 0..21 {
+        assert_eq!(read_line(&mut client_rd).await, "SIZE 400 800\r\n");
+        let window = start.elapsed().as_secs_f64() as usize;
+        assert_eq!(window, i / 10, "response {} in wrong window", i);
+    }
+}
+
+#[tokio::test]
+async fn compare_and_set() {
+    let test = Builder::new()
+        .read(b"CAS 1 1 000000 FF0000\n")
+        .write(b"CAS 1 1 1\r\n")
+        .read(b"CAS 1 1 000000 0000FF\n")
+        .write(b"CAS 1 1 0\r\n")
+        .read(b"PX 1 1\n")
+        .write(b"PX 1 1 FF0000\r\n")
+        .read(b"CAS 1000 1 000000 FF0000\n")
+        .write(b"ERR x coordinate out of bound\r\n")
+        .build();
+
+    run(test, None).await;
+}
+
+/// Send the given input from a client, and collect everything the server sends
+/// back until it disconnects, along with the disconnect reason.
+///
+/// A tiny buffer is used, so the server can't write its response at once.
+async fn disconnect_response(input: Vec<u8>) -> (Vec<u8>, DisconnectReason) {
+    let (client, server) = tokio::io::duplex(16);
+    let (mut client_rd, mut client_wr) = tokio::io::split(client);
+
+    let (lines, _) = new_lines(server, CODEC_OPTS);
+    let lines = tokio::spawn(lines);
+
+    // Send concurrently, the server may stop reading once it disconnects
+    tokio::spawn(async move {
+        let _ = client_wr.write_all(&input).await;
+        let _ = client_wr.shutdown().await;
+    });
+
+    let mut response = Vec::new();
+    client_rd.read_to_end(&mut response).await.unwrap();
+    (response, lines.await.unwrap())
+}
+
+#[tokio::test]
+async fn disconnect_line_too_long() {
+    let (response, reason) = disconnect_response(vec![b'A'; 2_000]).await;
+    assert_eq!(response, b"ERR Line length >1024\r\n");
+    assert_eq!(reason, DisconnectReason::LineTooLong);
+}
+
+#[tokio::test]
+async fn disconnect_decode_failure() {
+    let mut input = b"FOO\n".to_vec();
+    input.extend_from_slice(&b"PX 1 1\n".repeat(100));
+
+    // Nothing is processed after the failure
+    let (response, reason) = disconnect_response(input).await;
+    assert_eq!(response, b"ERR unknown command, use HELP\r\n");
+    assert_eq!(reason, DisconnectReason::DecodeError);
+}
+
+#[tokio::test]
+async fn disconnect_reasons() {
+    let (_, reason) = disconnect_response(b"PX 1 1 FF0000\nQUIT\n".to_vec()).await;
+    assert_eq!(reason, DisconnectReason::ClientQuit);
+    assert_eq!(reason.to_string(), "Client sent QUIT");
+
+    let (_, reason) = disconnect_response(b"PX 1 1 FF0000\n".to_vec()).await;
+    assert_eq!(reason, DisconnectReason::Eof);
+    assert_eq!(reason.to_string(), "Client disconnected");
+}
+
+#[tokio::test]
+async fn blank_lines() {
+    let test = Builder::new()
+        .read(b"\n\n\r\n\nPX 1 1 FF0000\n\n\n\r\n\r\nPX 1 1\r\n\n\n")
+        .write(b"PX 1 1 FF0000\r\n")
+        .read(b"\r\n\r\n\r\n\nSIZE\n\n")
+        .write(b"SIZE 400 800\r\n")
+        .build();
+
+    run(test, None).await;
+}
+
+#[tokio::test]
+async fn command_counters() {
+    let mut input = b"PX 1 1 FF0000\nPX 1 1\nSIZE\nHELP\n".to_vec();
+    input.extend_from_slice(&[b'P', b'B', 3, 0, 3, 0, 0xAB, 0xCD, 0xEF, 0xFF]);
+    input.extend_from_slice(b"\nQUIT\n");
+
+    let (stats, (mut client_rd, mut client_wr)) = spawn_lines(CODEC_OPTS);
+
+    client_wr.write_all(&input).await.unwrap();
+    let mut response = Vec::new();
+    client_rd.read_to_end(&mut response).await.unwrap();
+
+    assert_eq!(stats.commands(CmdKind::Set), 2);
+    assert_eq!(stats.commands(CmdKind::Get), 1);
+    assert_eq!(stats.commands(CmdKind::Size), 1);
+    assert_eq!(stats.commands(CmdKind::Help), 1);
+    assert_eq!(stats.commands(CmdKind::Other), 1);
+}
+
+#[tokio::test]
+async fn connection_counters() {
+    let test = Builder::new()
+        .read(b"PX 1 1 FF0000\r\nPX 2 2 00FF00\r\n")
+        .read(&[b'P', b'B', 3, 0, 3, 0, 0xAB, 0xCD, 0xEF, 0xFF])
+        .read(b"SIZE\r\n")
+        .write(b"SIZE 400 800\r\n")
+        .build();
+
+    let (mut lines, _) = new_lines(test, CODEC_OPTS);
+    Pin::new(&mut lines).await;
+
+    assert_eq!(lines.bytes_read(), 46);
+    assert_eq!(lines.pixels(), 3);
+}
+
+#[tokio::test]
+#[cfg(feature = "prometheus")]
+async fn command_sizes() {
+    let test = Builder::new()
+        .read(b"PX 1 1 FF0000\r\nPX 100 200 00FF00\n")
+        .read(&[b'P', b'B', 3, 0, 3, 0, 0xAB, 0xCD, 0xEF, 0xFF])
+        .read(b"SIZE\r\n")
+        .write(b"SIZE 400 800\r\n")
+        .read(format!("PX 1 1{}\n", " ".repeat(40)).as_bytes())
+        .write(b"PX 1 1 FF0000\r\n")
+        .build();
+
+    let (mut lines, stats) = new_lines(test, CODEC_OPTS);
+    Pin::new(&mut lines).await;
+
+    // Commands of 15, 18, 10, 6 and 47 bytes
+    assert_eq!(stats.command_bytes(), [1, 2, 1, 1, 0, 0, 0, 0, 0]);
+    assert_eq!(stats.command_bytes_sum(), 96);
+}
+
+#[tokio::test]
+async fn readonly() {
+    let codec_opts = Some(CodecOptions {
+        readonly: true,
+        ..CODEC_OPTS
+    });
+
+    let test = Builder::new()
+        .read(b"PX 1 1\r\n")
+        .write(b"PX 1 1 000000\r\n")
+        .read(b"PX 1 1 FF0000\r\n")
+        .write(b"ERR read-only server\r\n")
         .build();
 
     run(test, codec_opts).await;
 }
+
+#[tokio::test]
+async fn response_terminator() {
+    let test = Builder::new()
+        .read(b"SIZE\r\n")
+        .write(b"SIZE 400 800\n")
+        .read(b"PX 1000 0\n")
+        .write(b"ERR x coordinate out of bound\n")
+        .build();
+
+    let opts = CodecOptions {
+        response_terminator: Terminator::Lf,
+        ..CODEC_OPTS
+    };
+    run(test, Some(opts)).await;
+}
+
+/// Process a huge batch of commands, and count the slow polls with the given
+/// threshold.
+async fn slow_polls(threshold: Duration) -> usize {
+    let batch = b"PX 1 1 FF0000\n".repeat(1_000);
+    let test = Builder::new().read(&batch).build();
+
+    let opts = CodecOptions {
+        slow_poll_threshold: Some(threshold),
+        ..CODEC_OPTS
+    };
+
+    let (mut lines, _) = new_lines(test, opts);
+    Pin::new(&mut lines).await;
+    lines.slow_polls()
+}
+
+// Time is paused, so processing takes no time at all
+#[tokio::test(start_paused = true)]
+async fn slow_poll_threshold() {
+    assert!(slow_polls(Duration::ZERO).await > 0);
+    assert_eq!(slow_polls(Duration::from_secs(60)).await, 0);
+}
+
+#[tokio::test]
+async fn lenient_errors() {
+    let test = Builder::new()
+        .read(b"FOO\nPX 1 1 FF0000\nPX 1 1 GGGGGG\n")
+        .write(b"ERR unknown command, use HELP\r\n")
+        .write(b"ERR invalid color value\r\n")
+        .read(b"PX 1 1\n")
+        .write(b"PX 1 1 FF0000\r\n")
+        .build();
+
+    let opts = CodecOptions {
+        error_budget: Some(2),
+        ..CODEC_OPTS
+    };
+    run(test, Some(opts)).await;
+}
+
+#[tokio::test]
+async fn lenient_errors_budget_exceeded() {
+    let opts = CodecOptions {
+        error_budget: Some(2),
+        ..CODEC_OPTS
+    };
+    let (_, (mut client_rd, mut client_wr)) = spawn_lines(opts);
+
+    // The third error exceeds the budget, nothing is processed after it
+    client_wr
+        .write_all(b"FOO\nFOO\nSIZE\nFOO\nSIZE\n")
+        .await
+        .unwrap();
+
+    let mut response = Vec::new();
+    client_rd.read_to_end(&mut response).await.unwrap();
+    assert_eq!(
+        response,
+        b"ERR unknown command, use HELP\r\n\
+          ERR unknown command, use HELP\r\n\
+          SIZE 400 800\r\n\
+          ERR unknown command, use HELP\r\n"
+    );
+}
+
+#[tokio::test]
+async fn quit_summary() {
+    let mut input = b"PX 1 1 FF0000\nPX 2 2 00FF00\n".to_vec();
+    input.extend_from_slice(&[b'P', b'B', 3, 0, 3, 0, 0xAB, 0xCD, 0xEF, 0xFF]);
+    input.extend_from_slice(b"QUIT\nPX 4 4 0000FF\n");
+
+    let opts = CodecOptions {
+        quit_summary: true,
+        ..CODEC_OPTS
+    };
+    let (_, (mut client_rd, mut client_wr)) = spawn_lines(opts);
+
+    client_wr.write_all(&input).await.unwrap();
+
+    // The summary is the last thing sent before disconnecting
+    let mut response = Vec::new();
+    client_rd.read_to_end(&mut response).await.unwrap();
+    assert_eq!(
+        response,
+        format!("STATS px=3 bytes={}\r\n", input.len()).as_bytes()
+    );
+}
+
+#[test]
+fn options_builder() {
+    let opts = CodecOptions::default();
+    assert!(opts.rate_limit.is_none());
+    assert!(opts.cmd_rate_limit.is_none());
+    assert!(opts.allow_binary_cmd);
+    assert!(!opts.readonly);
+    assert_eq!(opts.response_terminator, Terminator::Crlf);
+    assert!(opts.error_budget.is_none());
+    assert!(!opts.quit_summary);
+    assert!(opts.slow_poll_threshold.is_none());
+
+    let opts = CodecOptions::default()
+        .with_cmd_rate_limit(Some(10))
+        .with_binary(false)
+        .with_readonly(true)
+        .with_response_terminator(Terminator::Lf)
+        .with_error_budget(Some(5));
+    assert_eq!(opts.cmd_rate_limit, Some(10));
+    assert!(!opts.allow_binary_cmd);
+    assert!(opts.readonly);
+    assert_eq!(opts.response_terminator, Terminator::Lf);
+    assert_eq!(opts.error_budget, Some(5));
+    assert!(opts.rate_limit.is_none());
+}
+
+#[test]
+fn erase_token() {
+    let token = EraseToken::new("--").unwrap();
+    assert_eq!(token.as_str(), "--");
+    assert_eq!(token.to_string(), "--");
+    assert!(EraseToken::new(&"x".repeat(ERASE_TOKEN_MAX_LENGTH)).is_ok());
+
+    assert!(EraseToken::new("").is_err());
+    assert!(EraseToken::new("a b").is_err());
+    assert!(EraseToken::new(&"x".repeat(ERASE_TOKEN_MAX_LENGTH + 1)).is_err());
+}
+
+#[tokio::test]
+async fn rate_command() {
+    let (_, (mut client_rd, mut client_wr)) = spawn_lines(CODEC_OPTS.with_rate_command(true));
+
+    // Send input over some time, so the rate can be measured
+    for _ in 0..3 {
+        client_wr
+            .write_all(&b"PX 1 1 FF0000\n".repeat(100))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(150)).await;
+    }
+    client_wr.write_all(b"RATE\n").await.unwrap();
+
+    let line = read_line(&mut client_rd).await;
+    let rate: u64 = line
+        .strip_prefix("RATE ")
+        .and_then(|rate| rate.trim_end().parse().ok())
+        .unwrap_or_else(|| panic!("unexpected response {:?}", line));
+    assert!((1..1_000_000).contains(&rate), "rate {}", rate);
+}
@@ -0,0 +1,158 @@
+//! Log received commands to a file, for debugging and replay.
+//!
+//! Each command is written on its own line, prefixed with a Unix timestamp in
+//! milliseconds and the address of the client that sent it:
+//!
+//! ```text
+//! 1697462400123 127.0.0.1:51234 PX 10 20 FF0000
+//! ```
+//!
+//! Binary commands are logged as their textual equivalent, so a log can be
+//! replayed with `--canvas-seed`.
+//!
+//! Every command of every client is logged, which is a lot of disk I/O under
+//! load: a single fast client easily produces tens of megabytes of log each
+//! second. Commands are handed to a single writer thread through a bounded
+//! queue. If the writer can't keep up, commands are dropped rather than
+//! slowing down clients.
+
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc;
+
+use crate::cmd::Cmd;
+
+/// The maximum number of commands queued for writing.
+const QUEUE_SIZE: usize = 64 * 1024;
+
+/// A handle to the command log, cheap to clone for each connection.
+#[derive(Clone)]
+pub struct CommandLog {
+    /// Sends log lines to the writer.
+    tx: mpsc::Sender<String>,
+
+    /// The number of commands dropped because the queue was full.
+    dropped: Arc<AtomicUsize>,
+}
+
+impl CommandLog {
+    /// Open the log file at the given path for appending, and spawn the thread
+    /// writing to it.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (log, rx) = Self::channel();
+
+        let dropped = log.dropped.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = write(rx, BufWriter::new(file), dropped) {
+                eprintln!("Warning: failed to write command log: {}", err);
+            }
+        });
+
+        Ok(log)
+    }
+
+    /// Create a log handle, and the receiver for its lines.
+    fn channel() -> (Self, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let log = CommandLog {
+            tx,
+            dropped: Arc::new(AtomicUsize::new(0)),
+        };
+        (log, rx)
+    }
+
+    /// Log a command received from the given address.
+    ///
+    /// This never blocks, the command is dropped if the queue is full.
+    pub fn log(&self, addr: SocketAddr, cmd: &Cmd) {
+        if let Cmd::None = cmd {
+            return;
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_millis())
+            .unwrap_or(0);
+        if self
+            .tx
+            .try_send(format!("{} {} {}\n", time, addr, cmd))
+            .is_err()
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Write all received log lines, until every log handle is dropped.
+///
+/// The output is flushed each time the queue is drained.
+fn write<W: Write>(
+    mut rx: mpsc::Receiver<String>,
+    mut out: W,
+    dropped: Arc<AtomicUsize>,
+) -> io::Result<()> {
+    while let Some(line) = rx.blocking_recv() {
+        out.write_all(line.as_bytes())?;
+        while let Ok(line) = rx.try_recv() {
+            out.write_all(line.as_bytes())?;
+        }
+        out.flush()?;
+
+        let count = dropped.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            eprintln!(
+                "Warning: command log can't keep up, dropped {} commands",
+                count
+            );
+        }
+    }
+
+    out.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use pixelpwnr_render::Color;
+
+    use super::*;
+
+    #[test]
+    fn log_lines() {
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let (log, rx) = CommandLog::channel();
+        let dropped = log.dropped.clone();
+
+        log.log(addr, &Cmd::SetPixel(1, 2, Color::from_rgb(255, 0, 0)));
+        log.log(addr, &Cmd::SetPixel(3, 4, Color::from_rgba(0, 255, 0, 128)));
+        log.log(addr, &Cmd::None);
+        log.log(addr, &Cmd::GetPixel(1, 2));
+        log.log(addr, &Cmd::BinarySize);
+        drop(log);
+
+        let mut out = Vec::new();
+        write(rx, &mut out, dropped).unwrap();
+
+        // Strip the timestamps
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "127.0.0.1:1234 PX 1 2 FF0000",
+                "127.0.0.1:1234 PX 3 4 00FF0080",
+                "127.0.0.1:1234 PX 1 2",
+                "127.0.0.1:1234 SIZE",
+            ]
+        );
+    }
+}
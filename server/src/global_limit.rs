@@ -0,0 +1,134 @@
+//! A bandwidth limit shared by all connections, divided fairly among them.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use crate::stats::Stats;
+
+/// A token bucket of bytes, refilled at a fixed rate up to its capacity.
+#[derive(Debug)]
+struct Bucket {
+    /// The number of bytes that may currently be read. May briefly be
+    /// negative, when connections read concurrently.
+    tokens: f64,
+
+    /// The last time the bucket was refilled.
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(tokens: f64) -> Self {
+        Self {
+            tokens,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Refill the bucket for the time passed since the last refill, and get
+    /// the number of tokens in it.
+    fn refill(&mut self, bytes_per_sec: f64, capacity: f64) -> f64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * bytes_per_sec).min(capacity);
+        self.last_refill = now;
+        self.tokens
+    }
+}
+
+/// A bandwidth limit for all connections together.
+///
+/// All connections read from a single shared token bucket. So that greedy
+/// connections can't starve others, each connection also has a share of the
+/// limit, the limit divided by the number of connected clients, that it may
+/// not exceed.
+pub struct GlobalLimit {
+    /// The total bandwidth, in bytes per second.
+    bytes_per_sec: f64,
+
+    /// The capacity of the shared bucket, in bytes.
+    burst: f64,
+
+    /// Server stats, for the number of connected clients.
+    stats: Arc<Stats>,
+
+    /// The shared token bucket.
+    bucket: Mutex<Bucket>,
+}
+
+impl GlobalLimit {
+    /// Construct a new limit of `limit` bits per second in total, allowing
+    /// bursts of up to `burst` bits.
+    pub fn new(limit: usize, burst: usize, stats: Arc<Stats>) -> Self {
+        let burst = (burst as f64 / 8.0).max(1.0);
+        Self {
+            bytes_per_sec: (limit as f64 / 8.0).max(1.0),
+            burst,
+            stats,
+            bucket: Mutex::new(Bucket::new(burst)),
+        }
+    }
+
+    /// Get the fair share of the limit of a single connection, as the rate in
+    /// bytes per second and the burst in bytes.
+    fn share(&self) -> (f64, f64) {
+        let clients = self.stats.clients().max(1) as f64;
+        (
+            self.bytes_per_sec / clients,
+            (self.burst / clients).max(1.0),
+        )
+    }
+}
+
+/// The share of a single connection in a `GlobalLimit`.
+pub struct GlobalShare {
+    /// The limit shared with other connections.
+    limit: Arc<GlobalLimit>,
+
+    /// The token bucket of this connection, refilled at its fair share.
+    bucket: Bucket,
+}
+
+impl GlobalShare {
+    /// Construct a new share in the given limit, starting with a full burst.
+    pub fn new(limit: Arc<GlobalLimit>) -> Self {
+        let (_, burst) = limit.share();
+        Self {
+            limit,
+            bucket: Bucket::new(burst),
+        }
+    }
+
+    /// Get the number of bytes this connection may read now, at most `max`.
+    ///
+    /// If nothing may be read, the time to wait before trying again is
+    /// returned instead, long enough for about a tenth of a second worth of
+    /// data.
+    pub fn allowed(&mut self, max: usize) -> Result<usize, Duration> {
+        let (rate, burst) = self.limit.share();
+        let own = self.bucket.refill(rate, burst);
+        let shared = self
+            .limit
+            .bucket
+            .lock()
+            .refill(self.limit.bytes_per_sec, self.limit.burst);
+
+        let allowed = (own.min(shared).max(0.0) as usize).min(max);
+        if allowed > 0 {
+            return Ok(allowed);
+        }
+
+        let wanted = (rate / 10.0).clamp(1.0, max.max(1) as f64);
+        let wait = ((wanted - own) / rate).max((wanted - shared) / self.limit.bytes_per_sec);
+        Err(Duration::from_secs_f64(wait.max(0.000_001)))
+    }
+
+    /// Take the given number of bytes that were read from this connection's
+    /// share and the shared bucket.
+    pub fn consume(&mut self, amount: usize) {
+        self.bucket.tokens -= amount as f64;
+        self.limit.bucket.lock().tokens -= amount as f64;
+    }
+}
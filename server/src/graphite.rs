@@ -0,0 +1,126 @@
+//! Report stats to Graphite over TCP, using its plaintext protocol.
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::stats::Stats;
+
+/// The prefix of all reported metric paths.
+const PREFIX: &str = "pixelpwnr";
+
+/// The delay before the first reconnect attempt, doubled on each failure.
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+
+/// The maximum delay between reconnect attempts.
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Reports stats to a Graphite server.
+pub struct Graphite {
+    /// The address of the Graphite plaintext listener.
+    addr: SocketAddr,
+
+    /// The interval to report at.
+    interval: Duration,
+
+    /// The stats to report.
+    stats: Arc<Stats>,
+}
+
+impl Graphite {
+    /// Construct a new reporter, writing to the Graphite server at `addr` each
+    /// `interval`.
+    pub fn new(addr: SocketAddr, interval: Duration, stats: Arc<Stats>) -> Self {
+        Self {
+            addr,
+            interval,
+            stats,
+        }
+    }
+
+    /// Keep reporting stats, forever.
+    ///
+    /// The connection is reopened when it fails, with an increasing delay
+    /// between failed attempts. Stats of intervals without a connection are
+    /// not sent.
+    pub async fn run(self) {
+        let mut backoff = BACKOFF_MIN;
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            let mut stream = match TcpStream::connect(self.addr).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!(
+                        "Failed to connect to Graphite, retrying in {}s: {}",
+                        backoff.as_secs(),
+                        err
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(BACKOFF_MAX);
+                    continue;
+                }
+            };
+            backoff = BACKOFF_MIN;
+
+            loop {
+                interval.tick().await;
+                let lines = lines(&self.stats, SystemTime::now());
+                if let Err(err) = stream.write_all(lines.as_bytes()).await {
+                    eprintln!("Failed to send stats to Graphite, reconnecting: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Format the current stats as Graphite plaintext lines, timestamped with
+/// `time` in seconds since the Unix epoch.
+///
+/// Rates that can't be determined are skipped.
+fn lines(stats: &Stats, time: SystemTime) -> String {
+    let time = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut lines = String::new();
+    let mut line = |name: &str, value: &dyn std::fmt::Display| {
+        let _ = writeln!(lines, "{}.{} {} {}", PREFIX, name, value, time);
+    };
+
+    line("pixels", &stats.pixels());
+    line("bytes_read", &stats.bytes_read());
+    line("clients", &stats.clients());
+    if let Some(pixels_sec) = stats.pixels_sec() {
+        line("pixels_per_sec", &(pixels_sec as u64));
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn plaintext_lines() {
+        let stats = Stats::new();
+        stats.inc_clients();
+        stats.inc_pixels_by_n(42);
+        stats.inc_bytes_read(1337);
+
+        // The pixel rate is unknown right after starting
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_697_462_400_500);
+        assert_eq!(
+            lines(&stats, time),
+            "pixelpwnr.pixels 42 1697462400\n\
+             pixelpwnr.bytes_read 1337 1697462400\n\
+             pixelpwnr.clients 1 1697462400\n"
+        );
+    }
+}
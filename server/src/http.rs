@@ -0,0 +1,656 @@
+//! A small HTTP server exposing stats and canvas snapshots.
+//!
+//! All routes share a single listener:
+//!
+//! - `/stats`: the current stats as JSON
+//! - `/stats/rates`: the recent per-second rates as JSON, for charting
+//! - `/snapshot.png`: a PNG snapshot of the canvas
+//! - `/stream`: a live MJPEG stream of the canvas
+//! - `/metrics`: stats as Prometheus metrics, with the `prometheus` feature
+//!
+//! If a preview is given, snapshots and streams show the downscaled preview
+//! instead of the full canvas, to save bandwidth.
+//!
+//! - `/px`: set pixels, if enabled, for clients that can't keep a pixelflut
+//!   connection open. `GET /px?x=1&y=2&c=FF0000` sets a single pixel, a
+//!   `POST /px` sets a pixel for each such query on its own line in the body.
+//! - `/ws`: the pixelflut protocol over WebSocket, upgraded connections are
+//!   sent to the pixelflut listener to be served like any other client
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ColorType, ImageEncoder, ImageResult};
+use pixelpwnr_render::{Color, Pixmap, PixmapErr, Preview};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::stats::Stats;
+use crate::websocket;
+
+/// A sender for upgraded WebSocket connections, along with the client address.
+pub type WebSockets = mpsc::UnboundedSender<(DuplexStream, SocketAddr)>;
+
+/// The maximum size of a request head in bytes.
+const REQUEST_MAX_SIZE: usize = 8 * 1024;
+
+/// The maximum size of a request body in bytes.
+const BODY_MAX_SIZE: usize = 64 * 1024;
+
+/// The interval between frames on the MJPEG stream.
+const STREAM_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The JPEG quality used for frames on the MJPEG stream.
+const STREAM_QUALITY: u8 = 75;
+
+/// The multipart boundary used on the MJPEG stream.
+const STREAM_BOUNDARY: &str = "frame";
+
+/// An incoming HTTP request.
+#[derive(Debug, PartialEq)]
+struct Request<'a> {
+    /// The request method, such as `GET`.
+    method: &'a str,
+
+    /// The request path, without query.
+    path: &'a str,
+
+    /// The request query, without leading `?`.
+    query: Option<&'a str>,
+
+    /// The length of the request body, from the `Content-Length` header.
+    content_length: usize,
+
+    /// The protocol to upgrade to, from the `Upgrade` header.
+    upgrade: Option<&'a str>,
+
+    /// The WebSocket key, from the `Sec-WebSocket-Key` header.
+    websocket_key: Option<&'a str>,
+}
+
+impl<'a> Request<'a> {
+    /// Parse a request from the given request head.
+    ///
+    /// Only the request line and the `Content-Length`, `Upgrade` and
+    /// `Sec-WebSocket-Key` headers are parsed, other headers are ignored.
+    fn parse(head: &'a str) -> Option<Self> {
+        let mut lines = head.lines();
+        let line = lines.next()?;
+        let mut parts = line.split(' ');
+        let method = parts.next()?;
+        let target = parts.next()?;
+        if !parts.next()?.starts_with("HTTP/") {
+            return None;
+        }
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+
+        let (mut content_length, mut upgrade, mut websocket_key) = (0, None, None);
+        for header in lines {
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().ok()?;
+                } else if name.eq_ignore_ascii_case("upgrade") {
+                    upgrade = Some(value.trim());
+                } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+                    websocket_key = Some(value.trim());
+                }
+            }
+        }
+
+        Some(Self {
+            method,
+            path,
+            query,
+            content_length,
+            upgrade,
+            websocket_key,
+        })
+    }
+}
+
+/// Create a preview of the canvas downscaled by the given factor, and spawn a
+/// thread updating it at the stream interval.
+pub fn spawn_preview(pixmap: Arc<Pixmap>, scale: usize) -> Arc<Preview> {
+    let (width, height) = pixmap.dimensions();
+    let preview = Arc::new(Preview::new(width, height, scale));
+
+    let updated = preview.clone();
+    std::thread::spawn(move || loop {
+        updated.update(&pixmap);
+        std::thread::sleep(STREAM_INTERVAL);
+    });
+
+    preview
+}
+
+/// Listen for HTTP connections on the given listener, and serve them.
+///
+/// Pixel writes on `/px` are only accepted if `allow_px` is set, and refused
+/// while `paused` is set. Connections upgraded to WebSocket on `/ws` are sent
+/// to `websockets`.
+pub async fn listen(
+    listener: std::net::TcpListener,
+    pixmap: Arc<Pixmap>,
+    stats: Arc<Stats>,
+    allow_px: bool,
+    paused: Arc<AtomicBool>,
+    preview: Option<Arc<Preview>>,
+    websockets: WebSockets,
+) {
+    listener.set_nonblocking(true).unwrap();
+    let listener = TcpListener::from_std(listener).unwrap();
+
+    loop {
+        let (socket, _) = if let Ok(res) = listener.accept().await {
+            res
+        } else {
+            println!("Failed to accept an HTTP connection");
+            continue;
+        };
+
+        let pixmap = pixmap.clone();
+        let stats = stats.clone();
+        let paused = paused.clone();
+        let preview = preview.clone();
+        let websockets = websockets.clone();
+        tokio::spawn(async move {
+            // Errors here are caused by clients going away, ignore them
+            let _ = handle(
+                socket, pixmap, stats, allow_px, &paused, preview, websockets,
+            )
+            .await;
+        });
+    }
+}
+
+/// Handle a single HTTP connection.
+async fn handle(
+    mut socket: TcpStream,
+    pixmap: Arc<Pixmap>,
+    stats: Arc<Stats>,
+    allow_px: bool,
+    paused: &AtomicBool,
+    preview: Option<Arc<Preview>>,
+    websockets: WebSockets,
+) -> io::Result<()> {
+    let (head, body) = read_head(&mut socket).await?;
+    let request = match Request::parse(&head) {
+        Some(request) => request,
+        None => return respond(&mut socket, "400 Bad Request", "text/plain", b"bad request").await,
+    };
+
+    if request.path == "/px" && allow_px {
+        if paused.load(Ordering::Relaxed) {
+            return respond(
+                &mut socket,
+                "503 Service Unavailable",
+                "text/plain",
+                b"writes paused",
+            )
+            .await;
+        }
+
+        let result = match request.method {
+            "GET" => set_pixel(&pixmap, request.query.unwrap_or("")).map(usize::from),
+            "POST" if request.content_length > BODY_MAX_SIZE => {
+                return respond(
+                    &mut socket,
+                    "413 Payload Too Large",
+                    "text/plain",
+                    b"request body too large",
+                )
+                .await
+            }
+            "POST" => {
+                let body = read_body(&mut socket, body, request.content_length).await?;
+                set_pixels(&pixmap, &body)
+            }
+            _ => {
+                return respond(
+                    &mut socket,
+                    "405 Method Not Allowed",
+                    "text/plain",
+                    b"method not allowed",
+                )
+                .await
+            }
+        };
+        return match result {
+            Ok(pixels) => {
+                stats.inc_pixels_by_n(pixels);
+                respond(&mut socket, "204 No Content", "text/plain", b"").await
+            }
+            Err(err) => respond(&mut socket, "400 Bad Request", "text/plain", err.as_bytes()).await,
+        };
+    }
+
+    if request.method != "GET" {
+        return respond(
+            &mut socket,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"method not allowed",
+        )
+        .await;
+    }
+
+    match request.path {
+        "/stats" => {
+            let body = serde_json::to_vec(&stats.snapshot())?;
+            respond(&mut socket, "200 OK", "application/json", &body).await
+        }
+        "/stats/rates" => {
+            let body = serde_json::to_vec(&stats.recent_rates())?;
+            respond(&mut socket, "200 OK", "application/json", &body).await
+        }
+        "/snapshot.png" => {
+            let png = tokio::task::spawn_blocking(move || {
+                encode_canvas(&pixmap, preview.as_deref(), encode_png)
+            })
+            .await?
+            .map_err(io::Error::other)?;
+            respond(&mut socket, "200 OK", "image/png", &png).await
+        }
+        "/stream" => stream(socket, pixmap, preview).await,
+        #[cfg(feature = "prometheus")]
+        "/metrics" => {
+            let body = crate::prometheus::render(&stats);
+            respond(
+                &mut socket,
+                "200 OK",
+                "text/plain; version=0.0.4",
+                body.as_bytes(),
+            )
+            .await
+        }
+        "/ws" => match (request.upgrade, request.websocket_key) {
+            (Some(upgrade), Some(key)) if upgrade.eq_ignore_ascii_case("websocket") => {
+                let head = format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    websocket::accept_key(key),
+                );
+                socket.write_all(head.as_bytes()).await?;
+
+                let addr = socket.peer_addr()?;
+                let _ = websockets.send((websocket::bridge(socket, body), addr));
+                Ok(())
+            }
+            _ => {
+                respond(
+                    &mut socket,
+                    "426 Upgrade Required",
+                    "text/plain",
+                    b"websocket upgrade required",
+                )
+                .await
+            }
+        },
+        _ => respond(&mut socket, "404 Not Found", "text/plain", b"not found").await,
+    }
+}
+
+/// Read the request head from the given socket, up to the blank line.
+///
+/// Returns the head, and the part of the body that was read along with it.
+async fn read_head(socket: &mut TcpStream) -> io::Result<(String, Vec<u8>)> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+
+    loop {
+        let read = socket.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let body = buf.split_off(end + 4);
+            buf.truncate(end);
+            let head = String::from_utf8(buf)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            return Ok((head, body));
+        }
+        if buf.len() > REQUEST_MAX_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+    }
+}
+
+/// Read the rest of a request body of the given length from the socket.
+async fn read_body(socket: &mut TcpStream, mut body: Vec<u8>, len: usize) -> io::Result<String> {
+    let read = body.len().min(len);
+    body.resize(len, 0);
+    socket.read_exact(&mut body[read..]).await?;
+
+    String::from_utf8(body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Set a pixel from a query, such as `x=1&y=2&c=FF0000`.
+///
+/// Returns whether the pixel was written, false if dropped within the
+/// cooldown.
+fn set_pixel(pixmap: &Pixmap, query: &str) -> Result<bool, String> {
+    let (mut x, mut y, mut color) = (None, None, None);
+    for pair in query.split('&') {
+        match pair.split_once('=') {
+            Some(("x", value)) => {
+                x = Some(value.parse::<usize>().map_err(|_| "invalid x coordinate")?)
+            }
+            Some(("y", value)) => {
+                y = Some(value.parse::<usize>().map_err(|_| "invalid y coordinate")?)
+            }
+            Some(("c", value)) => {
+                // Allow a single leading URL encoded hash, as in `%23RRGGBB`
+                let value = value.strip_prefix("%23").unwrap_or(value);
+                color =
+                    Some(Color::from_hex_raw(value.as_bytes()).map_err(|_| "invalid color value")?);
+            }
+            _ => {}
+        }
+    }
+
+    let x = x.ok_or("missing x coordinate")?;
+    let y = y.ok_or("missing y coordinate")?;
+    let color = color.ok_or("missing color")?;
+    pixmap
+        .set_pixel(x, y, color)
+        .map_err(|PixmapErr::OutOfBound(err)| err.to_string())
+}
+
+/// Set a pixel for each query line in the given body, and return the number
+/// of pixels set.
+///
+/// Lines before an invalid line are still applied.
+fn set_pixels(pixmap: &Pixmap, body: &str) -> Result<usize, String> {
+    let mut pixels = 0;
+    for (number, line) in body.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        if set_pixel(pixmap, line).map_err(|err| format!("line {}: {}", number + 1, err))? {
+            pixels += 1;
+        }
+    }
+    Ok(pixels)
+}
+
+/// Write a complete response with the given status and body, and close.
+async fn respond(
+    socket: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len(),
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body).await?;
+    socket.shutdown().await
+}
+
+/// Stream the canvas as MJPEG until the client disconnects.
+async fn stream(
+    mut socket: TcpStream,
+    pixmap: Arc<Pixmap>,
+    preview: Option<Arc<Preview>>,
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        STREAM_BOUNDARY,
+    );
+    socket.write_all(head.as_bytes()).await?;
+
+    let mut interval = tokio::time::interval(STREAM_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let pixmap = pixmap.clone();
+        let preview = preview.clone();
+        let jpeg = tokio::task::spawn_blocking(move || {
+            encode_canvas(&pixmap, preview.as_deref(), encode_jpeg)
+        })
+        .await?
+        .map_err(io::Error::other)?;
+
+        let part = format!(
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            STREAM_BOUNDARY,
+            jpeg.len(),
+        );
+        socket.write_all(part.as_bytes()).await?;
+        socket.write_all(&jpeg).await?;
+        socket.write_all(b"\r\n").await?;
+    }
+}
+
+/// Encode the current canvas with the given encoder, or its preview if given.
+fn encode_canvas(
+    pixmap: &Pixmap,
+    preview: Option<&Preview>,
+    encode: fn(&[u8], usize, usize) -> ImageResult<Vec<u8>>,
+) -> ImageResult<Vec<u8>> {
+    match preview {
+        Some(preview) => {
+            let (width, height) = preview.dimensions();
+            encode(&preview.to_bytes(), width, height)
+        }
+        None => {
+            let (width, height) = pixmap.dimensions();
+            encode(&crate::canvas_bytes(pixmap), width, height)
+        }
+    }
+}
+
+/// Encode the given RGBA bytes as PNG.
+fn encode_png(rgba: &[u8], width: usize, height: usize) -> ImageResult<Vec<u8>> {
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png).write_image(rgba, width as u32, height as u32, ColorType::Rgba8)?;
+    Ok(png)
+}
+
+/// Encode the given RGBA bytes as JPEG, dropping the alpha channel.
+fn encode_jpeg(rgba: &[u8], width: usize, height: usize) -> ImageResult<Vec<u8>> {
+    let rgb: Vec<u8> = rgba
+        .chunks_exact(4)
+        .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, STREAM_QUALITY).encode(
+        &rgb,
+        width as u32,
+        height as u32,
+        ColorType::Rgb8,
+    )?;
+    Ok(jpeg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        let request = Request::parse("GET /stats?pretty HTTP/1.1\r\nHost: localhost").unwrap();
+        assert_eq!(
+            request,
+            Request {
+                method: "GET",
+                path: "/stats",
+                query: Some("pretty"),
+                content_length: 0,
+                upgrade: None,
+                websocket_key: None,
+            }
+        );
+
+        let request = Request::parse("POST /px HTTP/1.1\r\ncontent-length: 42").unwrap();
+        assert_eq!(request.content_length, 42);
+
+        assert!(Request::parse("GET /stats").is_none());
+        assert!(Request::parse("").is_none());
+    }
+
+    #[test]
+    fn set_pixel_query() {
+        let pixmap = Pixmap::new(4, 4);
+        set_pixel(&pixmap, "x=1&y=2&c=FF0000").unwrap();
+        set_pixel(&pixmap, "c=%2300FF00&y=3&x=3").unwrap();
+        assert_eq!(pixmap.pixel(1, 2).unwrap().hex(), "FF0000");
+        assert_eq!(pixmap.pixel(3, 3).unwrap().hex(), "00FF00");
+
+        assert!(set_pixel(&pixmap, "x=1&y=2").is_err());
+        assert!(set_pixel(&pixmap, "x=1&y=2&c=nope").is_err());
+        assert!(set_pixel(&pixmap, "x=4&y=0&c=FF0000").is_err());
+    }
+
+    #[test]
+    fn set_pixels_body() {
+        let pixmap = Pixmap::new(4, 4);
+        assert_eq!(
+            set_pixels(&pixmap, "x=0&y=0&c=FF0000\n\nx=1&y=0&c=00FF00\n"),
+            Ok(2)
+        );
+        assert_eq!(pixmap.pixel(1, 0).unwrap().hex(), "00FF00");
+
+        assert_eq!(
+            set_pixels(&pixmap, "x=2&y=0&c=0000FF\nx=9&y=0&c=0000FF"),
+            Err("line 2: x coordinate out of bound".to_string())
+        );
+        assert_eq!(pixmap.pixel(2, 0).unwrap().hex(), "0000FF");
+    }
+
+    #[tokio::test]
+    async fn get_pixel_request() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pixmap = Arc::new(Pixmap::new(4, 4));
+        let stats = Arc::new(Stats::new());
+        let paused = Arc::new(AtomicBool::new(true));
+        let (websockets, _) = mpsc::unbounded_channel();
+        tokio::spawn(listen(
+            listener,
+            pixmap.clone(),
+            stats,
+            true,
+            paused.clone(),
+            None,
+            websockets,
+        ));
+        let request = || async {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            socket
+                .write_all(b"GET /px?x=1&y=2&c=FF0000 HTTP/1.1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            socket.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        // Writes are refused while paused
+        assert!(request()
+            .await
+            .starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(pixmap.pixel(1, 2).unwrap(), Color::black());
+
+        paused.store(false, Ordering::Relaxed);
+        assert!(request().await.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert_eq!(pixmap.pixel(1, 2).unwrap().hex(), "FF0000");
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pixmap = Arc::new(Pixmap::new(4, 4));
+        let stats = Arc::new(Stats::new());
+        let (websockets, mut upgraded) = mpsc::unbounded_channel();
+        tokio::spawn(listen(
+            listener,
+            pixmap,
+            stats,
+            false,
+            Default::default(),
+            None,
+            websockets,
+        ));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n")
+            .await
+            .unwrap();
+        let (head, _) = read_head(&mut socket).await.unwrap();
+        assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(head
+            .lines()
+            .any(|line| line == "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        // A masked text frame, its payload reaches the upgraded connection
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x81, 0x80 | 5];
+        frame.extend_from_slice(&mask);
+        frame.extend(b"SIZE\n".iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        socket.write_all(&frame).await.unwrap();
+
+        let (mut stream, _) = upgraded.recv().await.unwrap();
+        let mut payload = [0; 5];
+        stream.read_exact(&mut payload).await.unwrap();
+        assert_eq!(&payload, b"SIZE\n");
+
+        // Responses are sent back in a binary frame
+        stream.write_all(b"SIZE 4 4\r\n").await.unwrap();
+        let mut response = [0; 12];
+        socket.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response[..2], [0x82, 10]);
+        assert_eq!(&response[2..], b"SIZE 4 4\r\n");
+
+        // Closing the connection closes the WebSocket
+        drop(stream);
+        let mut close = [0; 4];
+        socket.read_exact(&mut close).await.unwrap();
+        assert_eq!(close, [0x88, 2, 0x03, 0xE8]);
+    }
+
+    #[tokio::test]
+    async fn websocket_upgrade_required() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pixmap = Arc::new(Pixmap::new(4, 4));
+        let stats = Arc::new(Stats::new());
+        let (websockets, _) = mpsc::unbounded_channel();
+        tokio::spawn(listen(
+            listener,
+            pixmap,
+            stats,
+            false,
+            Default::default(),
+            None,
+            websockets,
+        ));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(b"GET /ws HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+    }
+}
@@ -0,0 +1,65 @@
+//! Inherit an already bound listener through a file descriptor, for socket
+//! activation such as with systemd.
+
+use std::io;
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// Take ownership of the listening TCP socket behind the given file descriptor.
+///
+/// Fails if the descriptor is not a socket in the listening state.
+pub fn listener(fd: RawFd) -> io::Result<TcpListener> {
+    // Make sure the descriptor is a socket accepting connections
+    let mut accepting: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            &mut accepting as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if accepting == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "file descriptor is not a listening socket",
+        ));
+    }
+
+    // The descriptor is a listening socket, the listener now owns it
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    // Only TCP sockets have an internet address
+    listener.local_addr()?;
+
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+    use super::*;
+
+    #[test]
+    fn inherit_listener() {
+        let original = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = original.local_addr().unwrap();
+
+        let listener = listener(original.into_raw_fd()).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn reject_non_listening() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(listener(socket.as_raw_fd()).is_err());
+    }
+}
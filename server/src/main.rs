@@ -1,29 +1,59 @@
 mod args;
 mod cmd;
 mod codec;
+mod command_log;
+mod global_limit;
+#[cfg(feature = "graphite")]
+mod graphite;
+mod http;
+#[cfg(unix)]
+mod listen_fd;
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod socket_buffers;
 mod stat_monitor;
 mod stat_reporter;
 mod stats;
+#[cfg(unix)]
+mod stats_socket;
+#[cfg(feature = "statsd")]
+mod statsd;
+mod synths;
+mod test_pattern;
+mod websocket;
 
 use std::{
-    path::PathBuf,
+    borrow::Cow,
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    net::SocketAddr,
+    path::{Path, PathBuf},
     pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use clap::StructOpt;
-use pixelpwnr_render::{Pixmap, Renderer};
-use tokio::net::{TcpListener, TcpStream};
+use pixelpwnr_render::{Color, Pixmap, PixmapErr, RenderError, Renderer};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch, Notify};
 
+use cmd::Cmd;
 use codec::{CodecOptions, Lines};
+use command_log::CommandLog;
+use global_limit::GlobalLimit;
+use socket2::SockRef;
+use socket_buffers::SocketBuffers;
 use stat_reporter::StatReporter;
 use stats::{Stats, StatsRaw};
 
-use crate::args::Opts;
+use crate::args::{Opts, RenderBackend, SyntheticMode};
 
 // TODO: use some constant for new lines
 
@@ -38,12 +68,60 @@ fn main() {
         .map(|s| Stats::from_raw(&s))
         .unwrap_or(Stats::new());
 
-    let stats = Arc::new(stats);
+    let stats = Arc::new(
+        stats
+            .with_unique_ip_window(arg_handler.unique_ip_window())
+            .with_rate_unit(arg_handler.rate_unit)
+            .with_scores(arg_handler.scoreboard),
+    );
 
     let (width, height) = arg_handler.size();
-    let pixmap = Arc::new(Pixmap::new(width, height));
+    let pixmap = match arg_handler.canvas_pattern() {
+        Some(pattern) => Pixmap::new_with_pattern(width, height, pattern),
+        None => Pixmap::new(width, height),
+    };
+    let pixmap = pixmap
+        .with_gamma_correct(arg_handler.gamma_correct)
+        .with_no_alpha(arg_handler.no_alpha)
+        .with_write_mode(arg_handler.write_mode())
+        .with_wrap(arg_handler.wrap);
     println!("Canvas size: {}x{}", width, height);
 
+    // Draw the test pattern on the canvas
+    if let Some(pattern) = arg_handler.test_pattern {
+        test_pattern::draw(&pixmap, pattern);
+    }
+
+    // Draw the seed commands on the canvas
+    if let Some(path) = &arg_handler.canvas_seed {
+        if let Err(err) = seed_canvas(path, &pixmap) {
+            panic!("Failed to read canvas seed {:?}. Error: {:?}", path, err);
+        }
+    }
+
+    // Load the canvas from raw bytes on stdin
+    if arg_handler.canvas_stdin {
+        if let Err(err) = load_raw_canvas(std::io::stdin().lock(), &pixmap) {
+            panic!("Failed to load canvas from stdin. Error: {}", err);
+        }
+    }
+
+    // Only start the cooldown once the canvas is prepared
+    let pixmap = Arc::new(pixmap.with_cooldown(
+        arg_handler.pixel_cooldown(),
+        arg_handler.pixel_cooldown_region,
+    ));
+
+    // Fade the canvas towards black over time
+    if let Some(factor) = arg_handler.decay {
+        let pixmap = pixmap.clone();
+        let interval = Duration::from_millis(arg_handler.decay_interval);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            pixmap.decay(factor);
+        });
+    }
+
     // Create a new runtime to be ran on a different (set of) OS threads
     // so that we don't block the runtime by running the renderer on it
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -51,71 +129,454 @@ fn main() {
         .build()
         .unwrap();
 
-    if let Some(dir) = arg_handler.save_dir.clone() {
+    // Build the renderer, unless we shouldn't render, or can't
+    let renderer = match arg_handler.render_backend() {
+        RenderBackend::None => None,
+        RenderBackend::Gfx => match Renderer::try_new(env!("CARGO_PKG_NAME"), pixmap.clone()) {
+            Ok(renderer) => Some(renderer),
+            Err(err) => {
+                warn_no_render(&err);
+                None
+            }
+        },
+    };
+
+    // Define host to render
+    let stats_host = arg_handler.stats_host.unwrap_or(arg_handler.host);
+
+    // Create a stats reporter, and start reporting
+    let reporter = Arc::new(
+        StatReporter::new(
+            arg_handler.stats_screen_interval(),
+            arg_handler.stats_stdout_interval(),
+            arg_handler.stats_save_interval(),
+            arg_handler.stats_file.clone(),
+            stats.clone(),
+            renderer.as_ref().map(|renderer| renderer.stats().text()),
+            stats_host.ip().to_string(),
+            stats_host.port(),
+        )
+        .with_csv_report(
+            arg_handler.pps_report_interval(),
+            arg_handler.pps_report_file.clone(),
+        )
+        .with_stdout_json(arg_handler.stdout_json)
+        .with_scoreboard(
+            renderer
+                .as_ref()
+                .filter(|_| arg_handler.scoreboard)
+                .map(|renderer| renderer.scoreboard().text()),
+        ),
+    );
+    reporter.start();
+
+    // Shut down when interrupted, persistent stats are saved once stopped
+    let net_running = Arc::new(AtomicBool::new(true));
+    let shutdown = Arc::new(Notify::new());
+    let save_on_exit = arg_handler.stats_save_on_exit && arg_handler.stats_file.is_some();
+    if save_on_exit {
+        let (net_running, shutdown) = (net_running.clone(), shutdown.clone());
+        runtime.spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                net_running.store(false, Ordering::Relaxed);
+                shutdown.notify_one();
+            }
+        });
+    }
+
+    // Pause and resume pixel writes on SIGUSR2, clients stay connected. Not
+    // available on other platforms.
+    let paused = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        let paused = paused.clone();
+        runtime.spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut signals = match signal(SignalKind::user_defined2()) {
+                Ok(signals) => signals,
+                Err(err) => {
+                    eprintln!("Warning: failed to listen for SIGUSR2: {}", err);
+                    return;
+                }
+            };
+            while signals.recv().await.is_some() {
+                if paused.fetch_xor(true, Ordering::Relaxed) {
+                    println!("Resumed pixel writes");
+                } else {
+                    println!("Paused pixel writes");
+                }
+            }
+        });
+    }
+
+    // Replace the canvas with an image on SIGHUP
+    if let Some(path) = arg_handler.swap_image.clone() {
+        #[cfg(unix)]
+        {
+            let pixmap = pixmap.clone();
+            runtime.spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let mut signals = match signal(SignalKind::hangup()) {
+                    Ok(signals) => signals,
+                    Err(err) => {
+                        eprintln!("Warning: failed to listen for SIGHUP: {}", err);
+                        return;
+                    }
+                };
+                while signals.recv().await.is_some() {
+                    match swap_image(&path, &pixmap) {
+                        Ok(()) => println!("Replaced canvas with {:?}", path),
+                        Err(err) => eprintln!("Failed to load image {:?}: {}", path, err),
+                    }
+                }
+            });
+        }
+
+        #[cfg(not(unix))]
+        panic!(
+            "Failed to swap the canvas to {:?}, signals are only supported on Unix",
+            path
+        );
+    }
+
+    if let Some(mut dir) = arg_handler.save_dir.clone() {
+        if arg_handler.save_session_dirs {
+            dir = session_dir(&dir, SystemTime::now()).unwrap();
+        }
         let pixmap = pixmap.clone();
         runtime.spawn(spawn_save_image(
             dir,
             pixmap,
             Duration::from_secs(arg_handler.save_interval),
+            arg_handler.save_keep,
         ));
     }
 
-    let net_running = Arc::new(AtomicBool::new(true));
+    // WebSocket connections upgraded by the HTTP listener, served along with
+    // the pixelflut connections
+    let (websockets, websockets_rx) = mpsc::unbounded_channel();
+
+    // Serve stats and snapshots over HTTP
+    if let Some(http_host) = arg_handler.http {
+        let listener = match std::net::TcpListener::bind(http_host) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to bind to address {:?}. Error: {:?}", &http_host, e),
+        };
+        println!("Serving HTTP on: {}", http_host);
+        let preview = arg_handler
+            .preview_scale
+            .map(|scale| http::spawn_preview(pixmap.clone(), scale));
+        runtime.spawn(http::listen(
+            listener,
+            pixmap.clone(),
+            stats.clone(),
+            arg_handler.http_px,
+            paused.clone(),
+            preview,
+            websockets,
+        ));
+    }
+
+    // Report stats to StatsD
+    if let Some(addr) = arg_handler.statsd {
+        #[cfg(feature = "statsd")]
+        {
+            println!("Reporting stats to StatsD at: {}", addr);
+            let interval = Duration::from_secs(arg_handler.statsd_interval);
+            runtime.spawn(statsd::StatsD::new(addr, interval, stats.clone()).run());
+        }
+
+        #[cfg(not(feature = "statsd"))]
+        panic!(
+            "Failed to report to StatsD at {}, compiled without the statsd feature",
+            addr
+        );
+    }
+
+    // Report stats to Graphite
+    if let Some(addr) = arg_handler.graphite {
+        #[cfg(feature = "graphite")]
+        {
+            println!("Reporting stats to Graphite at: {}", addr);
+            let interval = Duration::from_secs(arg_handler.graphite_interval);
+            runtime.spawn(graphite::Graphite::new(addr, interval, stats.clone()).run());
+        }
+
+        #[cfg(not(feature = "graphite"))]
+        panic!(
+            "Failed to report to Graphite at {}, compiled without the graphite feature",
+            addr
+        );
+    }
+
+    // Serve stats on a Unix domain socket
+    if let Some(path) = &arg_handler.stats_unix {
+        #[cfg(unix)]
+        {
+            let listener = {
+                let _runtime = runtime.enter();
+                match stats_socket::bind(path) {
+                    Ok(v) => v,
+                    Err(e) => panic!("Failed to bind to socket {:?}. Error: {:?}", path, e),
+                }
+            };
+            println!("Serving stats on: {}", path.display());
+            runtime.spawn(stats_socket::listen(listener, stats.clone()));
+        }
+
+        #[cfg(not(unix))]
+        panic!(
+            "Failed to bind to socket {:?}, only supported on Unix",
+            path
+        );
+    }
+
+    // Mirror the canvas into a memory-mapped file
+    if let Some(path) = &arg_handler.mmap {
+        #[cfg(feature = "mmap")]
+        {
+            let mirror = match mmap::Mirror::create(path, pixmap.clone()) {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to map file {:?}. Error: {:?}", path, e),
+            };
+            println!("Mirroring canvas to: {}", path.display());
+            std::thread::spawn(move || mirror.run());
+        }
+
+        #[cfg(not(feature = "mmap"))]
+        panic!(
+            "Failed to map file {:?}, compiled without the mmap feature",
+            path
+        );
+    }
+
+    // The canvas can't be resized at runtime yet, but keep the sender around so
+    // clients can stay subscribed to size changes
+    let (_size_tx, size_rx) = watch::channel(pixmap.dimensions());
 
     // Create a std threa first. Tokio's [`TcpStream::listen`] automatically sets
     // SO_REUSEADDR which means that it won't return an error if another program is
     // already listening on our port/address. Weird.
     let host = arg_handler.host;
-    let listener = match std::net::TcpListener::bind(host) {
-        Ok(v) => v,
-        Err(e) => panic!("Failed to bind to address {:?}. Error: {:?}", &host, e),
+    let listener = match arg_handler.listen_fd {
+        Some(fd) => {
+            #[cfg(unix)]
+            let listener = match listen_fd::listener(fd) {
+                Ok(v) => v,
+                Err(e) => panic!("Failed to inherit listener from fd {}. Error: {:?}", fd, e),
+            };
+
+            #[cfg(not(unix))]
+            let listener = panic!(
+                "Failed to inherit listener from fd {}, only supported on Unix",
+                fd
+            );
+
+            listener
+        }
+        None => match std::net::TcpListener::bind(host) {
+            Ok(v) => v,
+            Err(e) => panic!("Failed to bind to address {:?}. Error: {:?}", &host, e),
+        },
     };
-    println!("Listening on: {}", host);
+    println!("Listening on: {}", listener.local_addr().unwrap_or(host));
+
+    // Tune the socket buffers, accepted sockets may inherit them
+    let buffers = arg_handler.socket_buffers();
+    buffers.apply(SockRef::from(&listener));
 
     let net_pixmap = pixmap.clone();
     let net_stats = stats.clone();
     let net_running_2 = net_running.clone();
-    let opts = arg_handler.clone().into();
+    let opts: CodecOptions = arg_handler.clone().into();
+    let log_connections = arg_handler.log_connections;
+
+    // Divide a bandwidth limit among all clients
+    let global_limit = arg_handler.global_bw_limit.map(|bps| {
+        let burst = arg_handler.global_bw_limit_burst.unwrap_or(bps);
+        Arc::new(GlobalLimit::new(bps, burst, stats.clone()))
+    });
+
+    // Log received commands to a file
+    let command_log = arg_handler
+        .command_log
+        .as_ref()
+        .map(|path| match CommandLog::open(path) {
+            Ok(log) => log,
+            Err(e) => panic!("Failed to open command log {:?}. Error: {:?}", path, e),
+        });
+
+    // Spawn a synthetic client, for testing
+    match arg_handler.synthetic {
+        SyntheticMode::Off => {}
+        SyntheticMode::Tokio => {
+            println!("Running synthetic tokio client");
+            runtime.spawn(synths::tokio_synthetic_client(
+                pixmap.clone(),
+                stats.clone(),
+                opts,
+                size_rx.clone(),
+            ));
+        }
+        SyntheticMode::Sync => {
+            println!("Running synthetic sync client");
+            let (pixmap, stats) = (pixmap.clone(), stats.clone());
+            std::thread::spawn(move || synths::sync_synthetic_client(pixmap, stats));
+        }
+    }
+
     let tokio_runtime = std::thread::spawn(move || {
         runtime.block_on(async move {
-            listen(listener, net_pixmap, net_stats, opts).await;
+            tokio::select! {
+                _ = listen(
+                    listener,
+                    websockets_rx,
+                    net_pixmap,
+                    net_stats,
+                    opts,
+                    size_rx,
+                    log_connections,
+                    command_log,
+                    paused,
+                    buffers,
+                    global_limit,
+                ) => {}
+                _ = shutdown.notified() => {}
+            }
             net_running_2.store(false, Ordering::Relaxed);
-        })
+        });
+
+        // Don't wait for blocking tasks still running, such as commands that
+        // timed out
+        runtime.shutdown_background();
     });
 
-    if !arg_handler.no_render {
-        render(&arg_handler, pixmap, stats, net_running);
-    } else {
-        tokio_runtime.join().unwrap()
+    match renderer {
+        Some(renderer) => {
+            // Keep serving without rendering if the renderer fails to start
+            if let Err(err) = render(&arg_handler, renderer, net_running) {
+                warn_no_render(&err);
+                tokio_runtime.join().unwrap();
+            }
+        }
+        None => tokio_runtime.join().unwrap(),
+    }
+
+    // Save persistent stats one final time
+    if save_on_exit {
+        reporter.save_on_exit();
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn listen(
     listener: std::net::TcpListener,
+    mut websockets: mpsc::UnboundedReceiver<(DuplexStream, SocketAddr)>,
     pixmap: Arc<Pixmap>,
     stats: Arc<Stats>,
     opts: CodecOptions,
+    size: watch::Receiver<(usize, usize)>,
+    log_connections: bool,
+    command_log: Option<CommandLog>,
+    paused: Arc<AtomicBool>,
+    buffers: SocketBuffers,
+    global_limit: Option<Arc<GlobalLimit>>,
 ) {
     let listener = TcpListener::from_std(listener).unwrap();
 
     loop {
         let pixmap_worker = pixmap.clone();
         let stats_worker = stats.clone();
-        let (socket, _) = if let Ok(res) = listener.accept().await {
-            res
-        } else {
-            println!("Failed to accept a connection");
-            continue;
-        };
-        handle_socket(socket, pixmap_worker, stats_worker, opts);
+        tokio::select! {
+            accepted = listener.accept() => {
+                let socket = if let Ok((socket, _)) = accepted {
+                    socket
+                } else {
+                    println!("Failed to accept a connection");
+                    continue;
+                };
+                buffers.apply(SockRef::from(&socket));
+
+                // A client connected, ensure we're able to get it's address
+                let addr = match socket.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(err) => {
+                        eprintln!("Failed to get remote address: {}", err);
+                        continue;
+                    }
+                };
+                handle_socket(
+                    socket,
+                    addr,
+                    pixmap_worker,
+                    stats_worker,
+                    opts,
+                    size.clone(),
+                    log_connections,
+                    command_log.clone(),
+                    paused.clone(),
+                    global_limit.clone(),
+                );
+            }
+            Some((socket, addr)) = websockets.recv() => {
+                handle_socket(
+                    socket,
+                    addr,
+                    pixmap_worker,
+                    stats_worker,
+                    opts,
+                    size.clone(),
+                    log_connections,
+                    command_log.clone(),
+                    paused.clone(),
+                    global_limit.clone(),
+                );
+            }
+        }
     }
 }
 
+/// Get the current canvas as RGBA image buffer.
+///
+/// With the `parallel` feature, the canvas is copied on multiple threads.
+fn canvas_bytes(pixmap: &Pixmap) -> Cow<[u8]> {
+    #[cfg(feature = "parallel")]
+    return Cow::Owned(pixmap.par_as_image());
+
+    #[cfg(not(feature = "parallel"))]
+    Cow::Borrowed(pixmap.as_bytes())
+}
+
 /// Save the current canvas at the current interval
-async fn spawn_save_image(dir: PathBuf, pixmap: Arc<Pixmap>, interval: Duration) {
+///
+/// If `keep` is set, only the given number of most recent images is kept.
+async fn spawn_save_image(
+    dir: PathBuf,
+    pixmap: Arc<Pixmap>,
+    interval: Duration,
+    keep: Option<usize>,
+) {
     std::fs::create_dir_all(&dir).unwrap();
 
+    // Hash of the last saved canvas, to skip saving identical images
+    let mut last_hash = None;
+
     loop {
+        // Hashing goes over the whole canvas, don't block the runtime with it
+        let hash = {
+            let pixmap = pixmap.clone();
+            tokio::task::spawn_blocking(move || pixmap.content_hash())
+                .await
+                .unwrap()
+        };
+        if last_hash == Some(hash) {
+            tokio::time::sleep(interval).await;
+            continue;
+        }
+        last_hash = Some(hash);
+
         let now = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
@@ -126,40 +587,224 @@ async fn spawn_save_image(dir: PathBuf, pixmap: Arc<Pixmap>, interval: Duration)
 
         let (width, height) = pixmap.dimensions();
 
-        let mut pixmap = (*pixmap).clone();
-
         image::save_buffer(
             path,
-            pixmap.as_bytes(),
+            &canvas_bytes(&pixmap),
             width as u32,
             height as u32,
             image::ColorType::Rgba8,
         )
         .unwrap();
 
+        if let Some(keep) = keep {
+            if let Err(err) = remove_old_images(&dir, keep) {
+                eprintln!("Failed to remove old saved images: {}", err);
+            }
+        }
+
         tokio::time::sleep(interval).await;
     }
 }
 
-/// Spawn a new task with the given socket
-fn handle_socket(
-    mut socket: TcpStream,
+/// Create a directory for the images of this server session under `dir`,
+/// named after the `launch` time in seconds.
+///
+/// If a session launched in the same second already claimed that name, a
+/// numbered suffix is added.
+fn session_dir(dir: &Path, launch: SystemTime) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+
+    let launch = launch
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let mut suffix = 0;
+    loop {
+        let path = match suffix {
+            0 => dir.join(launch.to_string()),
+            n => dir.join(format!("{}-{}", launch, n)),
+        };
+        match std::fs::create_dir(&path) {
+            Ok(()) => return Ok(path),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => suffix += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Remove all but the `keep` most recent saved images in the given directory.
+///
+/// Only files named like the images we save, `<timestamp>.png`, are removed.
+fn remove_old_images(dir: &Path, keep: usize) -> std::io::Result<()> {
+    let mut images = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some("png".as_ref()) {
+            continue;
+        }
+        if let Some(time) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u64>().ok())
+        {
+            images.push((time, path));
+        }
+    }
+
+    // Remove the oldest images beyond the amount to keep
+    images.sort_unstable();
+    let remove = images.len().saturating_sub(keep);
+    for (_, path) in images.into_iter().take(remove) {
+        std::fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+/// Replace the canvas with the image at `path`.
+///
+/// Pixels are stored as is, without blending. An image of other dimensions is
+/// centered, it is cropped where larger than the canvas, and the canvas
+/// background is left around it where smaller.
+fn swap_image(path: &Path, pixmap: &Pixmap) -> image::ImageResult<()> {
+    let image = image::open(path)?.into_rgba8();
+
+    let (width, height) = pixmap.dimensions();
+    let (image_width, image_height) = (image.width() as usize, image.height() as usize);
+    if (image_width, image_height) != (width, height) {
+        println!(
+            "Note: image is {}x{}, centering it on the {}x{} canvas",
+            image_width, image_height, width, height
+        );
+    }
+
+    // Offset of the image on the canvas, negative where it is cropped
+    let offset_x = (width as isize - image_width as isize) / 2;
+    let offset_y = (height as isize - image_height as isize) / 2;
+
+    for y in 0..height {
+        for x in 0..width {
+            let (image_x, image_y) = (x as isize - offset_x, y as isize - offset_y);
+            let color = if (0..image_width as isize).contains(&image_x)
+                && (0..image_height as isize).contains(&image_y)
+            {
+                let [r, g, b, a] = image.get_pixel(image_x as u32, image_y as u32).0;
+                Color::from_rgba(r, g, b, a)
+            } else {
+                pixmap.background(x, y)
+            };
+            // Coordinates are within bounds
+            let _ = pixmap.set_pixel_opaque(x, y, color);
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the canvas from raw RGBA bytes read from `reader`, row by row.
+///
+/// Pixels are stored as is, without blending. Fails if the number of bytes
+/// doesn't match the canvas size.
+fn load_raw_canvas(mut reader: impl Read, pixmap: &Pixmap) -> std::io::Result<()> {
+    let (width, height) = pixmap.dimensions();
+    let expected = width * height * 4;
+
+    // Read one byte more than expected, to detect excess input
+    let mut bytes = Vec::with_capacity(expected + 1);
+    reader
+        .by_ref()
+        .take(expected as u64 + 1)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() != expected {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "expected {} bytes for a {}x{} canvas, got {}",
+                expected,
+                width,
+                height,
+                if bytes.len() > expected {
+                    "more".to_string()
+                } else {
+                    bytes.len().to_string()
+                }
+            ),
+        ));
+    }
+
+    for (i, rgba) in bytes.chunks_exact(4).enumerate() {
+        let color = Color::from_rgba(rgba[0], rgba[1], rgba[2], rgba[3]);
+        // Coordinates are within bounds
+        let _ = pixmap.set_pixel_opaque(i % width, i / width, color);
+    }
+
+    Ok(())
+}
+
+/// Draw the pixel commands in the file at `path` on the given canvas.
+///
+/// Commands that don't draw are ignored. Malformed lines are reported and
+/// skipped.
+fn seed_canvas(path: &Path, pixmap: &Pixmap) -> std::io::Result<()> {
+    let file = BufReader::new(File::open(path)?);
+    for (number, line) in file.split(b'\n').enumerate() {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+
+        // Skip the timestamp and address of lines from a command log
+        let line = match line.first() {
+            Some(b'0'..=b'9') => line.splitn(3, |b| *b == b' ').nth(2).unwrap_or(b""),
+            _ => line,
+        };
+
+        // Accept the erase token used by the command log
+        let result = match Cmd::decode_line_erase(line, Some("--")) {
+            Ok(Cmd::SetPixel(x, y, color)) => pixmap.set_pixel(x, y, color).map(|_| ()),
+            Ok(Cmd::ErasePixel(x, y)) => pixmap.erase_pixel(x, y).map(|_| ()),
+            Ok(Cmd::SetRun(x, y, len, color)) => pixmap.fill_rect(x, y, len, 1, color).map(|_| ()),
+            Ok(Cmd::DrawText(x, y, color, text)) => {
+                pixelpwnr_render::draw_text(pixmap, x, y, &text, color).map(|_| ())
+            }
+            Ok(Cmd::CompareAndSet(x, y, expected, new)) => {
+                pixmap.compare_and_set(x, y, expected, new).map(|_| ())
+            }
+            Ok(_) => Ok(()),
+            Err(err) => {
+                eprintln!("Skipping canvas seed line {}: {}", number + 1, err);
+                continue;
+            }
+        };
+        if let Err(PixmapErr::OutOfBound(err)) = result {
+            eprintln!("Skipping canvas seed line {}: {}", number + 1, err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Spawn a new task with the given socket, of a client connected from the
+/// given address
+#[allow(clippy::too_many_arguments)]
+fn handle_socket<S>(
+    mut socket: S,
+    addr: SocketAddr,
     pixmap: Arc<Pixmap>,
     stats: Arc<Stats>,
     opts: CodecOptions,
-) {
-    // A client connected, ensure we're able to get it's address
-    let addr = match socket.peer_addr() {
-        Ok(addr) => addr,
-        Err(err) => {
-            eprintln!("Failed to get remote address: {}", err);
-            return; // Terminate the function gracefully
-        }
-    };
+    size: watch::Receiver<(usize, usize)>,
+    log_connections: bool,
+    command_log: Option<CommandLog>,
+    paused: Arc<AtomicBool>,
+    global_limit: Option<Arc<GlobalLimit>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     println!("A client connected (from: {})", addr);
 
     // Increase the number of clients
     stats.inc_clients();
+    stats.register_ip(addr.ip());
 
     let disconnect_stats = stats.clone();
 
@@ -167,17 +812,42 @@ fn handle_socket(
     let stats = stats.clone();
 
     tokio::spawn(async move {
+        let connected = Instant::now();
         let socket = Pin::new(&mut socket);
 
         // Wrap the socket with the Lines codec,
         // to interact with lines instead of raw bytes
-        let mut lines_val = Lines::new(socket, stats.clone(), pixmap, opts);
+        let mut lines_val = Lines::new(socket, stats.clone(), pixmap, opts, size)
+            .with_pause(paused)
+            .with_peer_addr(addr);
+        if let Some(log) = command_log {
+            lines_val = lines_val.with_command_log(log, addr);
+        }
+        if let Some(limit) = global_limit {
+            lines_val = lines_val.with_global_limit(limit);
+        }
+        if let Some(score) = stats.score(addr.ip()) {
+            lines_val = lines_val.with_score(score);
+        }
         let lines = Pin::new(&mut lines_val);
 
         let result = lines.await;
 
         // Print a disconnect message
         println!("A client disconnected (from: {}). Reason: {}", addr, result);
+        stats.inc_disconnects(result.category());
+
+        // Print a summary of the connection
+        if log_connections {
+            println!(
+                "Connection summary (from: {}): {} bytes read, {} pixels set, {} slow polls, connected for {:.1?}",
+                addr,
+                lines_val.bytes_read(),
+                lines_val.pixels(),
+                lines_val.slow_polls(),
+                connected.elapsed(),
+            );
+        }
 
         // Decreasde the client connections number
         disconnect_stats.dec_clients();
@@ -187,41 +857,135 @@ fn handle_socket(
 /// Start the pixel map renderer.
 fn render(
     arg_handler: &Opts,
-    pixmap: Arc<Pixmap>,
-    stats: Arc<Stats>,
+    renderer: Renderer<'_>,
     net_running: Arc<AtomicBool>,
-) {
-    // Build the renderer
-    let renderer = Renderer::new(env!("CARGO_PKG_NAME"), pixmap);
+) -> Result<(), RenderError> {
+    // Render the canvas
+    renderer.run(arg_handler.render_config(), net_running)
+}
 
-    // Borrow the statistics text
-    let stats_text = renderer.stats().text();
+/// Warn that the renderer failed to start, and that we continue without it.
+fn warn_no_render(err: &RenderError) {
+    eprintln!(
+        "Warning: {}, continuing without rendering as with --no-render",
+        err
+    );
+}
 
-    // Define host to render
-    let host = arg_handler.stats_host.unwrap_or(arg_handler.host);
-    let (host, port) = (host.ip().to_string(), host.port());
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    // Create a stats reporter, and start reporting
-    let reporter = StatReporter::new(
-        arg_handler.stats_screen_interval(),
-        arg_handler.stats_stdout_interval(),
-        arg_handler.stats_save_interval(),
-        arg_handler.stats_file.clone(),
-        stats,
-        Some(stats_text),
-        host,
-        port,
-    );
-    reporter.start();
+    #[test]
+    fn remove_old_images_keeps_recent() {
+        let dir = std::env::temp_dir().join(format!("pixelpwnr-save-keep-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
 
-    // Render the canvas
-    renderer.run(
-        arg_handler.fullscreen,
-        arg_handler.nearest_neighbor,
-        arg_handler.stats_font_size,
-        arg_handler.stats_offset(),
-        arg_handler.stats_padding,
-        arg_handler.stats_col_spacing,
-        net_running,
-    );
+        // Save 5 frames, and an unrelated file
+        for time in 1000..1005 {
+            std::fs::write(dir.join(format!("{}.png", time)), b"").unwrap();
+        }
+        std::fs::write(dir.join("notes.png"), b"").unwrap();
+
+        remove_old_images(&dir, 3).unwrap();
+
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["1002.png", "1003.png", "1004.png", "notes.png"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn session_dirs() {
+        let dir = std::env::temp_dir().join(format!("pixelpwnr-sessions-{}", std::process::id()));
+        let launch = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+
+        // Two sessions, launched within the same second
+        let first = session_dir(&dir, launch).unwrap();
+        let second = session_dir(&dir, launch + Duration::from_millis(500)).unwrap();
+        assert_eq!(first, dir.join("1000"));
+        assert_eq!(second, dir.join("1000-1"));
+
+        let mut dirs: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .collect();
+        dirs.sort();
+        assert_eq!(dirs, [first, second]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn swap_image_centered() {
+        let path = std::env::temp_dir().join(format!("pixelpwnr-swap-{}.png", std::process::id()));
+
+        // A 2x4 image, with a red top half and a green bottom half
+        let image = image::RgbaImage::from_fn(2, 4, |_, y| match y {
+            0 | 1 => image::Rgba([0xFF, 0, 0, 0xFF]),
+            _ => image::Rgba([0, 0xFF, 0, 0xFF]),
+        });
+        image.save(&path).unwrap();
+
+        // A wider, shorter canvas with a pixel drawn to be replaced
+        let pixmap = Pixmap::new(4, 2);
+        pixmap.set_pixel(0, 0, Color::from_rgb(0, 0, 0xFF)).unwrap();
+        swap_image(&path, &pixmap).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // The image is centered horizontally, and cropped to its middle rows
+        let rows: Vec<Vec<String>> = (0..2)
+            .map(|y| (0..4).map(|x| pixmap.pixel(x, y).unwrap().hex()).collect())
+            .collect();
+        assert_eq!(rows[0], ["000000", "FF0000", "FF0000", "000000"]);
+        assert_eq!(rows[1], ["000000", "00FF00", "00FF00", "000000"]);
+    }
+
+    #[test]
+    fn raw_canvas() {
+        let pixmap = Pixmap::new(2, 2);
+        let bytes = [
+            0xFF, 0, 0, 0xFF, 0, 0xFF, 0, 0xFF, //
+            0, 0, 0xFF, 0xFF, 0x12, 0x34, 0x56, 0x78,
+        ];
+        load_raw_canvas(&bytes[..], &pixmap).unwrap();
+
+        assert_eq!(pixmap.pixel(0, 0).unwrap(), Color::from_rgb(0xFF, 0, 0));
+        assert_eq!(pixmap.pixel(1, 0).unwrap(), Color::from_rgb(0, 0xFF, 0));
+        assert_eq!(pixmap.pixel(0, 1).unwrap(), Color::from_rgb(0, 0, 0xFF));
+        assert_eq!(
+            pixmap.pixel(1, 1).unwrap(),
+            Color::from_rgba(0x12, 0x34, 0x56, 0x78)
+        );
+
+        // Too few and too many bytes are refused
+        assert!(load_raw_canvas(&bytes[..15], &pixmap).is_err());
+        assert!(load_raw_canvas(&[0; 17][..], &pixmap).is_err());
+    }
+
+    #[test]
+    fn seed_canvas_commands() {
+        let path = std::env::temp_dir().join(format!("pixelpwnr-seed-{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "PX 0 0 FF0000\r\nSIZE\nnonsense\nPX 9 9 00FF00\n\nPX 1 0 0000FF\nPX 1 1\n\
+             1697462400123 127.0.0.1:1234 PX 0 1 FFFF00\n",
+        )
+        .unwrap();
+
+        let pixmap = Pixmap::new(2, 2);
+        seed_canvas(&path, &pixmap).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let pixel = |x, y| pixmap.pixel(x, y).unwrap().hex();
+        assert_eq!(pixel(0, 0), "FF0000");
+        assert_eq!(pixel(1, 0), "0000FF");
+        assert_eq!(pixel(0, 1), "FFFF00");
+        assert_eq!(pixel(1, 1), "000000");
+    }
 }
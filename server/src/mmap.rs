@@ -0,0 +1,109 @@
+//! Mirror the canvas into a memory-mapped file, so other local processes can
+//! read the live canvas without going through the network protocol.
+//!
+//! The file starts with a header of `HEADER_SIZE` bytes: the `MAGIC` bytes,
+//! followed by the canvas width and height, each as 4 byte LE u32. The pixels
+//! follow directly after the header, in row-major order, with 4 bytes for each
+//! pixel. This is the same layout as `Pixmap::as_bytes`.
+//!
+//! The canvas is copied into the file at a fixed interval, there is no
+//! synchronization with readers. A reader may observe a copy in progress,
+//! which shows up as a partially updated frame. On top of that, the same torn
+//! pixel caveat as described on `Pixmap` applies. The file must only be mapped
+//! read-only by other processes.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use memmap2::MmapMut;
+use pixelpwnr_render::Pixmap;
+
+/// The magic bytes the mirror file starts with.
+pub const MAGIC: [u8; 8] = *b"PXPWNR\0\0";
+
+/// The size of the mirror file header in bytes.
+pub const HEADER_SIZE: usize = MAGIC.len() + 4 + 4;
+
+/// The interval at which the canvas is copied into the mirror file.
+const MIRROR_INTERVAL: Duration = Duration::from_millis(1000 / 60);
+
+/// A canvas mirrored into a memory-mapped file.
+pub struct Mirror {
+    map: MmapMut,
+    pixmap: Arc<Pixmap>,
+}
+
+impl Mirror {
+    /// Create the mirror file at the given path, and map it.
+    ///
+    /// An existing file at the path is truncated. The header is written
+    /// right away, the canvas is copied on `update`.
+    pub fn create(path: &Path, pixmap: Arc<Pixmap>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len((HEADER_SIZE + pixmap.as_bytes().len()) as u64)?;
+
+        // # SAFETY
+        // Mapping a file is only sound if no other process modifies it while
+        // it is mapped. The file is owned by this server, other processes are
+        // documented to only ever read it.
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+
+        let (width, height) = pixmap.dimensions();
+        map[..MAGIC.len()].copy_from_slice(&MAGIC);
+        map[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(width as u32).to_le_bytes());
+        map[MAGIC.len() + 4..HEADER_SIZE].copy_from_slice(&(height as u32).to_le_bytes());
+
+        Ok(Self { map, pixmap })
+    }
+
+    /// Copy the current canvas into the mirror file.
+    pub fn update(&mut self) {
+        self.map[HEADER_SIZE..].copy_from_slice(self.pixmap.as_bytes());
+    }
+
+    /// Keep copying the canvas into the mirror file, forever.
+    pub fn run(mut self) {
+        loop {
+            self.update();
+            std::thread::sleep(MIRROR_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pixelpwnr_render::Color;
+
+    use super::*;
+
+    #[test]
+    fn mirror_canvas() {
+        let path = std::env::temp_dir().join(format!("pixelpwnr-mmap-{}", std::process::id()));
+
+        let pixmap = Arc::new(Pixmap::new(3, 2));
+        let mut mirror = Mirror::create(&path, pixmap.clone()).unwrap();
+        pixmap
+            .set_pixel(2, 1, Color::from_rgb(0x12, 0x34, 0x56))
+            .unwrap();
+        mirror.update();
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), HEADER_SIZE + 3 * 2 * 4);
+        assert_eq!(data[..MAGIC.len()], MAGIC);
+        assert_eq!(data[8..12], 3u32.to_le_bytes());
+        assert_eq!(data[12..16], 2u32.to_le_bytes());
+        assert_eq!(data[HEADER_SIZE..], *pixmap.as_bytes());
+        assert_eq!(data[data.len() - 4..], [0x12, 0x34, 0x56, 0xFF]);
+
+        drop(mirror);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+//! Render stats in the Prometheus text exposition format, served on the
+//! `/metrics` HTTP route.
+
+use std::fmt::Write;
+
+use crate::stats::{Stats, COMMAND_BYTES_BUCKETS};
+
+/// Render the current stats as Prometheus metrics.
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, value: usize| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+    metric(
+        "pixelpwnr_clients",
+        "gauge",
+        "Number of connected clients.",
+        stats.clients(),
+    );
+    metric(
+        "pixelpwnr_pixels_total",
+        "counter",
+        "Number of pixels set.",
+        stats.pixels(),
+    );
+    metric(
+        "pixelpwnr_bytes_read_total",
+        "counter",
+        "Number of bytes read from clients.",
+        stats.bytes_read(),
+    );
+
+    // Histogram buckets are cumulative
+    let counts = stats.command_bytes();
+    let _ = writeln!(
+        out,
+        "# HELP pixelpwnr_command_bytes Size of processed commands in bytes."
+    );
+    let _ = writeln!(out, "# TYPE pixelpwnr_command_bytes histogram");
+    let mut total = 0;
+    for (i, count) in counts.iter().enumerate() {
+        total += count;
+        let le = match COMMAND_BYTES_BUCKETS.get(i) {
+            Some(bound) => bound.to_string(),
+            None => "+Inf".into(),
+        };
+        let _ = writeln!(
+            out,
+            "pixelpwnr_command_bytes_bucket{{le=\"{}\"}} {}",
+            le, total
+        );
+    }
+    let _ = writeln!(
+        out,
+        "pixelpwnr_command_bytes_sum {}",
+        stats.command_bytes_sum()
+    );
+    let _ = writeln!(out, "pixelpwnr_command_bytes_count {}", total);
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_bytes_histogram() {
+        let stats = Stats::new();
+        stats.observe_command_bytes(5);
+        stats.observe_command_bytes(12);
+        stats.observe_command_bytes(5_000);
+
+        let metrics = render(&stats);
+        let lines: Vec<_> = metrics.lines().collect();
+        assert!(lines.contains(&"pixelpwnr_command_bytes_bucket{le=\"8\"} 1"));
+        assert!(lines.contains(&"pixelpwnr_command_bytes_bucket{le=\"16\"} 2"));
+        assert!(lines.contains(&"pixelpwnr_command_bytes_bucket{le=\"1024\"} 2"));
+        assert!(lines.contains(&"pixelpwnr_command_bytes_bucket{le=\"+Inf\"} 3"));
+        assert!(lines.contains(&"pixelpwnr_command_bytes_sum 5017"));
+        assert!(lines.contains(&"pixelpwnr_command_bytes_count 3"));
+    }
+}
@@ -0,0 +1,63 @@
+//! Tuning of the kernel buffer sizes of client sockets.
+
+use socket2::SockRef;
+
+/// The kernel send and receive buffer sizes to use for client sockets, in
+/// bytes. Sizes that aren't set are left at the OS default.
+///
+/// The sizes are applied to the listening socket, and again to each accepted
+/// socket. Platforms differ in which of these matters:
+///
+/// - On Linux, accepted sockets inherit the buffer sizes of the listening
+///   socket. The TCP window scale is negotiated during the handshake, before
+///   accepting, so a large receive buffer is only fully used if the listening
+///   socket has it too. The kernel doubles the requested size for its own
+///   bookkeeping, and clamps it to `net.core.rmem_max` and `net.core.wmem_max`.
+/// - On macOS and the BSDs, accepted sockets also inherit the buffer sizes,
+///   sizes above `kern.ipc.maxsockbuf` are refused with an error.
+/// - On Windows, buffer sizes set on the listening socket are not inherited,
+///   only the sizes set after accepting apply.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketBuffers {
+    /// The receive buffer size, `SO_RCVBUF`.
+    pub recv: Option<usize>,
+
+    /// The send buffer size, `SO_SNDBUF`.
+    pub send: Option<usize>,
+}
+
+impl SocketBuffers {
+    /// Apply the buffer sizes to the given socket.
+    ///
+    /// Warns if a size couldn't be set, or if the OS clamped it to a smaller
+    /// size.
+    pub fn apply(&self, socket: SockRef) {
+        if let Some(size) = self.recv {
+            let actual = socket
+                .set_recv_buffer_size(size)
+                .and_then(|_| socket.recv_buffer_size());
+            warn_clamped("receive", size, actual);
+        }
+        if let Some(size) = self.send {
+            let actual = socket
+                .set_send_buffer_size(size)
+                .and_then(|_| socket.send_buffer_size());
+            warn_clamped("send", size, actual);
+        }
+    }
+}
+
+/// Warn if setting a buffer size failed, or if the OS clamped it.
+fn warn_clamped(name: &str, size: usize, actual: std::io::Result<usize>) {
+    match actual {
+        Ok(actual) if actual < size => eprintln!(
+            "Warning: TCP {} buffer size clamped to {} bytes, requested {} bytes",
+            name, actual, size,
+        ),
+        Ok(_) => {}
+        Err(err) => eprintln!(
+            "Warning: failed to set TCP {} buffer size to {} bytes: {}",
+            name, size, err,
+        ),
+    }
+}
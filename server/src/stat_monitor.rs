@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::fmt;
 
 use std::time::{Duration, Instant};
@@ -20,6 +21,13 @@ const TICKS_MIN_INTERVAL: Duration = Duration::from_micros(100_000);
 /// This value is also the maximum period the calculated result is averaged at.
 const TICKS_MAX_AGE: Duration = Duration::from_micros(2_500_000);
 
+/// The maximum number of calculated results that are remembered in the
+/// history, about one for each second.
+const HISTORY_MAX: usize = 60;
+
+/// The minimum interval between results recorded in the history.
+const HISTORY_INTERVAL: Duration = Duration::from_secs(1);
+
 /// A monitor, that measures a cumulative value over time to report how much
 /// the value grows each second.
 ///
@@ -29,14 +37,21 @@ const TICKS_MAX_AGE: Duration = Duration::from_micros(2_500_000);
 /// This monitor remembers the values in various points in time (called ticks),
 /// to determine the result as reliably as possible.
 /// Note that the result is thus approximate (and not exact).
+///
+/// The calculated results are recorded in a history at most once a second,
+/// for the last `HISTORY_MAX` seconds the monitor was updated in.
 pub struct StatMonitor {
     ticks: Vec<(usize, Instant)>,
+    history: VecDeque<(Instant, f64)>,
 }
 
 impl StatMonitor {
     /// Construct a new monitor.
     pub fn new() -> Self {
-        StatMonitor { ticks: Vec::new() }
+        StatMonitor {
+            ticks: Vec::new(),
+            history: VecDeque::with_capacity(HISTORY_MAX),
+        }
     }
 
     /// Update the monitor by pushing a new value.
@@ -46,8 +61,21 @@ impl StatMonitor {
     /// Note that this value is approximate and that `None` might be returned
     /// in some cases. See the documentation of `calculate()` for more details.
     pub fn update(&mut self, value: usize) -> Option<f64> {
-        // Get the current time, and the last time that was recorded
         let now = Instant::now();
+        let result = self.tick(value, now);
+
+        // Remember the result in the history
+        if let Some(result) = result {
+            self.record(now, result);
+        }
+
+        result
+    }
+
+    /// Register a tick for the given value at the current time `now`, and
+    /// calculate the result.
+    fn tick(&mut self, value: usize, now: Instant) -> Option<f64> {
+        // Get the last time that was recorded
         let last = self.ticks.first().map(|i| i.1);
 
         // The difference between now and the last time must be large enough
@@ -67,6 +95,30 @@ impl StatMonitor {
         self.calculate()
     }
 
+    /// Record a calculated result at the current time `now` in the history.
+    ///
+    /// The result is dropped if the last one was recorded less than
+    /// `HISTORY_INTERVAL` ago. The oldest result is dropped if the history is
+    /// full.
+    fn record(&mut self, now: Instant, result: f64) {
+        if let Some(&(last, _)) = self.history.back() {
+            if now.duration_since(last) < HISTORY_INTERVAL {
+                return;
+            }
+        }
+
+        if self.history.len() >= HISTORY_MAX {
+            self.history.pop_front();
+        }
+        self.history.push_back((now, result));
+    }
+
+    /// Get the history of calculated results with the time they were
+    /// recorded at, oldest first.
+    pub fn history(&self) -> impl Iterator<Item = (Instant, f64)> + '_ {
+        self.history.iter().copied()
+    }
+
     /// Decay any ticks that have become outdated.
     /// This cleans up the list of ticks, to ensure the list is up-to-date
     /// for reliable calculations.
@@ -126,6 +178,7 @@ impl StatMonitor {
     /// Reset the monitor.
     pub fn reset(&mut self) {
         self.ticks.clear();
+        self.history.clear();
     }
 }
 
@@ -134,3 +187,31 @@ impl fmt::Debug for StatMonitor {
         write!(fmt, "StatMonitor")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn history_ring() {
+        let mut monitor = StatMonitor::new();
+        let start = Instant::now();
+
+        // Record more results than fit, one each second
+        for i in 0..HISTORY_MAX + 5 {
+            monitor.record(start + Duration::from_secs(i as u64), i as f64);
+        }
+
+        // Results recorded within the interval are dropped
+        monitor.record(
+            start + Duration::from_millis((HISTORY_MAX as u64 + 4) * 1000 + 500),
+            0.0,
+        );
+
+        let history: Vec<_> = monitor.history().collect();
+        assert_eq!(history.len(), HISTORY_MAX);
+        assert!(history.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(history.first().unwrap().1, 5.0);
+        assert_eq!(history.last().unwrap().1, (HISTORY_MAX + 4) as f64);
+    }
+}
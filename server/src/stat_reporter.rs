@@ -1,11 +1,21 @@
 use parking_lot::Mutex;
 use std::cmp::min;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::{self, sleep};
 use std::time::{Duration, SystemTime};
 
-use crate::stats::Stats;
+use crate::stats::{format_pixels, Stats, StatsSnapshot};
+
+/// The number of client IPs shown on the scoreboard.
+const SCOREBOARD_SIZE: usize = 10;
+
+/// The header line of the CSV report.
+const CSV_HEADER: &str = "timestamp,pixels_per_sec,bytes_per_sec";
 
 /// A struct that is used to periodically report stats.
 pub struct StatReporter {
@@ -17,6 +27,9 @@ pub struct StatReporter {
     /// If none, no screen stats should be reported.
     stdout_interval: Option<Duration>,
 
+    /// Whether to report stats to stdout as JSON, instead of a table.
+    stdout_json: bool,
+
     /// The interval to save the persistent file with.
     /// If none, no stats will be saved.
     save_interval: Option<Duration>,
@@ -24,6 +37,13 @@ pub struct StatReporter {
     /// The file to save persistent stats to.
     save_path: Option<PathBuf>,
 
+    /// The interval to report CSV stats with.
+    /// If none, no CSV stats should be reported.
+    csv_interval: Option<Duration>,
+
+    /// The file to write CSV stats to, instead of stdout.
+    csv_path: Option<PathBuf>,
+
     /// The last time the screen stats were updated.
     screen_last: Arc<Mutex<Option<SystemTime>>>,
 
@@ -33,12 +53,19 @@ pub struct StatReporter {
     /// The last time the stats were saved.
     save_last: Arc<Mutex<Option<SystemTime>>>,
 
+    /// Whether the stats were saved for the last time, before exiting.
+    save_exited: Arc<AtomicBool>,
+
     /// A stats manager.
     stats: Arc<Stats>,
 
     /// A string mutex for text on the screen.
     screen: Arc<Option<Arc<Mutex<String>>>>,
 
+    /// A string mutex for the scoreboard text on the screen, updated along
+    /// with the screen stats.
+    scoreboard: Option<Arc<Mutex<String>>>,
+
     host: String,
     port: u16,
 }
@@ -59,35 +86,85 @@ impl StatReporter {
         StatReporter {
             screen_interval,
             stdout_interval,
+            stdout_json: false,
             save_interval,
             save_path,
+            csv_interval: None,
+            csv_path: None,
             screen_last: Arc::new(Mutex::new(None)),
             stdout_last: Arc::new(Mutex::new(None)),
             save_last: Arc::new(Mutex::new(None)),
+            save_exited: Arc::new(AtomicBool::new(false)),
             stats,
             screen: Arc::new(screen),
+            scoreboard: None,
             host,
             port,
         }
     }
 
+    /// Report the pixels and bytes per second as CSV lines at the given
+    /// interval, for scripted benchmarking.
+    ///
+    /// Lines are written to the file at `path`, or to stdout if not set.
+    /// This is independent of the human readable stdout report.
+    pub fn with_csv_report(mut self, interval: Option<Duration>, path: Option<PathBuf>) -> Self {
+        self.csv_interval = interval;
+        self.csv_path = path;
+        self
+    }
+
+    /// Report stats to stdout as a JSON object on a single line, instead of a
+    /// human readable table.
+    pub fn with_stdout_json(mut self, stdout_json: bool) -> Self {
+        self.stdout_json = stdout_json;
+        self
+    }
+
+    /// Report the top client IPs by written pixels to the given scoreboard
+    /// text, at the screen stats interval.
+    pub fn with_scoreboard(mut self, scoreboard: Option<Arc<Mutex<String>>>) -> Self {
+        self.scoreboard = scoreboard;
+        self
+    }
+
     /// Start the reporter, and spawn a thread internally which controls the
     /// reporting.
     pub fn start(&self) {
         // Do not actually start a thread if there is nothing to report
-        if self.screen_interval.is_none() && self.stdout_interval.is_none() {
+        if self.screen_interval.is_none()
+            && self.stdout_interval.is_none()
+            && self.csv_interval.is_none()
+        {
             return;
         }
 
+        // Open the CSV report output, and write the header
+        let mut csv: Option<Box<dyn Write + Send>> = self.csv_interval.map(|_| {
+            let mut out: Box<dyn Write + Send> = match &self.csv_path {
+                Some(path) => {
+                    Box::new(File::create(path).expect("failed to create CSV report file"))
+                }
+                None => Box::new(io::stdout()),
+            };
+            writeln!(out, "{}", CSV_HEADER).expect("failed to write CSV report");
+            out
+        });
+
         // Clone the arcs for use in the reporter thread
         let stats = self.stats.clone();
         let screen = self.screen.clone();
+        let scoreboard = self.scoreboard.clone();
         let screen_interval = self.screen_interval;
         let stdout_interval = self.stdout_interval;
+        let stdout_json = self.stdout_json;
         let save_interval = self.save_interval;
+        let csv_interval = self.csv_interval;
+        let mut csv_last: Option<SystemTime> = None;
         let screen_last = self.screen_last.clone();
         let stdout_last = self.stdout_last.clone();
         let save_last = self.save_last.clone();
+        let save_exited = self.save_exited.clone();
         let save_path = self.save_path.clone();
         let host = self.host.clone();
         let port = self.port;
@@ -115,6 +192,10 @@ impl StatReporter {
                             Self::report_screen(&stats, screen, &host, port);
                             *last = Some(SystemTime::now());
                         }
+                        if let Some(scoreboard) = &scoreboard {
+                            *scoreboard.lock() =
+                                Self::scoreboard_text(&stats.top_scores(SCOREBOARD_SIZE));
+                        }
                     }
 
                     // See how long we should take, update the next update time
@@ -137,7 +218,14 @@ impl StatReporter {
 
                     // Report stats to the stdout
                     if last.is_none() || elapsed >= interval {
-                        Self::report_stdout(&stats);
+                        if stdout_json {
+                            println!(
+                                "{}",
+                                Self::json_report(&stats.snapshot(), SystemTime::now())
+                            );
+                        } else {
+                            Self::report_stdout(&stats);
+                        }
                         *last = Some(SystemTime::now());
                     }
 
@@ -148,6 +236,31 @@ impl StatReporter {
                     );
                 }
 
+                // Check the CSV report update time
+                if let (Some(interval), Some(csv)) = (csv_interval, csv.as_mut()) {
+                    // Get the number of elapsed seconds since the last report
+                    let elapsed = csv_last
+                        .map(|last| last.elapsed().ok())
+                        .unwrap_or(None)
+                        .unwrap_or(Duration::from_secs(0));
+
+                    // Report a CSV line
+                    if csv_last.is_none() || elapsed >= interval {
+                        let now = SystemTime::now();
+                        let row = Self::csv_row(now, stats.pixels_sec(), stats.bytes_read_sec());
+                        if let Err(err) = writeln!(csv, "{}", row).and_then(|_| csv.flush()) {
+                            eprintln!("Failed to write CSV report: {}", err);
+                        }
+                        csv_last = Some(now);
+                    }
+
+                    // See how long we should take, update the next update time
+                    next_update = min(
+                        next_update,
+                        interval.checked_sub(elapsed).unwrap_or(interval),
+                    );
+                }
+
                 // Check the stats save update time
                 if let Some(interval) = save_interval {
                     // Get the last save time
@@ -159,8 +272,10 @@ impl StatReporter {
                         .unwrap_or(None)
                         .unwrap_or(Duration::from_secs(0));
 
-                    // Report stats to the stdout
-                    if last.is_none() || elapsed >= interval {
+                    // Save the stats, unless the final save already happened
+                    if (last.is_none() || elapsed >= interval)
+                        && !save_exited.load(Ordering::SeqCst)
+                    {
                         // Create a raw stats instance
                         println!("Saving persistent stats...");
                         let raw = stats.to_raw();
@@ -186,13 +301,36 @@ impl StatReporter {
         });
     }
 
+    /// Save the persistent stats one final time, before exiting.
+    ///
+    /// The stats won't be saved by this reporter anymore afterwards.
+    /// Returns whether the stats were saved, which only happens the first time.
+    pub fn save_on_exit(&self) -> bool {
+        // Hold the save lock, to prevent writing while the reporter is saving
+        let _last = self.save_last.lock();
+
+        if self.save_exited.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+
+        match &self.save_path {
+            Some(save_path) => {
+                println!("Saving persistent stats...");
+                self.stats.to_raw().save(save_path.as_path());
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Report the stats to the screen.
     fn report_screen(stats: &Arc<Stats>, screen: &Arc<Mutex<String>>, host: &str, port: u16) {
         *screen.lock() = format!(
-            "CONNECT WITH:        \tpx:\t{}\t{}\tclients: {}\ntelnet {} {}        \tin:\t{}\t{}",
+            "CONNECT WITH:        \tpx:\t{}\t{}\tclients: {} (peak {})\ntelnet {} {}        \tin:\t{}\t{}",
             stats.pixels_human(),
             stats.pixels_sec_human(),
             stats.clients(),
+            stats.peak_clients(),
             host,
             port,
             stats.bytes_read_human(),
@@ -200,13 +338,71 @@ impl StatReporter {
         );
     }
 
+    /// Format the scoreboard text, as a table with a row for each score.
+    ///
+    /// Without any scores, the text is empty to hide the scoreboard.
+    fn scoreboard_text(scores: &[(IpAddr, usize)]) -> String {
+        if scores.is_empty() {
+            return String::new();
+        }
+        let mut text = String::from("SCOREBOARD");
+        for (rank, (ip, pixels)) in scores.iter().enumerate() {
+            text.push_str(&format!(
+                "\n{}.\t{}\t{}",
+                rank + 1,
+                ip,
+                format_pixels(*pixels)
+            ));
+        }
+        text
+    }
+
+    /// Format a CSV report line, with the UNIX timestamp in seconds.
+    ///
+    /// Rates that are unknown are left empty.
+    fn csv_row(time: SystemTime, pixels_sec: Option<f64>, bytes_read_sec: Option<f64>) -> String {
+        let timestamp = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let rate = |rate: Option<f64>| rate.map(|r| format!("{:.1}", r)).unwrap_or_default();
+        format!(
+            "{:.3},{},{}",
+            timestamp,
+            rate(pixels_sec),
+            rate(bytes_read_sec)
+        )
+    }
+
+    /// Format a JSON report of the given stats, with the UNIX timestamp in
+    /// seconds.
+    ///
+    /// Rates that are unknown are `null`.
+    fn json_report(snapshot: &StatsSnapshot, time: SystemTime) -> String {
+        let timestamp = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        serde_json::json!({
+            "timestamp": timestamp,
+            "pixels": snapshot.pixels,
+            "pixels_per_sec": snapshot.pixels_sec,
+            "bytes_read": snapshot.bytes_read,
+            "bytes_per_sec": snapshot.bytes_read_sec,
+            "clients": snapshot.clients,
+        })
+        .to_string()
+    }
+
     /// Report the stats to stdout.
     fn report_stdout(stats: &Arc<Stats>) {
         println!(
             "\
                 {: <7} {: <15} {: <12}\n\
                 {: <7} {: <15} {: <12}\n\
-                {: <7} {: <15} {: <12}\
+                {: <7} {: <15} {: <12}\n\
+                {: <7} {: <15} {: <12}\n\
+                {: <7} {}\
             ",
             "STATS",
             "Total:",
@@ -217,6 +413,99 @@ impl StatReporter {
             "Input:",
             stats.bytes_read_human(),
             stats.bytes_read_sec_human(),
+            "Clients:",
+            stats.clients(),
+            format!(
+                "{} unique, {} peak",
+                stats.unique_ips(),
+                stats.peak_clients()
+            ),
+            "Cmds:",
+            stats.commands_human(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stats::StatsRaw;
+
+    #[test]
+    fn save_on_exit_once() {
+        let path =
+            std::env::temp_dir().join(format!("pixelpwnr-save-on-exit-{}.yml", std::process::id()));
+
+        let stats = Arc::new(Stats::new());
+        stats.inc_pixels_by_n(42);
+        let reporter = StatReporter::new(
+            None,
+            None,
+            None,
+            Some(path.clone()),
+            stats,
+            None,
+            "localhost".into(),
+            1337,
+        );
+
+        // Only the first shutdown saves
+        assert!(reporter.save_on_exit());
+        assert!(!reporter.save_on_exit());
+
+        let raw = StatsRaw::load(&path).unwrap();
+        assert_eq!(raw.pixels, 42);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn json_format() {
+        let stats = Stats::new();
+        stats.inc_clients();
+        stats.inc_pixels_by_n(42);
+        stats.inc_bytes_read(1337);
+
+        // The rates are unknown right after starting
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_600_000_000_250);
+        let report = StatReporter::json_report(&stats.snapshot(), time);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&report).unwrap(),
+            serde_json::json!({
+                "timestamp": 1600000000.25,
+                "pixels": 42,
+                "pixels_per_sec": null,
+                "bytes_read": 1337,
+                "bytes_per_sec": null,
+                "clients": 1,
+            })
+        );
+        assert!(!report.contains('\n'));
+    }
+
+    #[test]
+    fn scoreboard_format() {
+        assert_eq!(StatReporter::scoreboard_text(&[]), "");
+        assert_eq!(
+            StatReporter::scoreboard_text(&[
+                ("10.0.0.2".parse().unwrap(), 12_345),
+                ("::1".parse().unwrap(), 42),
+            ]),
+            "SCOREBOARD\n1.\t10.0.0.2\t12.3 kP\n2.\t::1\t42 P"
+        );
+    }
+
+    #[test]
+    fn csv_format() {
+        assert_eq!(CSV_HEADER, "timestamp,pixels_per_sec,bytes_per_sec");
+
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_600_000_000_250);
+        assert_eq!(
+            StatReporter::csv_row(time, Some(1234.56), Some(42.0)),
+            "1600000000.250,1234.6,42.0"
+        );
+        assert_eq!(
+            StatReporter::csv_row(time, None, Some(42.0)),
+            "1600000000.250,,42.0"
         );
     }
 }
@@ -1,12 +1,17 @@
 extern crate number_prefix;
 
 use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::{Read, Write};
+use std::net::IpAddr;
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 use self::number_prefix::NumberPrefix::{self, Prefixed, Standalone};
+use pixelpwnr_render::fnv1a;
 use serde::{Deserialize, Serialize};
 
 use crate::stat_monitor::StatMonitor;
@@ -21,6 +26,9 @@ pub struct Stats {
     /// The number of clients that are currently connected.
     clients: AtomicUsize,
 
+    /// The highest number of clients that have been connected at once.
+    peak_clients: AtomicUsize,
+
     /// The total number of pixels that have been written by clients to the
     /// screen.
     pixels: AtomicUsize,
@@ -33,6 +41,85 @@ pub struct Stats {
 
     /// A monitor for the number of bytes being read this second.
     bytes_read_monitor: Mutex<StatMonitor>,
+
+    /// The unique client IPs that have been seen, with the last time they
+    /// connected.
+    unique_ips: Mutex<HashMap<IpAddr, Instant>>,
+
+    /// The time after which a client IP is forgotten, if set.
+    unique_ip_window: Option<Duration>,
+
+    /// The unit to report the input rate in.
+    rate_unit: RateUnit,
+
+    /// The number of pixels written from each client IP, for the scoreboard.
+    /// If `None`, scores are not tracked.
+    scores: Option<Mutex<HashMap<IpAddr, Arc<AtomicUsize>>>>,
+
+    /// The number of closed connections, by disconnect reason category.
+    disconnects: Mutex<BTreeMap<&'static str, usize>>,
+
+    /// The number of processed commands, for each kind of command.
+    commands: [AtomicUsize; CmdKind::ALL.len()],
+
+    /// The number of processed commands in each command size bucket. The last
+    /// bucket counts commands larger than the largest bound.
+    #[cfg(feature = "prometheus")]
+    command_bytes: [AtomicUsize; COMMAND_BYTES_BUCKETS.len() + 1],
+
+    /// The total size of all processed commands in bytes.
+    #[cfg(feature = "prometheus")]
+    command_bytes_sum: AtomicUsize,
+}
+
+/// The upper bounds of the command size histogram buckets, in bytes.
+#[cfg(feature = "prometheus")]
+pub const COMMAND_BYTES_BUCKETS: [usize; 8] = [8, 16, 32, 64, 128, 256, 512, 1024];
+
+/// A kind of command, counted separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmdKind {
+    /// Commands setting pixels.
+    Set,
+    /// Commands getting pixels.
+    Get,
+    /// Commands querying or subscribing to the canvas size.
+    Size,
+    /// Commands requesting help or capabilities.
+    Help,
+    /// Any other command.
+    Other,
+}
+
+impl CmdKind {
+    /// All kinds of commands, in reporting order.
+    pub const ALL: [CmdKind; 5] = [
+        CmdKind::Set,
+        CmdKind::Get,
+        CmdKind::Size,
+        CmdKind::Help,
+        CmdKind::Other,
+    ];
+
+    /// Get the name of this kind of command.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CmdKind::Set => "set",
+            CmdKind::Get => "get",
+            CmdKind::Size => "size",
+            CmdKind::Help => "help",
+            CmdKind::Other => "other",
+        }
+    }
+}
+
+/// A unit to report the input rate in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RateUnit {
+    /// Bits per second, `b/s`
+    Bits,
+    /// Bytes per second, `B/s`
+    Bytes,
 }
 
 impl Stats {
@@ -42,16 +129,101 @@ impl Stats {
             pixels: AtomicUsize::new(0),
             pixels_monitor: Mutex::new(StatMonitor::new()),
             clients: AtomicUsize::new(0),
+            peak_clients: AtomicUsize::new(0),
             bytes_read: AtomicUsize::new(0),
             bytes_read_monitor: Mutex::new(StatMonitor::new()),
+            unique_ips: Mutex::new(HashMap::new()),
+            unique_ip_window: None,
+            rate_unit: RateUnit::Bits,
+            scores: None,
+            disconnects: Mutex::new(BTreeMap::new()),
+            commands: Default::default(),
+            #[cfg(feature = "prometheus")]
+            command_bytes: Default::default(),
+            #[cfg(feature = "prometheus")]
+            command_bytes_sum: AtomicUsize::new(0),
         }
     }
 
+    /// Set the time after which a client IP is forgotten when counting unique
+    /// client IPs. This bounds the memory used on long running servers.
+    ///
+    /// If `None`, all client IPs are remembered.
+    pub fn with_unique_ip_window(mut self, window: Option<Duration>) -> Self {
+        self.unique_ip_window = window;
+        self
+    }
+
+    /// Set the unit to report the human readable input rate in. Defaults to
+    /// bits per second.
+    pub fn with_rate_unit(mut self, rate_unit: RateUnit) -> Self {
+        self.rate_unit = rate_unit;
+        self
+    }
+
+    /// Set whether to track the number of pixels written from each client IP,
+    /// for the scoreboard.
+    pub fn with_scores(mut self, scores: bool) -> Self {
+        self.scores = scores.then(|| Mutex::new(HashMap::new()));
+        self
+    }
+
+    /// Get the counter of pixels written from the given client IP, shared by
+    /// all connections from it. Returns `None` if scores aren't tracked.
+    pub fn score(&self, ip: IpAddr) -> Option<Arc<AtomicUsize>> {
+        let mut scores = self.scores.as_ref()?.lock();
+        Some(scores.entry(ip).or_default().clone())
+    }
+
+    /// Get the client IPs that wrote the most pixels, with their number of
+    /// pixels, highest first. At most `count` scores are returned.
+    pub fn top_scores(&self, count: usize) -> Vec<(IpAddr, usize)> {
+        let mut scores: Vec<_> = match &self.scores {
+            Some(scores) => scores
+                .lock()
+                .iter()
+                .map(|(ip, score)| (*ip, score.load(Ordering::Relaxed)))
+                .collect(),
+            None => return Vec::new(),
+        };
+        scores.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        scores.truncate(count);
+        scores
+    }
+
     /// Get the total number of clients currently connected.
     pub fn clients(&self) -> usize {
         self.clients.load(Ordering::Relaxed)
     }
 
+    /// Get the highest number of clients that have been connected at once.
+    pub fn peak_clients(&self) -> usize {
+        self.peak_clients.load(Ordering::Relaxed)
+    }
+
+    /// Register a client IP, to count unique client IPs.
+    pub fn register_ip(&self, ip: IpAddr) {
+        let mut ips = self.unique_ips.lock();
+        ips.insert(ip, Instant::now());
+        self.forget_ips(&mut ips);
+    }
+
+    /// Get the number of unique client IPs that have been seen.
+    ///
+    /// If a window is set, only client IPs seen within it are counted.
+    pub fn unique_ips(&self) -> usize {
+        let mut ips = self.unique_ips.lock();
+        self.forget_ips(&mut ips);
+        ips.len()
+    }
+
+    /// Forget client IPs that haven't been seen within the window, if set.
+    fn forget_ips(&self, ips: &mut HashMap<IpAddr, Instant>) {
+        if let Some(window) = self.unique_ip_window {
+            ips.retain(|_, last| last.elapsed() <= window);
+        }
+    }
+
     /// Get the total number of pixels that have been written to the screen
     /// by clients.
     pub fn pixels(&self) -> usize {
@@ -61,18 +233,7 @@ impl Stats {
     /// Get the total number of pixels that have been written to the screen
     /// by clients as a string in a humanly readable format.
     pub fn pixels_human(&self) -> String {
-        match NumberPrefix::decimal(self.pixels() as f64) {
-            Standalone(b) => format!("{:.00} P", b.ceil()),
-            Prefixed(p, n) => {
-                if n < 10f64 {
-                    format!("{:.02} {}P", n, p)
-                } else if n < 100f64 {
-                    format!("{:.01} {}P", n, p)
-                } else {
-                    format!("{:.00} {}P", n, p)
-                }
-            }
-        }
+        format_pixels(self.pixels())
     }
 
     /// Get the total number of pixels that have been written to the screen
@@ -111,7 +272,8 @@ impl Stats {
 
     /// Increment the number of clients that are connected, by one.
     pub fn inc_clients(&self) {
-        self.clients.fetch_add(1, Ordering::SeqCst);
+        let clients = self.clients.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_clients.fetch_max(clients, Ordering::Relaxed);
     }
 
     /// Decrease the number of clients that are connected, by one.
@@ -119,6 +281,65 @@ impl Stats {
         self.clients.fetch_sub(1, Ordering::SeqCst);
     }
 
+    /// Count a closed connection, in the given disconnect reason category.
+    pub fn inc_disconnects(&self, category: &'static str) {
+        *self.disconnects.lock().entry(category).or_insert(0) += 1;
+    }
+
+    /// Get the number of closed connections, by disconnect reason category.
+    pub fn disconnects(&self) -> BTreeMap<&'static str, usize> {
+        self.disconnects.lock().clone()
+    }
+
+    /// Count a processed command of the given kind.
+    pub fn inc_commands(&self, kind: CmdKind) {
+        self.commands[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the number of processed commands of the given kind.
+    pub fn commands(&self, kind: CmdKind) -> usize {
+        self.commands[kind as usize].load(Ordering::Relaxed)
+    }
+
+    /// Get the number of processed commands of each kind, in a humanly
+    /// readable format.
+    pub fn commands_human(&self) -> String {
+        CmdKind::ALL
+            .iter()
+            .map(|kind| format!("{} {}", self.commands(*kind), kind.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Count a processed command of the given size in bytes, in the command
+    /// size histogram.
+    #[cfg(feature = "prometheus")]
+    pub fn observe_command_bytes(&self, bytes: usize) {
+        let bucket = COMMAND_BYTES_BUCKETS
+            .iter()
+            .position(|bound| bytes <= *bound)
+            .unwrap_or(COMMAND_BYTES_BUCKETS.len());
+        self.command_bytes[bucket].fetch_add(1, Ordering::Relaxed);
+        self.command_bytes_sum.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Get the number of processed commands in each command size bucket, see
+    /// `COMMAND_BYTES_BUCKETS`. The counts are not cumulative, the last bucket
+    /// counts commands larger than the largest bound.
+    #[cfg(feature = "prometheus")]
+    pub fn command_bytes(&self) -> Vec<usize> {
+        self.command_bytes
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Get the total size of all processed commands in bytes.
+    #[cfg(feature = "prometheus")]
+    pub fn command_bytes_sum(&self) -> usize {
+        self.command_bytes_sum.load(Ordering::Relaxed)
+    }
+
     /// Increase the number of pixels that have been written to the screen by
     /// n.
     ///
@@ -170,18 +391,7 @@ impl Stats {
     /// reliably, `None` is returned.
     pub fn bytes_read_sec_human(&self) -> String {
         match self.bytes_read_sec() {
-            Some(bytes) => match NumberPrefix::decimal(bytes * 8f64) {
-                Standalone(b) => format!("{:.00} b/s", b.ceil()),
-                Prefixed(p, n) => {
-                    if n < 10f64 {
-                        format!("{:.02} {}b/s", n, p)
-                    } else if n < 100f64 {
-                        format!("{:.01} {}b/s", n, p)
-                    } else {
-                        format!("{:.00} {}b/s", n, p)
-                    }
-                }
-            },
+            Some(bytes) => format_rate(bytes, self.rate_unit),
             None => String::from("~"),
         }
     }
@@ -205,18 +415,157 @@ impl Stats {
         let mut me = Self::new();
         me.pixels = AtomicUsize::new(raw.pixels);
         me.bytes_read = AtomicUsize::new(raw.bytes_read);
+        me.peak_clients = AtomicUsize::new(raw.peak_clients);
         me.pixels_monitor.lock().reset();
         me.bytes_read_monitor.lock().reset();
 
         me
     }
 
+    /// Get the recent per-second rates of written pixels and read bytes, for
+    /// about the last minute, oldest first.
+    ///
+    /// Rates are recorded whenever they are calculated, at most once a
+    /// second, so the series may have gaps if stats aren't reported often.
+    pub fn recent_rates(&self) -> RecentRates {
+        RecentRates {
+            pixels_sec: rate_samples(&self.pixels_monitor.lock()),
+            bytes_read_sec: rate_samples(&self.bytes_read_monitor.lock()),
+        }
+    }
+
+    /// Take a snapshot of the current stats, for reporting.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            clients: self.clients(),
+            peak_clients: self.peak_clients(),
+            unique_ips: self.unique_ips(),
+            pixels: self.pixels(),
+            pixels_sec: self.pixels_sec(),
+            bytes_read: self.bytes_read(),
+            bytes_read_sec: self.bytes_read_sec(),
+            disconnects: self.disconnects(),
+            commands: CmdKind::ALL
+                .iter()
+                .map(|kind| (kind.as_str(), self.commands(*kind)))
+                .collect(),
+        }
+    }
+
     /// Convert this data in a raw stats object.
     pub fn to_raw(&self) -> StatsRaw {
-        StatsRaw::new(self.pixels(), self.bytes_read())
+        StatsRaw::new(self.pixels(), self.bytes_read(), self.peak_clients())
     }
 }
 
+/// A snapshot of the stats at some point in time, for reporting.
+#[derive(Debug, Serialize)]
+pub struct StatsSnapshot {
+    /// The number of clients that are currently connected.
+    pub clients: usize,
+
+    /// The highest number of clients that have been connected at once.
+    pub peak_clients: usize,
+
+    /// The number of unique client IPs that have been seen.
+    pub unique_ips: usize,
+
+    /// The total number of pixels that have been written.
+    pub pixels: usize,
+
+    /// The number of pixels written per second, if known.
+    pub pixels_sec: Option<f64>,
+
+    /// The total amount of bytes that have been read.
+    pub bytes_read: usize,
+
+    /// The number of bytes read per second, if known.
+    pub bytes_read_sec: Option<f64>,
+
+    /// The number of closed connections, by disconnect reason category.
+    pub disconnects: BTreeMap<&'static str, usize>,
+
+    /// The number of processed commands, by kind of command.
+    pub commands: BTreeMap<&'static str, usize>,
+}
+
+/// Recent per-second rates, as returned by `Stats::recent_rates`.
+#[derive(Debug, Serialize)]
+pub struct RecentRates {
+    /// The number of pixels written per second.
+    pub pixels_sec: Vec<RateSample>,
+
+    /// The number of bytes read per second.
+    pub bytes_read_sec: Vec<RateSample>,
+}
+
+/// A rate at some point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RateSample {
+    /// The UNIX timestamp in milliseconds the rate was calculated at.
+    pub time: u64,
+
+    /// The rate per second.
+    pub rate: f64,
+}
+
+/// Format a number of pixels in a humanly readable format.
+pub fn format_pixels(pixels: usize) -> String {
+    match NumberPrefix::decimal(pixels as f64) {
+        Standalone(b) => format!("{:.00} P", b.ceil()),
+        Prefixed(p, n) => {
+            if n < 10f64 {
+                format!("{:.02} {}P", n, p)
+            } else if n < 100f64 {
+                format!("{:.01} {}P", n, p)
+            } else {
+                format!("{:.00} {}P", n, p)
+            }
+        }
+    }
+}
+
+/// Format an input rate in bytes per second in a humanly readable format, in
+/// the given unit.
+fn format_rate(bytes_sec: f64, unit: RateUnit) -> String {
+    let (rate, symbol) = match unit {
+        RateUnit::Bits => (bytes_sec * 8f64, "b"),
+        RateUnit::Bytes => (bytes_sec, "B"),
+    };
+
+    match NumberPrefix::decimal(rate) {
+        Standalone(b) => format!("{:.00} {}/s", b.ceil(), symbol),
+        Prefixed(p, n) => {
+            if n < 10f64 {
+                format!("{:.02} {}{}/s", n, p, symbol)
+            } else if n < 100f64 {
+                format!("{:.01} {}{}/s", n, p, symbol)
+            } else {
+                format!("{:.00} {}{}/s", n, p, symbol)
+            }
+        }
+    }
+}
+
+/// Collect the rate history of the given monitor as samples.
+fn rate_samples(monitor: &StatMonitor) -> Vec<RateSample> {
+    // Map monotonic times onto the wall clock
+    let now = Instant::now();
+    let unix_now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    monitor
+        .history()
+        .map(|(time, rate)| RateSample {
+            time: unix_now
+                .saturating_sub(now.duration_since(time))
+                .as_millis() as u64,
+            rate,
+        })
+        .collect()
+}
+
 /// A struct that contains raw stats data.
 /// This struct can be used to store and load stats data.
 #[derive(Debug, Serialize, Deserialize)]
@@ -227,16 +576,63 @@ pub struct StatsRaw {
 
     /// The total amount of bytes that have been read.
     pub bytes_read: usize,
+
+    /// The highest number of clients that have been connected at once.
+    #[serde(default)]
+    pub peak_clients: usize,
+
+    /// A checksum of the stats above, to detect corrupted files. Set when
+    /// saving, files without one are trusted.
+    #[serde(default)]
+    checksum: Option<u64>,
 }
 
 impl StatsRaw {
     /// Construct a new raw stats object.
-    pub fn new(pixels: usize, bytes_read: usize) -> Self {
-        Self { pixels, bytes_read }
+    pub fn new(pixels: usize, bytes_read: usize, peak_clients: usize) -> Self {
+        Self {
+            pixels,
+            bytes_read,
+            peak_clients,
+            checksum: None,
+        }
+    }
+
+    /// Compute a checksum of the stats, with the 64-bit FNV-1a hash.
+    fn compute_checksum(&self) -> u64 {
+        fnv1a(
+            [self.pixels, self.bytes_read, self.peak_clients]
+                .iter()
+                .flat_map(|v| (*v as u64).to_le_bytes()),
+        )
+    }
+
+    /// Check whether the stats look intact.
+    ///
+    /// Values that would be negative as signed integer can't be reached, and
+    /// are reported as corruption, as is a checksum that doesn't match.
+    fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("pixels", self.pixels),
+            ("bytes_read", self.bytes_read),
+            ("peak_clients", self.peak_clients),
+        ] {
+            if value as u64 > i64::MAX as u64 {
+                return Err(format!("implausible {} value {}", name, value));
+            }
+        }
+
+        match self.checksum {
+            Some(checksum) if checksum != self.compute_checksum() => {
+                Err("checksum mismatch".into())
+            }
+            _ => Ok(()),
+        }
     }
 
     /// Load the raw stats to the file at the given path.
-    /// If no stats could be loaded, `None` is returned.
+    /// If no stats could be loaded, or they look corrupted, `None` is
+    /// returned.
     pub fn load(path: &Path) -> Option<Self> {
         // Make sure the file exists
         if !path.is_file() {
@@ -253,15 +649,30 @@ impl StatsRaw {
             .expect("failed to read persistent stats from file");
 
         // Load the raw state
-        serde_yaml::from_str(&data)
+        let raw: Self = serde_yaml::from_str(&data)
             .map_err(|_| println!("failed to load persistent stats, malformed data"))
-            .ok()
+            .ok()?;
+
+        if let Err(err) = raw.validate() {
+            eprintln!(
+                "Warning: not loading persistent stats, file is corrupted: {}",
+                err
+            );
+            return None;
+        }
+
+        Some(raw)
     }
 
     /// Save the raw stats to the file at the given path.
     pub fn save(&self, path: &Path) {
+        let raw = Self {
+            checksum: Some(self.compute_checksum()),
+            ..*self
+        };
+
         // Save the object to a string.
-        let data = serde_yaml::to_string(&self).expect("failed to serialize");
+        let data = serde_yaml::to_string(&raw).expect("failed to serialize");
 
         // Write the data to the file
         let mut file = File::create(path).expect("failed to create persistent stats file");
@@ -269,3 +680,125 @@ impl StatsRaw {
             .expect("failed to write to persistent stats file");
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unique_ips() {
+        let stats = Stats::new();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        // Repeated connections from a single IP count once
+        stats.register_ip(ip);
+        stats.register_ip(ip);
+        stats.register_ip(ip);
+        assert_eq!(stats.unique_ips(), 1);
+
+        stats.register_ip("::1".parse().unwrap());
+        assert_eq!(stats.unique_ips(), 2);
+    }
+
+    #[test]
+    fn top_scores() {
+        let stats = Stats::new();
+        assert!(stats.score("10.0.0.1".parse().unwrap()).is_none());
+
+        let stats = Stats::new().with_scores(true);
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        // Connections from the same IP share a score
+        stats.score(a).unwrap().fetch_add(10, Ordering::Relaxed);
+        stats.score(a).unwrap().fetch_add(5, Ordering::Relaxed);
+        stats.score(b).unwrap().fetch_add(20, Ordering::Relaxed);
+
+        assert_eq!(stats.top_scores(5), [(b, 20), (a, 15)]);
+        assert_eq!(stats.top_scores(1), [(b, 20)]);
+    }
+
+    #[test]
+    fn peak_clients() {
+        let stats = Stats::new();
+        for _ in 0..3 {
+            stats.inc_clients();
+        }
+        stats.dec_clients();
+        stats.dec_clients();
+        assert_eq!(stats.clients(), 1);
+        assert_eq!(stats.peak_clients(), 3);
+
+        // The peak survives a restart
+        let stats = Stats::from_raw(&stats.to_raw());
+        assert_eq!(stats.peak_clients(), 3);
+    }
+
+    #[test]
+    fn load_checksum() {
+        let path = std::env::temp_dir().join(format!("pixelpwnr-stats-{}.yml", std::process::id()));
+        let load = |data: &str| {
+            std::fs::write(&path, data).unwrap();
+            StatsRaw::load(&path)
+        };
+
+        // A saved file loads intact
+        StatsRaw::new(42, 1337, 3).save(&path);
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let raw = StatsRaw::load(&path).unwrap();
+        assert_eq!(
+            (raw.pixels, raw.bytes_read, raw.peak_clients),
+            (42, 1337, 3)
+        );
+
+        // Files without a checksum are trusted
+        assert!(load("pixels: 42\nbytes_read: 1337\n").is_some());
+
+        // A tampered value no longer matches the checksum
+        assert!(load(&saved.replace("pixels: 42", "pixels: 4200000")).is_none());
+
+        // A bad checksum is rejected
+        assert!(load("pixels: 42\nbytes_read: 1337\npeak_clients: 3\nchecksum: 1\n").is_none());
+
+        // Values that look negative are rejected, even without a checksum
+        assert!(load("pixels: 18446744073709551615\nbytes_read: 1337\n").is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "prometheus")]
+    fn command_bytes() {
+        let stats = Stats::new();
+        for bytes in [1, 8, 9, 1024, 2000] {
+            stats.observe_command_bytes(bytes);
+        }
+
+        assert_eq!(stats.command_bytes(), [2, 1, 0, 0, 0, 0, 0, 1, 1]);
+        assert_eq!(stats.command_bytes_sum(), 3042);
+    }
+
+    #[test]
+    fn rate_units() {
+        assert_eq!(format_rate(1_500.0, RateUnit::Bits), "12.0 kb/s");
+        assert_eq!(format_rate(1_500.0, RateUnit::Bytes), "1.50 kB/s");
+        assert_eq!(format_rate(100.0, RateUnit::Bits), "800 b/s");
+        assert_eq!(format_rate(100.0, RateUnit::Bytes), "100 B/s");
+    }
+
+    #[test]
+    fn recent_rates() {
+        let stats = Stats::new();
+        assert!(stats.recent_rates().pixels_sec.is_empty());
+
+        // Two ticks are required to calculate and record a rate
+        stats.pixels_sec();
+        std::thread::sleep(Duration::from_millis(150));
+        stats.inc_pixels_by_n(100);
+        stats.pixels_sec();
+
+        let rates = stats.recent_rates();
+        assert_eq!(rates.pixels_sec.len(), 1);
+        assert!(rates.pixels_sec[0].rate > 0.0);
+        assert!(rates.bytes_read_sec.is_empty());
+    }
+}
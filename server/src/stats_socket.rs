@@ -0,0 +1,77 @@
+//! Serve stats as JSON over a Unix domain socket, for local scraping.
+//!
+//! Each connection receives a single stats snapshot, after which it is closed.
+
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixListener;
+
+use crate::stats::Stats;
+
+/// Bind a Unix domain socket at the given path.
+///
+/// A stale socket left behind at the path is removed first.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    UnixListener::bind(path)
+}
+
+/// Accept connections on the given listener, and respond with a stats snapshot.
+pub async fn listen(listener: UnixListener, stats: Arc<Stats>) {
+    loop {
+        let mut socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                eprintln!("Failed to accept a stats socket connection: {}", err);
+                continue;
+            }
+        };
+
+        let stats = stats.clone();
+        tokio::spawn(async move {
+            let mut json = serde_json::to_vec(&stats.snapshot()).unwrap();
+            json.push(b'\n');
+
+            // Errors here are caused by clients going away, ignore them
+            let _ = socket.write_all(&json).await;
+            let _ = socket.shutdown().await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::UnixStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_snapshot() {
+        let path =
+            std::env::temp_dir().join(format!("pixelpwnr-stats-{}.sock", std::process::id()));
+
+        let stats = Arc::new(Stats::new());
+        stats.inc_pixels_by_n(42);
+        tokio::spawn(listen(bind(&path).unwrap(), stats));
+
+        let mut socket = UnixStream::connect(&path).await.unwrap();
+        let mut json = String::new();
+        socket.read_to_string(&mut json).await.unwrap();
+
+        let snapshot: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot["pixels"], 42);
+        assert_eq!(snapshot["clients"], 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
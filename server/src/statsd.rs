@@ -0,0 +1,105 @@
+//! Report stats as StatsD gauges over UDP, for StatsD and Telegraf pipelines.
+
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::stats::Stats;
+
+/// The prefix of all reported gauge names.
+const PREFIX: &str = "pixelpwnr";
+
+/// Reports stats as StatsD gauges to a StatsD server.
+pub struct StatsD {
+    /// The address of the StatsD server.
+    addr: SocketAddr,
+
+    /// The interval to report at.
+    interval: Duration,
+
+    /// The stats to report.
+    stats: Arc<Stats>,
+}
+
+impl StatsD {
+    /// Construct a new reporter, sending to the StatsD server at `addr` each
+    /// `interval`.
+    pub fn new(addr: SocketAddr, interval: Duration, stats: Arc<Stats>) -> Self {
+        Self {
+            addr,
+            interval,
+            stats,
+        }
+    }
+
+    /// Keep reporting stats, forever.
+    ///
+    /// Failing to send is reported, but doesn't stop reporting.
+    pub async fn run(self) {
+        let bind: SocketAddr = match self.addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = match UdpSocket::bind(bind).await {
+            Ok(socket) => socket,
+            Err(err) => {
+                eprintln!("Failed to bind StatsD socket: {}", err);
+                return;
+            }
+        };
+
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = socket
+                .send_to(gauges(&self.stats).as_bytes(), self.addr)
+                .await
+            {
+                eprintln!("Failed to send stats to StatsD: {}", err);
+            }
+        }
+    }
+}
+
+/// Format the current stats as StatsD gauges, one on each line.
+///
+/// Rates that can't be determined are skipped.
+fn gauges(stats: &Stats) -> String {
+    let mut gauges = String::new();
+    let mut gauge = |name: &str, value: &dyn std::fmt::Display| {
+        let _ = writeln!(gauges, "{}.{}:{}|g", PREFIX, name, value);
+    };
+
+    gauge("pixels", &stats.pixels());
+    gauge("bytes_read", &stats.bytes_read());
+    gauge("clients", &stats.clients());
+    if let Some(pixels_sec) = stats.pixels_sec() {
+        gauge("pixels_per_sec", &(pixels_sec as u64));
+    }
+
+    gauges
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gauge_lines() {
+        let stats = Stats::new();
+        stats.inc_clients();
+        stats.inc_pixels_by_n(42);
+        stats.inc_bytes_read(1337);
+
+        // The pixel rate is unknown right after starting
+        assert_eq!(
+            gauges(&stats),
+            "pixelpwnr.pixels:42|g\n\
+             pixelpwnr.bytes_read:1337|g\n\
+             pixelpwnr.clients:1|g\n"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+//! Synthetic clients, that keep drawing the whole canvas without any external
+//! client being connected.
+//!
+//! These are only meant as a testing and benchmarking aid.
+
+use std::sync::Arc;
+
+use pixelpwnr_render::{Color, Pixmap};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
+
+use crate::codec::{CodecOptions, Lines};
+use crate::stats::Stats;
+
+/// The capacity of the in-memory connection of the tokio synthetic client.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// Get the color to draw the given frame in, cycling through colors.
+fn frame_color(frame: usize) -> Color {
+    Color::from_rgb(
+        frame.wrapping_mul(47) as u8,
+        frame.wrapping_mul(97) as u8,
+        frame.wrapping_mul(193) as u8,
+    )
+}
+
+/// Get the coordinates of every pixel on a canvas with the given dimensions,
+/// row by row.
+fn pixels((width, height): (usize, usize)) -> impl Iterator<Item = (usize, usize)> {
+    (0..height).flat_map(move |y| (0..width).map(move |x| (x, y)))
+}
+
+/// Build a buffer of `PX` commands drawing every pixel of the given canvas
+/// once, in the given color.
+fn frame_commands(pixmap: &Pixmap, color: Color) -> Vec<u8> {
+    let color = color.hex();
+    let mut buf = Vec::new();
+    for (x, y) in pixels(pixmap.dimensions()) {
+        buf.extend_from_slice(format!("PX {} {} {}\n", x, y, color).as_bytes());
+    }
+    buf
+}
+
+/// Run a synthetic client on the tokio runtime.
+///
+/// The client is connected through an in-memory pipe, and sends `PX`
+/// commands for every pixel of the canvas through the line codec, just like
+/// a real client would.
+pub async fn tokio_synthetic_client(
+    pixmap: Arc<Pixmap>,
+    stats: Arc<Stats>,
+    opts: CodecOptions,
+    size: watch::Receiver<(usize, usize)>,
+) {
+    let (mut client, server) = tokio::io::duplex(PIPE_SIZE);
+
+    let lines = Lines::new(Box::pin(server), stats, pixmap.clone(), opts, size);
+    tokio::spawn(async move {
+        let reason = lines.await;
+        println!("Synthetic client disconnected. Reason: {}", reason);
+    });
+
+    for frame in 0.. {
+        let buf = frame_commands(&pixmap, frame_color(frame));
+
+        // Stop when the codec disconnected
+        if client.write_all(&buf).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Run a synthetic client on the current thread, forever.
+///
+/// The client sets every pixel of the canvas directly, bypassing the line
+/// codec.
+pub fn sync_synthetic_client(pixmap: Arc<Pixmap>, stats: Arc<Stats>) {
+    let (width, height) = pixmap.dimensions();
+
+    for frame in 0.. {
+        let color = frame_color(frame);
+        for (x, y) in pixels((width, height)) {
+            pixmap.set_pixel(x, y, color).unwrap();
+        }
+        stats.inc_pixels_by_n(width * height);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::cmd::Cmd;
+
+    #[test]
+    fn frame_commands_cover_canvas() {
+        let pixmap = Pixmap::new(10, 10);
+        let buf = frame_commands(&pixmap, Color::from_rgb(0xFF, 0, 0));
+
+        let mut seen = HashSet::new();
+        for line in buf.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            match Cmd::decode_line(line) {
+                Ok(Cmd::SetPixel(x, y, _)) => {
+                    assert!(x < 10 && y < 10, "pixel ({}, {}) out of bounds", x, y);
+                    assert!(seen.insert((x, y)), "pixel ({}, {}) drawn twice", x, y);
+                }
+                _ => panic!("unexpected command {:?}", String::from_utf8_lossy(line)),
+            }
+        }
+        assert_eq!(seen.len(), 100);
+    }
+}
@@ -0,0 +1,121 @@
+//! Built-in test patterns, to calibrate displays without sending commands.
+
+use pixelpwnr_render::{Color, Pixmap};
+
+/// A test pattern to draw on the canvas at startup.
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum TestPattern {
+    /// Vertical color bars
+    Bars,
+    /// A horizontal black to white gradient
+    Gradient,
+    /// A white crosshair and border on black
+    Crosshair,
+}
+
+/// The colors of the color bars, from left to right.
+const BARS: [Color; 8] = [
+    Color::from_rgb(0xFF, 0xFF, 0xFF),
+    Color::from_rgb(0xFF, 0xFF, 0x00),
+    Color::from_rgb(0x00, 0xFF, 0xFF),
+    Color::from_rgb(0x00, 0xFF, 0x00),
+    Color::from_rgb(0xFF, 0x00, 0xFF),
+    Color::from_rgb(0xFF, 0x00, 0x00),
+    Color::from_rgb(0x00, 0x00, 0xFF),
+    Color::from_rgb(0x00, 0x00, 0x00),
+];
+
+/// The color of the crosshair and border.
+const WHITE: Color = Color::from_rgb(0xFF, 0xFF, 0xFF);
+
+/// Draw the given test pattern over the whole canvas.
+pub fn draw(pixmap: &Pixmap, pattern: TestPattern) {
+    let (width, height) = pixmap.dimensions();
+
+    // All patterns are drawn within bounds, which can't fail
+    let fill = |x, y, w, h, color| {
+        pixmap.fill_rect(x, y, w, h, color).unwrap();
+    };
+
+    match pattern {
+        TestPattern::Bars => {
+            for (i, color) in BARS.iter().enumerate() {
+                let (start, end) = (i * width / BARS.len(), (i + 1) * width / BARS.len());
+                fill(start, 0, end - start, height, *color);
+            }
+        }
+        TestPattern::Gradient => {
+            for x in 0..width {
+                let value = (x * 0xFF / (width - 1).max(1)) as u8;
+                fill(x, 0, 1, height, Color::from_rgb(value, value, value));
+            }
+        }
+        TestPattern::Crosshair => {
+            fill(0, 0, width, height, Color::black());
+            fill(0, height / 2, width, 1, WHITE);
+            fill(width / 2, 0, 1, height, WHITE);
+
+            // Border
+            fill(0, 0, width, 1, WHITE);
+            fill(0, height - 1, width, 1, WHITE);
+            fill(0, 0, 1, height, WHITE);
+            fill(width - 1, 0, 1, height, WHITE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Draw the given pattern on a 16x8 canvas, and check the color of the
+    /// given sample pixels.
+    fn assert_samples(pattern: TestPattern, samples: &[(usize, usize, Color)]) {
+        let pixmap = Pixmap::new(16, 8);
+        draw(&pixmap, pattern);
+        for &(x, y, color) in samples {
+            assert_eq!(pixmap.pixel(x, y).unwrap(), color, "pixel ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn bars() {
+        assert_samples(
+            TestPattern::Bars,
+            &[
+                (0, 0, BARS[0]),
+                (1, 7, BARS[0]),
+                (2, 3, BARS[1]),
+                (9, 4, BARS[4]),
+                (15, 7, BARS[7]),
+            ],
+        );
+    }
+
+    #[test]
+    fn gradient() {
+        assert_samples(
+            TestPattern::Gradient,
+            &[
+                (0, 0, Color::black()),
+                (5, 3, Color::from_rgb(0x55, 0x55, 0x55)),
+                (15, 7, WHITE),
+            ],
+        );
+    }
+
+    #[test]
+    fn crosshair() {
+        assert_samples(
+            TestPattern::Crosshair,
+            &[
+                (0, 0, WHITE),
+                (15, 7, WHITE),
+                (8, 2, WHITE),
+                (3, 4, WHITE),
+                (3, 2, Color::black()),
+                (12, 6, Color::black()),
+            ],
+        );
+    }
+}
@@ -0,0 +1,232 @@
+//! Pixelflut over WebSocket, for clients such as browsers that can't open a
+//! plain TCP connection.
+//!
+//! An upgraded connection is bridged to an in-memory stream carrying the raw
+//! protocol, so it is served by the regular `Lines` codec. The payload of
+//! received text and binary frames is passed on as is, responses are sent back
+//! in binary frames.
+
+use std::io;
+use std::sync::Arc;
+
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// The GUID appended to the client key, to compute the accept key.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The capacity of the in-memory stream bridging a connection.
+const BRIDGE_SIZE: usize = 64 * 1024;
+
+/// The maximum payload size of an outgoing frame.
+const FRAME_MAX_SIZE: usize = 16 * 1024;
+
+/// Frame opcodes.
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Compute the `Sec-WebSocket-Accept` value for the given client key.
+pub fn accept_key(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key.trim(), GUID)).digest();
+    base64::engine::general_purpose::STANDARD.encode(digest.bytes())
+}
+
+/// Bridge an upgraded WebSocket connection to an in-memory stream, carrying
+/// the raw protocol.
+///
+/// `buffered` holds data the client sent right after its upgrade request.
+/// The connection is closed once the returned stream is dropped.
+pub fn bridge(socket: TcpStream, buffered: Vec<u8>) -> DuplexStream {
+    let (stream, bridge) = tokio::io::duplex(BRIDGE_SIZE);
+    let (bridge_rd, bridge_wr) = tokio::io::split(bridge);
+    let (socket_rd, socket_wr) = socket.into_split();
+    let socket_wr = Arc::new(Mutex::new(socket_wr));
+
+    // Errors here are caused by clients going away, ignore them
+    let reader_wr = socket_wr.clone();
+    tokio::spawn(async move {
+        let socket_rd = io::Cursor::new(buffered).chain(socket_rd);
+        let _ = read_frames(socket_rd, bridge_wr, reader_wr).await;
+    });
+    tokio::spawn(async move {
+        let _ = write_frames(bridge_rd, socket_wr).await;
+    });
+
+    stream
+}
+
+/// Read frames from the client, and pass their payload on to the bridge until
+/// the client closes the connection.
+async fn read_frames<R>(
+    mut socket: R,
+    mut bridge: WriteHalf<DuplexStream>,
+    socket_wr: Arc<Mutex<OwnedWriteHalf>>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = [0; 4096];
+
+    loop {
+        let mut head = [0; 2];
+        if socket.read_exact(&mut head).await.is_err() {
+            break;
+        }
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7F {
+            126 => socket.read_u16().await? as u64,
+            127 => socket.read_u64().await?,
+            len => len as u64,
+        };
+
+        // Clients must mask all frames they send
+        if !masked {
+            send_frame(&socket_wr, OP_CLOSE, &1002u16.to_be_bytes()).await?;
+            break;
+        }
+        let mut mask = [0; 4];
+        socket.read_exact(&mut mask).await?;
+
+        match opcode {
+            // Pass data on as it comes in, frame boundaries don't matter
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                let mut offset = 0;
+                while offset < len {
+                    let chunk = (len - offset).min(buf.len() as u64) as usize;
+                    socket.read_exact(&mut buf[..chunk]).await?;
+                    unmask(&mut buf[..chunk], mask, offset as usize);
+                    bridge.write_all(&buf[..chunk]).await?;
+                    offset += chunk as u64;
+                }
+            }
+            OP_CLOSE | OP_PING | OP_PONG => {
+                // Control frames carry at most 125 bytes
+                if len > 125 {
+                    send_frame(&socket_wr, OP_CLOSE, &1002u16.to_be_bytes()).await?;
+                    break;
+                }
+                let payload = &mut buf[..len as usize];
+                socket.read_exact(payload).await?;
+                unmask(payload, mask, 0);
+
+                match opcode {
+                    OP_PING => send_frame(&socket_wr, OP_PONG, payload).await?,
+                    OP_CLOSE => {
+                        send_frame(&socket_wr, OP_CLOSE, payload.get(..2).unwrap_or(&[])).await?;
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            _ => {
+                send_frame(&socket_wr, OP_CLOSE, &1002u16.to_be_bytes()).await?;
+                break;
+            }
+        }
+    }
+
+    // The client is gone, let the codec see the end of the stream
+    bridge.shutdown().await
+}
+
+/// Send everything written to the bridge to the client in binary frames, until
+/// the bridge is closed.
+async fn write_frames(
+    mut bridge: ReadHalf<DuplexStream>,
+    socket_wr: Arc<Mutex<OwnedWriteHalf>>,
+) -> io::Result<()> {
+    let mut buf = vec![0; FRAME_MAX_SIZE];
+
+    loop {
+        let read = bridge.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        send_frame(&socket_wr, OP_BINARY, &buf[..read]).await?;
+    }
+
+    // The codec closed the connection, close it on the client as well
+    send_frame(&socket_wr, OP_CLOSE, &1000u16.to_be_bytes()).await?;
+    socket_wr.lock().await.shutdown().await
+}
+
+/// Send a single unmasked frame with the given opcode and payload.
+async fn send_frame(
+    socket_wr: &Mutex<OwnedWriteHalf>,
+    opcode: u8,
+    payload: &[u8],
+) -> io::Result<()> {
+    let frame = encode_frame(opcode, payload);
+    socket_wr.lock().await.write_all(&frame).await
+}
+
+/// Encode a single unmasked frame with the given opcode and payload.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Unmask the given part of a payload, starting at the given payload offset.
+fn unmask(data: &mut [u8], mask: [u8; 4], offset: usize) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[(offset + i) % 4];
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn accept_key_rfc() {
+        // The example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn encode_frame_lengths() {
+        assert_eq!(encode_frame(OP_BINARY, b"Hi"), [0x82, 2, b'H', b'i']);
+        assert_eq!(encode_frame(OP_BINARY, &[0; 200])[..4], [0x82, 126, 0, 200]);
+        assert_eq!(
+            encode_frame(OP_BINARY, &[0; 70_000])[..10],
+            [0x82, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]
+        );
+    }
+
+    #[test]
+    fn unmask_offset() {
+        let mask = [1, 2, 3, 4];
+        let mut data = *b"SIZE\n";
+        unmask(&mut data, mask, 0);
+
+        // Unmasking in parts gives the same result
+        let mut parts = data;
+        unmask(&mut parts[..3], mask, 0);
+        unmask(&mut parts[3..], mask, 3);
+        assert_eq!(&parts, b"SIZE\n");
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{BufRead, BufReader};
+use std::net::SocketAddr;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The maximum time to wait for a single response.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server process, killed when dropped.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Start the server on an ephemeral port, and return the address it listens on.
+fn start_server() -> (Server, SocketAddr) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_pixelpwnr-server"));
+    command.arg("--no-render");
+    spawn_server(command)
+}
+
+/// Spawn the given server command on an ephemeral port, and return the address
+/// it listens on.
+fn spawn_server(mut command: Command) -> (Server, SocketAddr) {
+    let mut child = command
+        .args(["--host", "127.0.0.1:0", "--width", "10", "--height", "10"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start server");
+    let stdout = child.stdout.take().unwrap();
+    let server = Server(child);
+
+    // Find the address the server is listening on, keep draining its output
+    // so it doesn't fail writing to a closed pipe
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let line = line.expect("failed to read server output");
+            if let Some(addr) = line.strip_prefix("Listening on: ") {
+                let _ = tx.send(addr.parse().expect("invalid listen address"));
+            }
+        }
+    });
+    let addr = rx
+        .recv_timeout(TIMEOUT)
+        .expect("server did not report listen address");
+
+    (server, addr)
+}
+
+/// Read a single line, without line terminator.
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Option<String> {
+    let mut line = String::new();
+    let read = tokio::time::timeout(TIMEOUT, reader.read_line(&mut line))
+        .await
+        .expect("timed out waiting for response")
+        .unwrap();
+    (read > 0).then(|| line.trim_end().to_owned())
+}
+
+#[tokio::test]
+async fn set_and_get_pixel() {
+    let (_server, addr) = start_server();
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (read, mut write) = socket.into_split();
+    let mut read = tokio::io::BufReader::new(read);
+
+    write.write_all(b"SIZE\n").await.unwrap();
+    assert_eq!(read_line(&mut read).await.as_deref(), Some("SIZE 10 10"));
+
+    write.write_all(b"PX 1 1 FF0000\nPX 1 1\n").await.unwrap();
+    assert_eq!(read_line(&mut read).await.as_deref(), Some("PX 1 1 FF0000"));
+
+    // The server closes the connection on quit
+    write.write_all(b"QUIT\n").await.unwrap();
+    assert_eq!(read_line(&mut read).await, None);
+}
+
+/// Without a display server, the renderer fails to start, and the server keeps
+/// serving clients without rendering.
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn render_failure_fallback() {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_pixelpwnr-server"));
+    command.env_remove("DISPLAY").env_remove("WAYLAND_DISPLAY");
+    let (_server, addr) = spawn_server(command);
+
+    let socket = TcpStream::connect(addr).await.unwrap();
+    let (read, mut write) = socket.into_split();
+    let mut read = tokio::io::BufReader::new(read);
+
+    write.write_all(b"PX 2 2 00FF00\nPX 2 2\n").await.unwrap();
+    assert_eq!(read_line(&mut read).await.as_deref(), Some("PX 2 2 00FF00"));
+}